@@ -7,7 +7,8 @@
       none = null;
       # not implemented
       amqp = null;
-      http = [ "responder" "-v" "info" "--tls-chain" "/run/credentials/lxddns-responder.service/cert.pem" "--tls-key" "/run/credentials/lxddns-responder.service/key.pem" "--https-bind" "${cfg.http.listenAddress}:${toString cfg.http.listenPort}" ];
+      # the socket unit provides the listener with socket activation
+      http = [ "responder" "-v" "info" "--tls-chain" "/run/credentials/lxddns-responder.service/cert.pem" "--tls-key" "/run/credentials/lxddns-responder.service/key.pem" ] ++ lib.optionals (!cfg.socketActivation) [ "--https-bind" "${cfg.http.listenAddress}:${toString cfg.http.listenPort}" ];
     };
     cfg = config.services.lxddns-responder;
   in
@@ -124,6 +125,27 @@
               Dependent arguments passed to the systemd service conditionally.
             '';
           };
+          socketActivation = lib.mkOption
+          {
+            default = false;
+            type = lib.types.bool;
+            description =
+            ''
+              Let a systemd socket unit listen on `http.listenAddress` and `http.listenPort` and pass the socket to *lxddns*.
+              This only applies to the `http` protocol.
+            '';
+          };
+          notify = lib.mkOption
+          {
+            default = cfg.protocol != "none";
+            defaultText = lib.literalExpression ''cfg.protocol != "none"'';
+            type = lib.types.bool;
+            description =
+            ''
+              Whether the responder notifies systemd about its readiness, making the service `Type=notify`.
+              Both `http` and `amqp` responders do, custom ones used with `none` need to enable this explicitly.
+            '';
+          };
           extraArgs = lib.mkOption
           {
             default = [];
@@ -200,7 +222,7 @@
               path = [ "/run/wrappers" ];
               serviceConfig =
               {
-                Type = "simple";
+                Type = if cfg.notify then "notify" else "simple";
                 ExecStart = "${cfg.package}/bin/${cfg.binary} ${toString cfg.args} ${toString cfg.dependentArgs} ${toString cfg.extraArgs}";
                 User = cfg.user;
                 Group = cfg.group;
@@ -214,6 +236,21 @@
             };
           };
         })
+        (lib.mkIf (cfg.enable && cfg.socketActivation)
+        {
+          systemd.services.lxddns-responder.requires = [ "lxddns-responder.socket" ];
+          systemd.sockets.lxddns-responder =
+          {
+            description = "lxddns responder socket";
+            listenStreams = [ "${cfg.http.listenAddress}:${toString cfg.http.listenPort}" ];
+            socketConfig =
+            {
+              # anything but "http" is served with TLS
+              FileDescriptorName = "https";
+            };
+            wantedBy = [ "sockets.target" ];
+          };
+        })
         (lib.mkIf (cfg.enable && cfg.sudo)
         {
           security.sudo =
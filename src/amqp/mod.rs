@@ -9,7 +9,25 @@ mod responder;
 mod unix;
 mod pipe;
 mod query;
+mod presence;
 
 pub use responder::Responder;
 pub use unix::Unix;
 pub use pipe::Pipe;
+pub use presence::Presence;
+pub use query::ReplyQueue;
+
+/// Message type of a reply from a responder hosting the requested instance.
+///
+/// Replies without a type stem from responders predating negative replies and are treated as a match.
+pub(crate) const REPLY_MATCH: &str = "match";
+/// Message type of a reply from a responder not hosting the requested instance, only sent in reply to [`REQUEST_LOOKUP`].
+pub(crate) const REPLY_NO_MATCH: &str = "nomatch";
+/// Message type of an address lookup by a querier which understands [`REPLY_NO_MATCH`], the body being the instance name.
+///
+/// Requests without a type are lookups by queriers predating negative replies, which take any reply for a match.
+pub(crate) const REQUEST_LOOKUP: &str = "lookup";
+/// Message type of a request for metadata, the body being the instance name or empty for all instances.
+pub(crate) const REQUEST_METADATA: &str = "metadata";
+/// Message type of a reply carrying metadata as JSON.
+pub(crate) const REPLY_METADATA: &str = "metadata";
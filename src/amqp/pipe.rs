@@ -11,7 +11,30 @@ use crate::
 	pdns::
 	{
 		TtlConfig,
+		Zone,
+		ZoneConfig,
 	},
+	amqp::
+	{
+		presence::Directory,
+		ReplyQueue,
+	},
+	cache::
+	{
+		AnswerCache,
+		CacheConfig,
+		CachedQuery,
+	},
+	soa::
+	{
+		Soa,
+		SoaConfig,
+	},
+	pdns_io::
+	{
+		RemoteQuery,
+	},
+	shutdown::Shutdown,
 };
 
 use ::
@@ -19,6 +42,7 @@ use ::
 	lapin::
 	{
 		Connection,
+		protocol::constants::REPLY_SUCCESS,
 	},
 	tokio::
 	{
@@ -28,6 +52,10 @@ use ::
 			stdout,
 		},
 	},
+	std::
+	{
+		sync::Arc,
+	},
 };
 
 pub struct Pipe
@@ -36,6 +64,11 @@ pub struct Pipe
 	hostmaster: String,
 	ttl_config: TtlConfig,
 	connection: Connection,
+	reply_queue: ReplyQueue,
+	cache_config: CacheConfig,
+	soa_config: SoaConfig,
+	zone: Zone,
+	shutdown: Shutdown,
 }
 
 impl Pipe
@@ -52,23 +85,48 @@ impl Pipe
 		let channel = self.connection.create_channel().await?;
 		debug!("[pipe] channel created");
 
-		let backend = super::query::RemoteQuery::new(channel).await?;
-		let handler = crate::pdns_io::PdnsStreamHandler::new(self.domain, &self.ttl_config, self.hostmaster, backend, stdin(), stdout()).await?;
-		handler.run().await?;
+		let directory = Arc::new(Directory::new(&self.connection).await?);
+		let backend = super::query::RemoteQuery::new(channel, directory, self.reply_queue).await?;
+		let backend: Box<dyn RemoteQuery+Send+Sync> = if self.cache_config.enabled()
+		{
+			Box::new(CachedQuery::new(backend, AnswerCache::new(&self.cache_config, &self.ttl_config)))
+		}
+		else
+		{
+			Box::new(backend)
+		};
+		self.zone.records().reload_on_signal()?;
+		let handler = crate::pdns_io::PdnsStreamHandler::new(self.domain, &self.ttl_config, self.hostmaster, backend, stdin(), stdout()).await?
+			.soa(Soa::new(self.soa_config.clone()))
+			.zone(self.zone.clone())
+			.shutdown(self.shutdown.clone());
+		if self.shutdown.drain(handler.run()).await.transpose()?.is_none()
+		{
+			warn!("[pipe] connection cut off by shutdown");
+		}
 
+		if let Err(err) = self.connection.close(REPLY_SUCCESS, "backend stopped".into()).await
+		{
+			debug!("[pipe] error closing connection: {}", err);
+		}
 		debug!("[pipe] connection closed");
 
 		Ok(())
 	}
 }
 
-#[derive(Clone,Eq,PartialEq,Hash,Debug,Default)]
+#[derive(Clone,Debug,Default)]
 pub struct PipeBuilder
 {
 	url: Option<String>,
 	domain: Option<String>,
 	hostmaster: Option<String>,
 	ttl_config: Option<TtlConfig>,
+	reply_queue: Option<ReplyQueue>,
+	cache_config: Option<CacheConfig>,
+	soa_config: Option<SoaConfig>,
+	zone_config: Option<ZoneConfig>,
+	shutdown: Option<Shutdown>,
 }
 
 impl PipeBuilder
@@ -97,12 +155,48 @@ impl PipeBuilder
 		self
 	}
 
+	pub fn reply_queue(mut self, reply_queue: ReplyQueue) -> Self
+	{
+		self.reply_queue = Some(reply_queue);
+		self
+	}
+
+	pub fn soa_config(mut self, soa_config: SoaConfig) -> Self
+	{
+		self.soa_config = Some(soa_config);
+		self
+	}
+
+	pub fn zone_config(mut self, zone_config: ZoneConfig) -> Self
+	{
+		self.zone_config = Some(zone_config);
+		self
+	}
+
+	pub fn cache_config(mut self, cache_config: CacheConfig) -> Self
+	{
+		self.cache_config = Some(cache_config);
+		self
+	}
+
+	pub fn shutdown(mut self, shutdown: Shutdown) -> Self
+	{
+		self.shutdown = Some(shutdown);
+		self
+	}
+
 	pub async fn run(self) -> Result<()>
 	{
 		let url = self.url.map(Result::Ok).unwrap_or_else(|| bail!("no url provided")).context(Error::InvalidConfiguration)?;
 		let domain = self.domain.map(Result::Ok).unwrap_or_else(|| bail!("no domain provided")).context(Error::InvalidConfiguration)?;
 		let hostmaster = self.hostmaster.map(Result::Ok).unwrap_or_else(|| bail!("no hostmaster provided")).context(Error::InvalidConfiguration)?;
 		let ttl_config = self.ttl_config.unwrap_or_default();
+		let reply_queue = self.reply_queue.unwrap_or_default();
+		let cache_config = self.cache_config.unwrap_or_default();
+		let soa_config = self.soa_config.unwrap_or_default();
+		soa_config.without_tracking().context(Error::InvalidConfiguration)?;
+		let zone = Zone::new(&domain, &self.zone_config.unwrap_or_default())?;
+		let shutdown = self.shutdown.unwrap_or_default();
 
 		let connection = Connection::connect(url.as_ref(), Default::default())
 			.await
@@ -116,6 +210,11 @@ impl PipeBuilder
 			hostmaster,
 			ttl_config,
 			connection,
+			reply_queue,
+			cache_config,
+			soa_config,
+			zone,
+			shutdown,
 		}.run().await
 	}
 }
@@ -0,0 +1,345 @@
+// Copyright (C) benaryorg <binary@benary.org>
+//
+// This software is licensed as described in the file COPYING, which
+// you should have received as part of this distribution.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::
+{
+	error::*,
+	amqp::
+	{
+		REPLY_METADATA,
+		REPLY_NO_MATCH,
+	},
+	lxd::
+	{
+		instance_count,
+		server_name,
+	},
+};
+
+use ::
+{
+	getset::Getters,
+	serde::
+	{
+		Deserialize,
+		Serialize,
+	},
+	lapin::
+	{
+		Channel,
+		Connection,
+		ExchangeKind,
+		options::
+		{
+			BasicConsumeOptions,
+			QueueDeclareOptions,
+		},
+		protocol::basic::AMQPProperties,
+	},
+	tokio::
+	{
+		task::JoinHandle,
+		time::interval,
+	},
+	futures::
+	{
+		stream::
+		{
+			StreamExt,
+		},
+	},
+	std::
+	{
+		collections::
+		{
+			BTreeSet,
+			HashMap,
+			HashSet,
+		},
+		sync::
+		{
+			Arc,
+			Mutex,
+		},
+		time::
+		{
+			Duration,
+			Instant,
+		},
+	},
+};
+
+/// Name of the fanout exchange carrying the presence announcements.
+pub const PRESENCE_EXCHANGE: &str = "lxddns-presence";
+
+/// Number of announcement intervals after which a silent responder is considered gone.
+const PRESENCE_EXPIRY_INTERVALS: u32 = 3;
+/// Longest announcement interval honoured, in seconds, whatever a responder claims.
+const PRESENCE_MAX_INTERVAL: u64 = 3600;
+
+/// Heartbeat periodically published by every responder.
+#[derive(Getters,Serialize,Deserialize,Clone,Eq,PartialEq,Hash,Debug)]
+pub struct Presence
+{
+	/// Identity of the responder, also sent along with every response.
+	#[get = "pub"]
+	host_id: String,
+	/// Name of the LXD server the responder is querying.
+	#[get = "pub"]
+	server_name: String,
+	/// Number of instances on the LXD server, regardless of their state.
+	#[get = "pub"]
+	instance_count: usize,
+	/// Version of *lxddns* the responder runs.
+	#[get = "pub"]
+	version: String,
+	/// Seconds until the next heartbeat of this responder.
+	#[get = "pub"]
+	interval: u64,
+	/// Reply types the responder sends whenever asked, e.g. [`REPLY_NO_MATCH`], empty for responders predating this.
+	#[serde(default)]
+	#[get = "pub"]
+	capabilities: BTreeSet<String>,
+}
+
+impl Presence
+{
+	/// Time after which the responder is considered gone unless it announces itself again.
+	fn expiry(&self) -> Duration
+	{
+		Duration::from_secs(self.interval.min(PRESENCE_MAX_INTERVAL)).saturating_mul(PRESENCE_EXPIRY_INTERVALS)
+	}
+}
+
+pub(crate) async fn declare_exchange(channel: &Channel) -> Result<()>
+{
+	channel.exchange_declare(PRESENCE_EXCHANGE.into(), ExchangeKind::Fanout, Default::default(), Default::default()).await?;
+	trace!("[presence] created fanout exchange");
+
+	Ok(())
+}
+
+/// Periodically announces the presence of a responder until dropped.
+pub(crate) struct Announcer
+{
+	task: JoinHandle<()>,
+}
+
+impl Announcer
+{
+	pub async fn start(connection: &Connection, command: String, host_id: String, period: Duration) -> Result<Self>
+	{
+		let channel = connection.create_channel().await.context(Error::QueueConnectionError)?;
+		declare_exchange(&channel).await?;
+
+		let period = period.max(Duration::from_secs(1));
+
+		let task = tokio::spawn(async move
+		{
+			let mut ticker = interval(period);
+			// the server name does not change, it is only asked for again until it is known
+			let mut known_server_name = None;
+			loop
+			{
+				ticker.tick().await;
+
+				if known_server_name.is_none()
+				{
+					match server_name(&command).await
+					{
+						Ok(name) => known_server_name = Some(name),
+						Err(err) => warn!("[presence][{}] cannot determine server name: {}", host_id, err),
+					}
+				}
+
+				// a responder which cannot talk to LXD still answers (with errors), so it still announces itself
+				let server_name = known_server_name.clone().unwrap_or_default();
+				let instance_count = instance_count(&command).await
+					.unwrap_or_else(|err|
+					{
+						warn!("[presence][{}] cannot determine instance count: {}", host_id, err);
+						0
+					});
+
+				let presence = Presence
+				{
+					host_id: host_id.clone(),
+					server_name,
+					instance_count,
+					version: env!("CARGO_PKG_VERSION").to_string(),
+					interval: period.as_secs(),
+					capabilities: [REPLY_NO_MATCH, REPLY_METADATA].iter().map(|capability| capability.to_string()).collect(),
+				};
+
+				let payload = match serde_json::to_vec(&presence)
+				{
+					Ok(payload) => payload,
+					Err(err) =>
+					{
+						warn!("[presence][{}] cannot serialize presence: {}", host_id, err);
+						continue;
+					},
+				};
+
+				match channel.basic_publish(PRESENCE_EXCHANGE.into(), "".into(), Default::default(), &payload, AMQPProperties::default().with_app_id(host_id.clone().into())).await
+				{
+					Ok(_) => trace!("[presence][{}] announced: {:?}", host_id, presence),
+					Err(err) => warn!("[presence][{}] announcement failed: {}", host_id, err),
+				}
+			}
+		});
+
+		Ok(Self
+		{
+			task,
+		})
+	}
+}
+
+impl Drop for Announcer
+{
+	fn drop(&mut self)
+	{
+		self.task.abort();
+	}
+}
+
+/// Set of responders currently announcing their presence.
+///
+/// The directory listens on the presence exchange until dropped.
+pub(crate) struct Directory
+{
+	/// Latest announcement of every responder along with the time it expires.
+	responders: Arc<Mutex<HashMap<String,(Presence,Instant)>>>,
+	task: JoinHandle<()>,
+}
+
+impl Directory
+{
+	pub async fn new(connection: &Connection) -> Result<Self>
+	{
+		let channel = connection.create_channel().await.context(Error::QueueConnectionError)?;
+		declare_exchange(&channel).await?;
+
+		let queue = channel.queue_declare(
+			"".into(),
+			QueueDeclareOptions
+			{
+				exclusive: true,
+				auto_delete: true,
+				..QueueDeclareOptions::default()
+			},
+			Default::default()
+		).await.with_context(|| "error in queue_declare")?;
+
+		channel.queue_bind(queue.name().clone(), PRESENCE_EXCHANGE.into(), "".into(), Default::default(), Default::default()).await?;
+		trace!("[presence][directory] bound exchange to queue {}", queue.name());
+
+		let mut consumer = channel.basic_consume(queue.name().clone(),
+			"".into(),
+			BasicConsumeOptions
+			{
+				no_ack: true,
+				..Default::default()
+			},
+			Default::default()
+		).await.with_context(|| "error in basic_consume")?;
+
+		let responders: Arc<Mutex<HashMap<String,(Presence,Instant)>>> = Default::default();
+
+		let task = tokio::spawn(
+		{
+			let responders = responders.clone();
+
+			async move
+			{
+				// keep the channel open as long as the directory exists
+				let _channel = channel;
+
+				while let Some(delivery) = consumer.next().await
+				{
+					let delivery = match delivery
+					{
+						Ok(delivery) => delivery,
+						Err(err) =>
+						{
+							warn!("[presence][directory] consumer error: {}", err);
+							break;
+						},
+					};
+
+					match serde_json::from_slice::<Presence>(&delivery.data)
+					{
+						Ok(presence) =>
+						{
+							let now = Instant::now();
+							let mut responders = responders.lock().unwrap();
+
+							responders.retain(|host_id, (presence, expires)|
+							{
+								let alive = *expires > now;
+								if !alive
+								{
+									info!("[presence][directory] responder expired: {} ({})", host_id, presence.server_name);
+								}
+								alive
+							});
+
+							let previous = responders.insert(presence.host_id.clone(), (presence.clone(), now + presence.expiry()));
+							if previous.is_none()
+							{
+								info!("[presence][directory] responder appeared: {} ({})", presence.host_id, presence.server_name);
+							}
+							trace!("[presence][directory] heartbeat: {:?}", presence);
+						},
+						Err(err) => debug!("[presence][directory] invalid announcement: {}", err),
+					}
+				}
+				debug!("[presence][directory] stopped");
+			}
+		});
+
+		Ok(Self
+		{
+			responders,
+			task,
+		})
+	}
+
+	/// Responders which announced themselves recently enough to be considered alive.
+	pub fn live(&self) -> Vec<Presence>
+	{
+		let now = Instant::now();
+
+		self.responders.lock().unwrap().values()
+			.filter(|(_, expires)| *expires > now)
+			.map(|(presence, _)| presence.clone())
+			.collect()
+	}
+
+	/// Host ids of all live responders, provided every one of them announced the given capability.
+	///
+	/// A responder staying silent only means something if it would have answered, so without any or with older responders around there is nothing to expect.
+	pub fn live_ids_with(&self, capability: &str) -> Option<HashSet<String>>
+	{
+		let live = self.live();
+		if live.is_empty() || live.iter().any(|presence| !presence.capabilities.contains(capability))
+		{
+			return None;
+		}
+
+		Some(live.into_iter().map(|presence| presence.host_id).collect())
+	}
+}
+
+impl Drop for Directory
+{
+	fn drop(&mut self)
+	{
+		self.task.abort();
+	}
+}
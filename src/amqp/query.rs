@@ -11,30 +11,51 @@ use crate::
 	lxd::
 	{
 		ContainerName,
+		InstanceMetadata,
 	},
 	pdns_io::
 	{
 		RemoteQuery as RemoteQueryTrait,
 	},
+	amqp::
+	{
+		presence::Directory,
+		REPLY_METADATA,
+		REPLY_NO_MATCH,
+		REQUEST_LOOKUP,
+		REQUEST_METADATA,
+	},
 };
 
 use ::
 {
 	lapin::
 	{
+		Confirmation,
 		Channel,
-		Queue,
+		ExchangeKind,
+		message::Delivery,
 		options::
 		{
 			BasicAckOptions,
 			BasicConsumeOptions,
+			BasicPublishOptions,
 			BasicRejectOptions,
+			ConfirmSelectOptions,
 			QueueDeclareOptions,
 		},
-		protocol::basic::AMQPProperties,
+		protocol::
+		{
+			basic::AMQPProperties,
+			constants::REPLY_SUCCESS,
+		},
+		types::ShortString,
 	},
+	clap::ValueEnum,
 	tokio::
 	{
+		sync::mpsc,
+		task::JoinHandle,
 		time::timeout,
 	},
 	futures::
@@ -44,9 +65,29 @@ use ::
 			StreamExt,
 		},
 	},
+	serde_json::
+	{
+		json,
+		Value,
+	},
 	uuid::Uuid,
 	std::
 	{
+		collections::
+		{
+			HashMap,
+			HashSet,
+		},
+		sync::
+		{
+			Arc,
+			Mutex,
+			atomic::
+			{
+				AtomicUsize,
+				Ordering,
+			},
+		},
 		time::
 		{
 			Duration,
@@ -57,142 +98,443 @@ use ::
 	},
 };
 
+/// Queries currently waiting for responses, keyed by their correlation id.
+type Pending = Arc<Mutex<HashMap<Uuid,mpsc::UnboundedSender<Delivery>>>>;
+
+/// Pseudo queue provided by RabbitMQ for replies straight to the consuming channel.
+const DIRECT_REPLY_TO: &str = "amq.rabbitmq.reply-to";
+
+/// Where responders send their replies to.
+#[derive(Clone,Copy,Eq,PartialEq,Hash,Debug,Default,ValueEnum)]
+pub enum ReplyQueue
+{
+	/// A server-named queue per query backend, deleted along with its consumer.
+	#[default]
+	Exclusive,
+	/// RabbitMQ's `amq.rabbitmq.reply-to` pseudo queue, which needs no queue at all.
+	DirectReplyTo,
+}
+
 pub struct RemoteQuery
 {
 	channel: Channel,
-	response_queue: Queue,
+	reply_to: ShortString,
+	no_ack: bool,
+	pending: Pending,
+	dispatcher: JoinHandle<()>,
+	directory: Arc<Directory>,
+	unroutable: AtomicUsize,
 }
 
 impl RemoteQuery
 {
-	pub async fn new(channel: Channel) -> Result<Self>
+	pub async fn new(channel: Channel, directory: Arc<Directory>, reply_queue: ReplyQueue) -> Result<Self>
 	{
-		let response_queue = channel.queue_declare(
+		// declaring the exchange here too turns a missing responder into an unroutable message rather than a channel error
+		channel.exchange_declare("lxddns".into(), ExchangeKind::Fanout, Default::default(), Default::default()).await?;
+		channel.confirm_select(ConfirmSelectOptions::default()).await.with_context(|| "error in confirm_select")?;
+
+		// direct reply-to only works with automatic acknowledgement
+		let (reply_to, no_ack) = match reply_queue
+		{
+			ReplyQueue::Exclusive =>
+			{
+				let response_queue = channel.queue_declare(
+					"".into(),
+					QueueDeclareOptions
+					{
+						exclusive: true,
+						auto_delete: true,
+						..QueueDeclareOptions::default()
+					},
+					Default::default()
+				).await.with_context(|| "error in queue_declare")?;
+
+				(response_queue.name().clone(), false)
+			},
+			ReplyQueue::DirectReplyTo => (DIRECT_REPLY_TO.into(), true),
+		};
+
+		info!("[amqp-remote_query][new] connection to message queue {} established", reply_to);
+
+		let consumer = channel.basic_consume(reply_to.clone(),
 			"".into(),
-			QueueDeclareOptions
+			BasicConsumeOptions
 			{
-				exclusive: false,
-				auto_delete: false,
-				..QueueDeclareOptions::default()
+				no_ack,
+				no_local: true,
+				..Default::default()
 			},
 			Default::default()
-		).await.with_context(|| "error in queue_declare")?;
+		).await.with_context(|| "error in basic_consume")?;
+
+		let pending: Pending = Default::default();
+
+		// a single consumer per queue dispatches responses to the queries by correlation id, so concurrent queries do not steal each others responses
+		let dispatcher = tokio::spawn(
+		{
+			let pending = pending.clone();
+			let queue = reply_to.to_string();
+
+			async move
+			{
+				let mut consumer = consumer;
+				while let Some(delivery) = consumer.next().await
+				{
+					let delivery = match delivery
+					{
+						Ok(delivery) => delivery,
+						Err(err) =>
+						{
+							warn!("[amqp-remote_query][{}] consumer error: {}", queue, err);
+							break;
+						},
+					};
 
-		info!("[amqp-remote_query][new] connection to message queue {} established", response_queue.name());
+					let received_id = match delivery.properties.correlation_id().as_ref().map(|id| Uuid::parse_str(id.as_str()))
+					{
+						Some(Ok(received_id)) => received_id,
+						Some(Err(_)) =>
+						{
+							info!("[amqp-remote_query][{}] response with invalid correlation_id; rejecting", queue);
+							let _ = reject(&delivery, no_ack).await;
+							continue;
+						},
+						None =>
+						{
+							info!("[amqp-remote_query][{}] response without correlation_id; rejecting", queue);
+							let _ = reject(&delivery, no_ack).await;
+							continue;
+						},
+					};
+
+					let sender = pending.lock().unwrap().get(&received_id).cloned();
+					match sender
+					{
+						Some(sender) =>
+						{
+							if let Err(mpsc::error::SendError(delivery)) = sender.send(delivery)
+							{
+								debug!("[amqp-remote_query][{}][{}] query finished before response arrived; rejecting", queue, received_id);
+								let _ = reject(&delivery, no_ack).await;
+							}
+						},
+						None =>
+						{
+							debug!("[amqp-remote_query][{}][{}] unrelated message received; rejecting", queue, received_id);
+							let _ = reject(&delivery, no_ack).await;
+						},
+					}
+				}
+				debug!("[amqp-remote_query][{}] dispatcher stopped", queue);
+			}
+		});
 
 		Ok(Self
 		{
 			channel,
-			response_queue,
+			reply_to,
+			no_ack,
+			pending,
+			dispatcher,
+			directory,
+			unroutable: AtomicUsize::new(0),
 		})
 	}
 }
 
+impl RemoteQuery
+{
+	/// Publishes a request to all responders, returning the receiver for their replies which stays registered as long as the guard lives.
+	async fn publish(&self, label: &str, body: &[u8], kind: Option<&str>) -> Result<(PendingGuard<'_>, mpsc::UnboundedReceiver<Delivery>)>
+	{
+		let correlation_id = Uuid::new_v4();
+		trace!("[amqp-remote_query][{}] correlation id: {}", label, correlation_id);
+
+		let (sender, receiver) = mpsc::unbounded_channel();
+		if self.pending.lock().unwrap().insert(correlation_id, sender).is_some()
+		{
+			bail!(Error::DuplicateCorrelationId);
+		}
+		let guard = PendingGuard
+		{
+			pending: &self.pending,
+			correlation_id,
+		};
+
+		let mut properties = AMQPProperties::default()
+			.with_correlation_id(format!("{}", correlation_id).into())
+			.with_reply_to(self.reply_to.clone());
+		if let Some(kind) = kind
+		{
+			properties = properties.with_type(kind.into());
+		}
+
+		let confirmation = self.channel.basic_publish("lxddns".into(), "lxddns".into(),
+			BasicPublishOptions
+			{
+				mandatory: true,
+				..Default::default()
+			},
+			body,
+			properties
+		).await.with_context(|| "error in basic_publish")?
+			.await.with_context(|| "error in publisher confirm")?;
+
+		match confirmation
+		{
+			Confirmation::Ack(None) | Confirmation::NotRequested => trace!("[amqp-remote_query][{}][{}] published message", label, correlation_id),
+			Confirmation::Ack(Some(returned)) =>
+			{
+				// mandatory messages are returned if not a single queue is bound to the exchange, i.e. no responder is running
+				self.unroutable.fetch_add(1, Ordering::Relaxed);
+				warn!("[amqp-remote_query][{}][{}] message returned ({} {}), no responders are bound", label, correlation_id, returned.reply_code, returned.reply_text);
+				bail!(Error::NoResponders);
+			},
+			Confirmation::Nack(_) =>
+			{
+				warn!("[amqp-remote_query][{}][{}] message not acknowledged by broker", label, correlation_id);
+				bail!(Error::QueueConnectionError);
+			},
+		}
+
+		Ok((guard, receiver))
+	}
+
+	/// Rejects the replies which arrived after the query stopped reading them.
+	async fn discard(&self, label: &str, mut receiver: mpsc::UnboundedReceiver<Delivery>) -> Result<()>
+	{
+		receiver.close();
+		while let Ok(delivery) = receiver.try_recv()
+		{
+			trace!("[amqp-remote_query][{}] late response; rejecting", label);
+			reject(&delivery, self.no_ack).await?;
+		}
+
+		Ok(())
+	}
+}
+
+impl Drop for RemoteQuery
+{
+	fn drop(&mut self)
+	{
+		self.dispatcher.abort();
+	}
+}
+
+async fn ack(delivery: &Delivery, no_ack: bool) -> Result<()>
+{
+	if !no_ack
+	{
+		delivery.acker.ack(BasicAckOptions
+		{
+			multiple: false,
+		}).await?;
+	}
+
+	Ok(())
+}
+
+async fn reject(delivery: &Delivery, no_ack: bool) -> Result<()>
+{
+	if !no_ack
+	{
+		delivery.acker.reject(BasicRejectOptions
+		{
+			requeue: false,
+		}).await?;
+	}
+
+	Ok(())
+}
+
+/// Removes the registration of a query once it finishes, no matter how.
+struct PendingGuard<'a>
+{
+	pending: &'a Pending,
+	correlation_id: Uuid,
+}
+
+impl Drop for PendingGuard<'_>
+{
+	fn drop(&mut self)
+	{
+		self.pending.lock().unwrap().remove(&self.correlation_id);
+	}
+}
+
 #[async_trait::async_trait]
 impl RemoteQueryTrait for RemoteQuery
 {
 	fn name(&self) -> String
 	{
-		self.response_queue.name().to_string()
+		self.reply_to.to_string()
 	}
 
-	async fn remote_query(&self, name: &ContainerName) -> Result<Option<Vec<Ipv6Addr>>>
+	async fn close(&self) -> Result<()>
 	{
-		debug!("[amqp-remote_query][{}] starting query", name.as_ref());
+		debug!("[amqp-remote_query][{}] closing channel", self.reply_to);
+		self.dispatcher.abort();
+		self.channel.close(REPLY_SUCCESS, "query backend closed".into()).await?;
 
-		let mut consumer = self.channel.basic_consume(self.response_queue.name().clone(),
-			"".into(),
-			BasicConsumeOptions
-			{
-				no_ack: false,
-				no_local: true,
-				..Default::default()
-			},
-			Default::default()
-		).await.with_context(|| "error in basic_consume")?;
+		Ok(())
+	}
 
-		let correlation_id = Uuid::new_v4();
-		trace!("[amqp-remote_query][{}] correlation id: {}", name.as_ref(), correlation_id);
+	fn diagnostics(&self) -> Value
+	{
+		json!(
+		{
+			"queue": self.reply_to.as_str(),
+			"pending": self.pending.lock().unwrap().len(),
+			"unroutable": self.unroutable.load(Ordering::Relaxed),
+			"responders": self.directory.live(),
+		})
+	}
 
-		self.channel.basic_publish("lxddns".into(), "lxddns".into(), Default::default(), name.as_ref().as_bytes(),
-			AMQPProperties::default()
-				.with_correlation_id(format!("{}", correlation_id).into())
-				.with_reply_to(self.response_queue.name().clone())
-		).await.with_context(|| "error in basic_publish")?;
-		trace!("[amqp-remote_query][{}][{}] published message", name.as_ref(), correlation_id);
+	async fn remote_query(&self, name: &ContainerName) -> Result<Option<Vec<Ipv6Addr>>>
+	{
+		debug!("[amqp-remote_query][{}] starting query", name.as_ref());
+
+		let (guard, mut receiver) = self.publish(name.as_ref(), name.as_ref().as_bytes(), Some(REQUEST_LOOKUP)).await?;
+		let correlation_id = guard.correlation_id;
 
 		let mut result = None;
 
+		// once every responder known to be alive has answered there is nothing left to wait for
+		let expected = self.directory.live_ids_with(REPLY_NO_MATCH);
+		let mut answered = HashSet::new();
+		trace!("[amqp-remote_query][{}][{}] expecting answers from {:?}", name.as_ref(), correlation_id, expected);
+
 		// FIXME: this timeout needs to be configurable
 		//  the timeout strongly depends on the latency between hosts, in my case ~250ms at most
 		let mut timer = Duration::from_millis(2000);
 		let extension = Duration::from_millis(250);
 		let instant = Instant::now();
 
-		while let Ok(Some(Ok(delivery))) = timeout(timer.saturating_sub(instant.elapsed()), consumer.next()).await
+		while let Ok(Some(delivery)) = timeout(timer.saturating_sub(instant.elapsed()), receiver.recv()).await
 		{
 			let elapsed = instant.elapsed();
 			trace!("[amqp-remote_query][{}][{}] got response after {:.3}s", name.as_ref(), correlation_id, elapsed.as_secs_f64());
 
-			let received_id = match delivery.properties.correlation_id()
+			if delivery.properties.kind().as_ref().is_some_and(|kind| kind.as_str() == REPLY_NO_MATCH)
 			{
-				Some(received_id) => received_id,
-				None =>
-				{
-					info!("[amqp-remote_query][{}][{}] response without correlation_id; rejecting", name.as_ref(), correlation_id);
-					delivery.acker.reject(BasicRejectOptions
-					{
-						requeue: false,
-					}).await?;
-					continue;
-				},
-			};
+				debug!("[amqp-remote_query][{}][{}] got no match after {:.3}s from {:?}", name.as_ref(), correlation_id, elapsed.as_secs_f64(), delivery.properties.app_id());
+				ack(&delivery, self.no_ack).await?;
+			}
+			else if let Ok(addresses) = delivery.data.chunks(16)
+				.map(|v| Ok(Ipv6Addr::from(u128::from_le_bytes(v.to_vec().try_into()?))))
+				.collect::<std::result::Result<Vec<_>,Vec<_>>>()
+			{
+				// only a match shortens the wait, negative replies usually arrive quicker than the match
+				timer = elapsed + (elapsed + 2*extension)/2;
+
+				debug!("[amqp-remote_query][{}][{}] got response after {:.3}s: {:?}", name.as_ref(), correlation_id, instant.elapsed().as_secs_f64(), addresses);
+				result.get_or_insert_with(Vec::new).extend(addresses);
+				ack(&delivery, self.no_ack).await?;
+			}
+			else
+			{
+				debug!("[amqp-remote_query][{}][{}] invalid content; rejecting", name.as_ref(), correlation_id);
+				reject(&delivery, self.no_ack).await?;
+			}
 
-			let received_id = match Uuid::parse_str(received_id.as_str())
+			if let Some(host_id) = delivery.properties.app_id()
 			{
-				Ok(received_id) => received_id,
-				Err(_) =>
-				{
-					info!("[amqp-remote_query][{}][{}] response with invalid correlation_id: {}; rejecting", name.as_ref(), correlation_id, received_id);
-					delivery.acker.reject(BasicRejectOptions
-					{
-						requeue: false,
-					}).await?;
-					continue;
-				},
-			};
+				answered.insert(host_id.to_string());
+			}
 
-			if received_id.ne(&correlation_id)
+			if expected.as_ref().is_some_and(|expected| expected.is_subset(&answered))
 			{
-				debug!("[amqp-remote_query][{}][{}] unrelated message received; rejecting", name.as_ref(), correlation_id);
-				delivery.acker.reject(BasicRejectOptions
-				{
-					requeue: false,
-				}).await?;
-				continue;
+				debug!("[amqp-remote_query][{}][{}] all {} live responders answered after {:.3}s (match: {})", name.as_ref(), correlation_id, answered.len(), instant.elapsed().as_secs_f64(), result.is_some());
+				break;
 			}
+		}
 
-			timer = elapsed + (elapsed + 2*extension)/2;
+		// responses which arrived after the timer ran out are not going to be read
+		self.discard(name.as_ref(), receiver).await?;
 
-			if let Ok(addresses) = delivery.data.chunks(16)
-				.map(|v| Ok(Ipv6Addr::from(u128::from_le_bytes(v.to_vec().try_into()?))))
-				.collect::<std::result::Result<Vec<_>,Vec<_>>>()
+		// without a match a responder which would have denied but did not might well host the instance
+		// if not every responder is known to deny, silence is all there is to go by, as before negative replies
+		if let Some(expected) = expected.filter(|expected| result.is_none() && !expected.is_subset(&answered))
+		{
+			debug!("[amqp-remote_query][{}][{}] no match and {} of {} live responders answered", name.as_ref(), correlation_id, answered.intersection(&expected).count(), expected.len());
+			bail!(Error::BackendTimeout);
+		}
+
+		Ok(result)
+	}
+
+	async fn metadata(&self, name: Option<&ContainerName>) -> Result<Vec<InstanceMetadata>>
+	{
+		let label = name.map(AsRef::as_ref).unwrap_or("*");
+		debug!("[amqp-remote_query][{}] starting metadata query", label);
+
+		let body = name.map(AsRef::as_ref).unwrap_or("");
+		let (guard, mut receiver) = self.publish(label, body.as_bytes(), Some(REQUEST_METADATA)).await?;
+		let correlation_id = guard.correlation_id;
+
+		let mut result = Vec::new();
+		let expected = self.directory.live_ids_with(REPLY_METADATA);
+		let mut answered = HashSet::new();
+
+		// FIXME: this timeout needs to be configurable, see remote_query
+		let timer = Duration::from_millis(2000);
+		let instant = Instant::now();
+
+		while let Ok(Some(delivery)) = timeout(timer.saturating_sub(instant.elapsed()), receiver.recv()).await
+		{
+			if delivery.properties.kind().as_ref().is_some_and(|kind| kind.as_str() == REPLY_METADATA)
 			{
-				debug!("[amqp-remote_query][{}][{}] got response after {:.3}s: {:?}", name.as_ref(), correlation_id, instant.elapsed().as_secs_f64(), addresses);
-				result.get_or_insert_with(Vec::new).extend(addresses);
-				delivery.acker.ack(BasicAckOptions
+				match serde_json::from_slice::<Vec<InstanceMetadata>>(&delivery.data)
 				{
-					multiple: false,
-				}).await?;
+					Ok(metadata) =>
+					{
+						trace!("[amqp-remote_query][{}][{}] got metadata of {} instances", label, correlation_id, metadata.len());
+						result.extend(metadata);
+						ack(&delivery, self.no_ack).await?;
+					},
+					Err(err) =>
+					{
+						debug!("[amqp-remote_query][{}][{}] invalid metadata ({}); rejecting", label, correlation_id, err);
+						reject(&delivery, self.no_ack).await?;
+					},
+				}
 			}
 			else
 			{
-				debug!("[amqp-remote_query][{}][{}] invalid content; rejecting", name.as_ref(), correlation_id);
-				delivery.acker.reject(BasicRejectOptions
-				{
-					requeue: false,
-				}).await?;
+				// responders predating metadata take the request for a lookup
+				debug!("[amqp-remote_query][{}][{}] responder {:?} does not support metadata", label, correlation_id, delivery.properties.app_id());
+				ack(&delivery, self.no_ack).await?;
+			}
+
+			if let Some(host_id) = delivery.properties.app_id()
+			{
+				answered.insert(host_id.to_string());
 			}
+
+			// an instance lives on a single host, so its metadata is complete once it arrived
+			if name.is_some() && !result.is_empty()
+			{
+				debug!("[amqp-remote_query][{}][{}] got the instance after {:.3}s", label, correlation_id, instant.elapsed().as_secs_f64());
+				break;
+			}
+
+			// otherwise there is no telling whether another responder has more, so every one is waited for
+			if expected.as_ref().is_some_and(|expected| expected.is_subset(&answered))
+			{
+				debug!("[amqp-remote_query][{}][{}] all {} live responders answered after {:.3}s", label, correlation_id, answered.len(), instant.elapsed().as_secs_f64());
+				break;
+			}
+		}
+
+		self.discard(label, receiver).await?;
+
+		// partial metadata would silently drop records of the responders which did not answer
+		if let Some(expected) = expected.filter(|expected| (name.is_none() || result.is_empty()) && !expected.is_subset(&answered))
+		{
+			debug!("[amqp-remote_query][{}][{}] {} of {} live responders answered", label, correlation_id, answered.intersection(&expected).count(), expected.len());
+			bail!(Error::BackendTimeout);
 		}
 
 		Ok(result)
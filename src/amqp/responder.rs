@@ -12,22 +12,40 @@ use crate::
 	{
 		ContainerName,
 	},
+	amqp::
+	{
+		presence::Announcer,
+		REPLY_MATCH,
+		REPLY_METADATA,
+		REPLY_NO_MATCH,
+		REQUEST_LOOKUP,
+		REQUEST_METADATA,
+	},
+	shutdown::Shutdown,
 };
 
 use ::
 {
 	lapin::
 	{
+		Confirmation,
 		Connection,
 		ExchangeKind,
 		options::
 		{
 			BasicAckOptions,
+			BasicCancelOptions,
 			BasicConsumeOptions,
+			BasicPublishOptions,
 			BasicRejectOptions,
+			ConfirmSelectOptions,
 			QueueDeclareOptions,
 		},
-		protocol::basic::AMQPProperties,
+		protocol::
+		{
+			basic::AMQPProperties,
+			constants::REPLY_SUCCESS,
+		},
 	},
 	log::
 	{
@@ -46,15 +64,26 @@ use ::
 	std::
 	{
 		sync::Arc,
+		time::Duration,
 	},
 };
 
+/// Request as told apart by the message type.
+enum Request
+{
+	Lookup(ContainerName),
+	Metadata(Option<ContainerName>),
+}
+
 pub struct Responder
 {
 	command: String,
 	connection: Connection,
 	queue_name: String,
 	responder_workers: usize,
+	host_id: String,
+	presence_interval: Duration,
+	shutdown: Shutdown,
 }
 
 impl Responder
@@ -97,24 +126,37 @@ impl Responder
 			},
 			Default::default()
 		).await?;
-		info!("[responder] running");
+		let _announcer = Announcer::start(&self.connection, self.command.clone(), self.host_id.clone(), self.presence_interval).await?;
+		info!("[responder] running as {}", self.host_id);
+		let ready = crate::systemd::ready();
 
 		let me = Arc::new(&self);
+		let consumer_tag = consumer.tag();
 
-		consumer.err_into::<anyhow::Error>().try_for_each_concurrent(self.responder_workers, |delivery|
+		let serve = consumer.err_into::<anyhow::Error>().try_for_each_concurrent(self.responder_workers, |delivery|
 		{
 			let me = me.clone();
 
 			async move
 			{
 				debug!("[responder] received message");
-				let name = String::from_utf8_lossy(&delivery.data);
+				let name = String::from_utf8_lossy(&delivery.data).to_string();
 				debug!("[responder][{}] received request", name);
 
 				let channel = me.connection.create_channel().await.context(Error::QueueConnectionError)?;
+				channel.confirm_select(ConfirmSelectOptions::default()).await.context(Error::QueueConnectionError)?;
 				debug!("[responder][{}] channel created", name);
 
-				let name = match name.parse::<ContainerName>()
+				let metadata = delivery.properties.kind().as_ref().is_some_and(|kind| kind.as_str() == REQUEST_METADATA);
+				// queriers predating negative replies take any reply for a match
+				let negative = delivery.properties.kind().as_ref().is_some_and(|kind| kind.as_str() == REQUEST_LOOKUP);
+				let request = match (metadata, name.as_str())
+				{
+					(true, "") => Ok(Request::Metadata(None)),
+					(true, _) => name.parse().map(|name| Request::Metadata(Some(name))),
+					(false, _) => name.parse().map(Request::Lookup),
+				};
+				let request = match request
 				{
 					Ok(ok) => ok,
 					Err(_) =>
@@ -133,7 +175,7 @@ impl Responder
 					(Some(reply_to), Some(corr_id)) => (reply_to, corr_id),
 					_ =>
 					{
-						info!("[responder][{}] message without reply_to or correlation_id; rejecting", name.as_ref());
+						info!("[responder][{}] message without reply_to or correlation_id; rejecting", name);
 						delivery.acker.reject(BasicRejectOptions
 						{
 							requeue: false,
@@ -142,17 +184,38 @@ impl Responder
 					}
 				};
 
-				let addresses = match crate::lxd::local_query(&me.command, &name).await
+				let reply = match request
 				{
-					Ok(Some(addresses)) =>
+					Request::Lookup(ref name) => crate::lxd::local_query(&me.command, name).await.map(|addresses| match addresses
 					{
-						trace!("[responder][{}][{}] got {:?}", name.as_ref(), corr_id, addresses);
+						Some(addresses) =>
+						{
+							trace!("[responder][{}][{}] got {:?}", name.as_ref(), corr_id, addresses);
 
-						addresses
-					},
-					Ok(_) =>
+							Some((REPLY_MATCH, addresses.into_iter().flat_map(|addr| u128::from(addr).to_le_bytes().to_vec()).collect::<Vec<u8>>()))
+						},
+						None if negative =>
+						{
+							trace!("[responder][{}][{}] no info; replying with no match", name.as_ref(), corr_id);
+
+							Some((REPLY_NO_MATCH, Vec::new()))
+						},
+						None => None,
+					}),
+					Request::Metadata(ref name) => crate::lxd::local_metadata(&me.command, name.as_ref()).await
+						.and_then(|metadata|
+						{
+							trace!("[responder][{}][{}] got metadata of {} instances", name.as_ref().map(AsRef::as_ref).unwrap_or("*"), corr_id, metadata.len());
+
+							Ok(Some((REPLY_METADATA, serde_json::to_vec(&metadata)?)))
+						}),
+				};
+				let (kind, response) = match reply
+				{
+					Ok(Some(reply)) => reply,
+					Ok(None) =>
 					{
-						trace!("[responder][{}] no info; rejecting", name.as_ref());
+						trace!("[responder][{}][{}] no info; rejecting", name, corr_id);
 						delivery.acker.reject(BasicRejectOptions
 						{
 							requeue: false,
@@ -161,10 +224,10 @@ impl Responder
 					},
 					Err(err) =>
 					{
-						warn!("[responder][{}][{}] query error: {}", name.as_ref(), corr_id, err);
+						warn!("[responder][{}][{}] query error: {}", name, corr_id, err);
 						for err in err.chain().skip(1)
 						{
-							warn!("[responder][{}][{}]  caused by: {}", name.as_ref(), corr_id, err);
+							warn!("[responder][{}][{}]  caused by: {}", name, corr_id, err);
 						}
 						delivery.acker.reject(BasicRejectOptions
 						{
@@ -173,13 +236,27 @@ impl Responder
 						return Ok(());
 					},
 				};
-				let response = addresses.into_iter().flat_map(|addr| u128::from(addr).to_le_bytes().to_vec()).collect::<Vec<u8>>();
 
-				channel.basic_publish("".into(), reply_to.clone(), Default::default(), &response,
+				let confirmation = channel.basic_publish("".into(), reply_to.clone(),
+					BasicPublishOptions
+					{
+						mandatory: true,
+						..Default::default()
+					},
+					&response,
 					AMQPProperties::default()
 						.with_correlation_id(corr_id.clone())
-				).await.context("basic_publish")?;
-				trace!("[responder][{}][{}] message published to {}", name.as_ref(), corr_id, reply_to);
+						.with_app_id(me.host_id.clone().into())
+						.with_type(kind.into())
+				).await.context("basic_publish")?
+					.await.context("publisher confirm")?;
+
+				match confirmation
+				{
+					Confirmation::Ack(Some(returned)) => debug!("[responder][{}][{}] {} returned ({} {}), querier is gone", name, corr_id, kind, returned.reply_code, returned.reply_text),
+					Confirmation::Nack(_) => warn!("[responder][{}][{}] {} not acknowledged by broker", name, corr_id, kind),
+					_ => trace!("[responder][{}][{}] {} published to {}", name, corr_id, kind, reply_to),
+				}
 
 				delivery.acker.ack(BasicAckOptions
 				{
@@ -188,19 +265,51 @@ impl Responder
 
 				Ok(())
 			}
-		}).await.context("responder loop error")?;
+		});
+
+		// cancelling the consumer ends its stream once the deliveries in flight are answered
+		let cancel = async
+		{
+			self.shutdown.wait().await;
+			info!("[responder] cancelling consumer");
+			if let Err(err) = channel.basic_cancel(consumer_tag, BasicCancelOptions::default()).await
+			{
+				warn!("[responder] cannot cancel consumer: {}", err);
+			}
+			futures::future::pending::<()>().await;
+		};
+
+		let result = tokio::select!
+		{
+			result = self.shutdown.drain(serve) => result,
+			_ = cancel => unreachable!(),
+		};
+		match result
+		{
+			Some(result) => result.context("responder loop error")?,
+			None => warn!("[responder] requests cut off by shutdown"),
+		}
+
+		drop(ready);
+		if let Err(err) = self.connection.close(REPLY_SUCCESS, "responder stopped".into()).await
+		{
+			debug!("[responder] error closing connection: {}", err);
+		}
 
 		Ok(())
 	}
 }
 
-#[derive(Clone, Eq, PartialEq, Hash, Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct ResponderBuilder
 {
 	command: Option<String>,
 	url: Option<String>,
 	queue_name: Option<String>,
 	responder_workers: Option<usize>,
+	host_id: Option<String>,
+	presence_interval: Option<Duration>,
+	shutdown: Option<Shutdown>,
 }
 
 impl ResponderBuilder
@@ -229,12 +338,33 @@ impl ResponderBuilder
 		self
 	}
 
+	pub fn host_id<S: AsRef<str>>(mut self, host_id: S) -> Self
+	{
+		self.host_id = Some(host_id.as_ref().into());
+		self
+	}
+
+	pub fn presence_interval(mut self, presence_interval: Duration) -> Self
+	{
+		self.presence_interval = Some(presence_interval);
+		self
+	}
+
+	pub fn shutdown(mut self, shutdown: Shutdown) -> Self
+	{
+		self.shutdown = Some(shutdown);
+		self
+	}
+
 	pub async fn run(self) -> Result<()>
 	{
 		let command = self.command.map(Result::Ok).unwrap_or_else(|| bail!("no command provided")).context(Error::InvalidConfiguration)?;
 		let url = self.url.map(Result::Ok).unwrap_or_else(|| bail!("no url provided")).context(Error::InvalidConfiguration)?;
 		let queue_name = self.queue_name.unwrap_or_default();
 		let responder_workers = self.responder_workers.unwrap_or(8);
+		let host_id = self.host_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+		let presence_interval = self.presence_interval.unwrap_or(Duration::from_secs(10));
+		let shutdown = self.shutdown.unwrap_or_default();
 
 		let connection = Connection::connect(url.as_ref(), Default::default())
 			.await
@@ -250,6 +380,9 @@ impl ResponderBuilder
 			connection,
 			queue_name,
 			responder_workers,
+			host_id,
+			presence_interval,
+			shutdown,
 		}.run().await
 	}
 }
@@ -11,7 +11,35 @@ use crate::
 	pdns::
 	{
 		TtlConfig,
+		Zone,
+		ZoneConfig,
 	},
+	pdns_io::
+	{
+		RemoteQuery,
+	},
+	coalesce::
+	{
+		CoalescingQuery,
+		Inflight,
+	},
+	cache::
+	{
+		AnswerCache,
+		CacheConfig,
+		CachedQuery,
+	},
+	soa::
+	{
+		Soa,
+		SoaConfig,
+	},
+	amqp::
+	{
+		presence::Directory,
+		ReplyQueue,
+	},
+	shutdown::Shutdown,
 };
 
 use ::
@@ -19,6 +47,7 @@ use ::
 	lapin::
 	{
 		Connection,
+		protocol::constants::REPLY_SUCCESS,
 	},
 	tokio_stream::wrappers::UnixListenerStream,
 	tokio::
@@ -38,6 +67,7 @@ use ::
 	std::
 	{
 		os::unix::fs::FileTypeExt,
+		sync::Arc,
 	},
 };
 
@@ -47,8 +77,15 @@ pub struct Unix
 	hostmaster: String,
 	ttl_config: TtlConfig,
 	connection: Connection,
+	reply_queue: ReplyQueue,
 	unixpath: String,
 	unix_workers: usize,
+	pipeline_depth: usize,
+	coalesce: bool,
+	cache_config: CacheConfig,
+	soa_config: SoaConfig,
+	zone: Zone,
+	shutdown: Shutdown,
 }
 
 impl Unix
@@ -62,35 +99,58 @@ impl Unix
 	{
 		debug!("[unix] started");
 
-		match metadata(&self.unixpath).await
+		// a socket passed by the service manager is owned by it, including the socket file
+		let activated = crate::systemd::take_unix_listener();
+		let owns_socket = activated.is_none();
+		let listener = match activated
 		{
-			Ok(metadata) =>
+			Some(listener) => UnixListener::from_std(listener)?,
+			None =>
 			{
-				if metadata.file_type().is_socket()
-				{
-					warn!("[unix] removing potentially stale socket");
-					remove_file(&self.unixpath).await?;
-				}
-				else
+				match metadata(&self.unixpath).await
 				{
-					Err(Error::UnixServerError).with_context(|| format!("unix socket exists and is not a file: {}", self.unixpath))?;
+					Ok(metadata) =>
+					{
+						if metadata.file_type().is_socket()
+						{
+							warn!("[unix] removing potentially stale socket");
+							remove_file(&self.unixpath).await?;
+						}
+						else
+						{
+							Err(Error::UnixServerError).with_context(|| format!("unix socket exists and is not a file: {}", self.unixpath))?;
+						}
+					},
+					Err(err) =>
+					{
+						if err.kind() != std::io::ErrorKind::NotFound
+						{
+							bail!(err);
+						}
+					},
 				}
-			},
-			Err(err) =>
-			{
-				if err.kind() != std::io::ErrorKind::NotFound
-				{
-					bail!(err);
-				}
-			},
-		}
 
-		let listener = UnixListener::bind(&self.unixpath)?;
+				UnixListener::bind(&self.unixpath)?
+			},
+		};
 		info!("[unix] unix socket opened");
 
-		UnixListenerStream::new(listener).map(|res| res.context(Error::UnixServerError)).try_for_each_concurrent(self.unix_workers, |stream|
+		let inflight = Inflight::default();
+		let cache = self.cache_config.enabled().then(|| AnswerCache::new(&self.cache_config, &self.ttl_config));
+		let soa = Soa::new(self.soa_config.clone());
+		self.zone.records().reload_on_signal()?;
+		let directory = Arc::new(Directory::new(&self.connection).await?);
+
+		let ready = crate::systemd::ready();
+
+		// stop accepting connections on shutdown, the open ones stop reading requests themselves
+		let serve = UnixListenerStream::new(listener).take_until(self.shutdown.wait()).map(|res| res.context(Error::UnixServerError)).try_for_each_concurrent(self.unix_workers, |stream|
 		{
 			let me = &self;
+			let inflight = &inflight;
+			let cache = &cache;
+			let soa = &soa;
+			let directory = directory.clone();
 			async move
 			{
 				debug!("[unix] connection opened");
@@ -98,24 +158,55 @@ impl Unix
 				let channel = me.connection.create_channel().await?;
 				debug!("[unix] channel created");
 
-				let backend = super::query::RemoteQuery::new(channel).await?;
+				let backend = super::query::RemoteQuery::new(channel, directory, me.reply_queue).await?;
+				let backend: Box<dyn RemoteQuery+Send+Sync> = if me.coalesce
+				{
+					Box::new(CoalescingQuery::new(backend, inflight.clone()))
+				}
+				else
+				{
+					Box::new(backend)
+				};
+				// only misses reach the coalescing, a hit needs no merging
+				let backend: Box<dyn RemoteQuery+Send+Sync> = match cache
+				{
+					Some(cache) => Box::new(CachedQuery::new(backend, cache.clone())),
+					None => backend,
+				};
 				let (read, write) = stream.into_split();
-				let handler = crate::pdns_io::PdnsStreamHandler::new(&me.domain, &me.ttl_config, &me.hostmaster, backend, read, write).await?;
+				let handler = crate::pdns_io::PdnsStreamHandler::new(&me.domain, &me.ttl_config, &me.hostmaster, backend, read, write).await?
+					.pipeline_depth(me.pipeline_depth)
+					.soa(soa.clone())
+					.zone(me.zone.clone())
+					.shutdown(me.shutdown.clone());
 				handler.run().await?;
 
 				debug!("[unix] connection closed");
 				Ok(())
 			}
-		}).await?;
+		});
 
-		remove_file(self.unixpath).await?;
+		if self.shutdown.drain(serve).await.transpose()?.is_none()
+		{
+			warn!("[unix] connections cut off by shutdown");
+		}
+
+		drop(ready);
+		if owns_socket
+		{
+			remove_file(self.unixpath).await?;
+		}
+		if let Err(err) = self.connection.close(REPLY_SUCCESS, "backend stopped".into()).await
+		{
+			debug!("[unix] error closing connection: {}", err);
+		}
 		debug!("[unix] stopped");
 
 		Ok(())
 	}
 }
 
-#[derive(Clone,Eq,PartialEq,Hash,Debug,Default)]
+#[derive(Clone,Debug,Default)]
 pub struct UnixBuilder
 {
 	url: Option<String>,
@@ -123,7 +214,14 @@ pub struct UnixBuilder
 	hostmaster: Option<String>,
 	unixpath: Option<String>,
 	ttl_config: Option<TtlConfig>,
+	reply_queue: Option<ReplyQueue>,
 	unix_workers: Option<usize>,
+	pipeline_depth: Option<usize>,
+	coalesce: Option<bool>,
+	cache_config: Option<CacheConfig>,
+	soa_config: Option<SoaConfig>,
+	zone_config: Option<ZoneConfig>,
+	shutdown: Option<Shutdown>,
 }
 
 impl UnixBuilder
@@ -158,12 +256,54 @@ impl UnixBuilder
 		self
 	}
 
+	pub fn reply_queue(mut self, reply_queue: ReplyQueue) -> Self
+	{
+		self.reply_queue = Some(reply_queue);
+		self
+	}
+
 	pub fn unix_workers(mut self, unix_workers: usize) -> Self
 	{
 		self.unix_workers = Some(unix_workers);
 		self
 	}
 
+	pub fn pipeline_depth(mut self, pipeline_depth: usize) -> Self
+	{
+		self.pipeline_depth = Some(pipeline_depth);
+		self
+	}
+
+	pub fn coalesce(mut self, coalesce: bool) -> Self
+	{
+		self.coalesce = Some(coalesce);
+		self
+	}
+
+	pub fn soa_config(mut self, soa_config: SoaConfig) -> Self
+	{
+		self.soa_config = Some(soa_config);
+		self
+	}
+
+	pub fn zone_config(mut self, zone_config: ZoneConfig) -> Self
+	{
+		self.zone_config = Some(zone_config);
+		self
+	}
+
+	pub fn cache_config(mut self, cache_config: CacheConfig) -> Self
+	{
+		self.cache_config = Some(cache_config);
+		self
+	}
+
+	pub fn shutdown(mut self, shutdown: Shutdown) -> Self
+	{
+		self.shutdown = Some(shutdown);
+		self
+	}
+
 	pub async fn run(self) -> Result<()>
 	{
 		let url = self.url.map(Result::Ok).unwrap_or_else(|| bail!("no url provided")).context(Error::InvalidConfiguration)?;
@@ -171,7 +311,14 @@ impl UnixBuilder
 		let hostmaster = self.hostmaster.map(Result::Ok).unwrap_or_else(|| bail!("no hostmaster provided")).context(Error::InvalidConfiguration)?;
 		let unixpath = self.unixpath.map(Result::Ok).unwrap_or_else(|| bail!("no unixpath provided")).context(Error::InvalidConfiguration)?;
 		let ttl_config = self.ttl_config.unwrap_or_default();
+		let reply_queue = self.reply_queue.unwrap_or_default();
 		let unix_workers = self.unix_workers.unwrap_or(0);
+		let pipeline_depth = self.pipeline_depth.unwrap_or(1);
+		let coalesce = self.coalesce.unwrap_or(false);
+		let cache_config = self.cache_config.unwrap_or_default();
+		let soa_config = self.soa_config.unwrap_or_default();
+		let zone = Zone::new(&domain, &self.zone_config.unwrap_or_default())?;
+		let shutdown = self.shutdown.unwrap_or_default();
 
 		let connection = Connection::connect(url.as_ref(), Default::default())
 			.await
@@ -185,8 +332,15 @@ impl UnixBuilder
 			hostmaster,
 			ttl_config,
 			connection,
+			reply_queue,
 			unixpath,
 			unix_workers,
+			pipeline_depth,
+			coalesce,
+			cache_config,
+			soa_config,
+			zone,
+			shutdown,
 		}.run().await
 	}
 }
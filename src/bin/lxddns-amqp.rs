@@ -15,11 +15,16 @@ use ::
 			Responder,
 			Pipe,
 			Unix,
+			ReplyQueue,
 		},
 		pdns::
 		{
 			TtlConfig,
+			ZoneConfig,
 		},
+		cache::CacheConfig,
+		soa::SoaConfig,
+		shutdown::Shutdown,
 	},
 	clap::
 	{
@@ -30,6 +35,10 @@ use ::
 		info,
 		error,
 	},
+	std::
+	{
+		time::Duration,
+	},
 };
 
 // check https://github.com/clap-rs/clap/issues/3221 at a later time
@@ -42,6 +51,10 @@ struct Args
 	#[clap(short = 'v', long, global = true)]
 	loglevel: Option<String>,
 
+	/// Seconds granted to requests in flight after SIGTERM or SIGINT before they are cut off
+	#[clap(long, value_name = "SECONDS", default_value = "10", global = true)]
+	shutdown_timeout: u64,
+
 	#[clap(subcommand)]
 	command: Command,
 }
@@ -68,6 +81,14 @@ enum Command
 		/// Number of parallel worker threads for message queue responders (0: unlimited)
 		#[clap(long, value_name = "THREAD_COUNT", default_value = "2")]
 		responder_workers: usize,
+
+		/// Identity announced to and sent along with responses to the query side; if not specified uses a random id
+		#[clap(long, value_name = "HOST_ID", env = "LXDDNS_HOST_ID")]
+		host_id: Option<String>,
+
+		/// Seconds in between presence announcements
+		#[clap(long, value_name = "SECONDS", default_value = "10")]
+		presence_interval: u64,
 	},
 
 	/// Run the AMQP remote backend via a stdio pipe for PowerDNS
@@ -86,8 +107,21 @@ enum Command
 		#[clap(short, long)]
 		domain: String,
 
+		/// Queue responders send their replies to
+		#[clap(long, value_name = "REPLY_QUEUE", default_value = "exclusive")]
+		reply_queue: ReplyQueue,
+
 		#[command(flatten)]
 		ttl_config: TtlConfig,
+
+		#[command(flatten)]
+		cache_config: CacheConfig,
+
+		#[command(flatten)]
+		soa_config: SoaConfig,
+
+		#[command(flatten)]
+		zone_config: ZoneConfig,
 	},
 
 	/// Run the AMQP remote backend via a Unix Domain Socket for PowerDNS
@@ -106,6 +140,10 @@ enum Command
 		#[clap(short, long)]
 		domain: String,
 
+		/// Queue responders send their replies to
+		#[clap(long, value_name = "REPLY_QUEUE", default_value = "exclusive")]
+		reply_queue: ReplyQueue,
+
 		/// Location of the unix domain socket to be created
 		#[clap(short, long, value_name = "SOCKET_PATH", default_value = "/var/run/lxddns/lxddns.sock")]
 		socket: String,
@@ -114,8 +152,25 @@ enum Command
 		#[clap(long, value_name = "THREAD_COUNT", default_value = "2")]
 		unix_workers: usize,
 
+		/// Number of requests per connection read ahead and resolved concurrently (responses keep their order)
+		#[clap(long, value_name = "DEPTH", default_value = "1")]
+		pipeline_depth: usize,
+
+		/// Merge identical lookups in flight across all connections into a single backend query
+		#[clap(long)]
+		coalesce: bool,
+
 		#[command(flatten)]
 		ttl_config: TtlConfig,
+
+		#[command(flatten)]
+		cache_config: CacheConfig,
+
+		#[command(flatten)]
+		soa_config: SoaConfig,
+
+		#[command(flatten)]
+		zone_config: ZoneConfig,
 	},
 }
 
@@ -136,43 +191,72 @@ async fn main() -> std::result::Result<(), u8>
 
 	info!("[main] logging initialised");
 
+	let shutdown = match Shutdown::on_signal(Duration::from_secs(args.shutdown_timeout))
+	{
+		Ok(shutdown) => shutdown,
+		Err(err) =>
+		{
+			error!("[main] fatal error occured: {}", err);
+			return Err(1);
+		},
+	};
+
 	let res = match args.command
 	{
-		Command::Pipe { url, ttl_config, domain, hostmaster, } =>
+		Command::Pipe { url, ttl_config, cache_config, soa_config, zone_config, domain, hostmaster, reply_queue, } =>
 		{
 			let pipe = Pipe::builder()
+				.shutdown(shutdown)
 				.url(url)
 				.domain(domain)
 				.ttl_config(ttl_config)
+				.cache_config(cache_config)
+				.soa_config(soa_config)
+				.zone_config(zone_config)
 				.hostmaster(hostmaster)
+				.reply_queue(reply_queue)
 			;
 
 			info!("[main] running pipe");
 			pipe.run().await
 		},
-		Command::Unix { url, ttl_config, domain, hostmaster, socket, unix_workers, } =>
+		Command::Unix { url, ttl_config, cache_config, soa_config, zone_config, domain, hostmaster, reply_queue, socket, unix_workers, pipeline_depth, coalesce, } =>
 		{
 			let unix = Unix::builder()
+				.shutdown(shutdown)
 				.url(url)
 				.domain(domain)
 				.hostmaster(hostmaster)
 				.unixpath(socket)
 				.ttl_config(ttl_config)
+				.cache_config(cache_config)
+				.soa_config(soa_config)
+				.zone_config(zone_config)
+				.reply_queue(reply_queue)
 				.unix_workers(unix_workers)
+				.pipeline_depth(pipeline_depth)
+				.coalesce(coalesce)
 			;
 
 			info!("[main] running unix");
 			unix.run().await
 		},
-		Command::Responder { command, url, queue_name, responder_workers, } =>
+		Command::Responder { command, url, queue_name, responder_workers, host_id, presence_interval, } =>
 		{
-			let responder = Responder::builder()
+			let mut responder = Responder::builder()
+				.shutdown(shutdown)
 				.command(command)
 				.url(url)
 				.queue_name(queue_name.unwrap_or_default())
 				.responder_workers(responder_workers)
+				.presence_interval(Duration::from_secs(presence_interval))
 			;
 
+			if let Some(host_id) = host_id
+			{
+				responder = responder.host_id(host_id);
+			}
+
 			info!("[main] running responder");
 			responder.run().await
 		},
@@ -15,11 +15,18 @@ use ::
 			Responder,
 			Pipe,
 			Unix,
+			QueryStrategy,
+			HealthConfig,
+			DiscoveryConfig,
 		},
 		pdns::
 		{
 			TtlConfig,
+			ZoneConfig,
 		},
+		cache::CacheConfig,
+		soa::SoaConfig,
+		shutdown::Shutdown,
 	},
 	clap::
 	{
@@ -30,6 +37,10 @@ use ::
 		info,
 		error,
 	},
+	std::
+	{
+		time::Duration,
+	},
 };
 
 // check https://github.com/clap-rs/clap/issues/3221 at a later time
@@ -42,6 +53,10 @@ struct Args
 	#[clap(short = 'v', long, global = true)]
 	loglevel: Option<String>,
 
+	/// Seconds granted to requests in flight after SIGTERM or SIGINT before they are cut off
+	#[clap(long, value_name = "SECONDS", default_value = "10", global = true)]
+	shutdown_timeout: u64,
+
 	#[clap(subcommand)]
 	command: Command,
 }
@@ -61,17 +76,33 @@ enum Command
 		#[clap(short = 'm', long, value_name = "MAX_CONNS", default_value = "32")]
 		max_connections: usize,
 
-		/// Address-Port pair to bind to for incoming HTTPS traffic.
-		#[clap(short = 'b', long, value_name = "HTTP:PORT", default_value = "[::1]:9132", env = "LXDDNS_HTTP_BIND")]
-		https_bind: String,
+		/// Address-Port pair to bind to for incoming HTTPS traffic (may be repeated, defaults to [::1]:9132 if no listener is given at all).
+		#[clap(short = 'b', long, value_name = "HTTP:PORT", env = "LXDDNS_HTTP_BIND")]
+		https_bind: Vec<String>,
+
+		/// Address-Port pair to bind to for incoming plain HTTP traffic, e.g. behind a reverse proxy terminating TLS (may be repeated).
+		#[clap(long, value_name = "HTTP:PORT")]
+		http_bind: Vec<String>,
 
-		/// File containing the TLS certificate chain.
-		#[clap(short = 'c', long, value_name = "FILE", env = "LXDDNS_HTTP_TLS_CHAIN")]
-		tls_chain: String,
+		/// Unix domain socket to create for incoming plain HTTP traffic (may be repeated).
+		#[clap(long, value_name = "SOCKET_PATH")]
+		unix_bind: Vec<String>,
 
-		/// File containing the TLS key.
+		/// File containing the TLS certificate chain, required for HTTPS.
+		#[clap(long, value_name = "FILE", env = "LXDDNS_HTTP_TLS_CHAIN")]
+		tls_chain: Option<String>,
+
+		/// File containing the TLS key, required for HTTPS.
 		#[clap(short = 'k', long, value_name = "FILE", env = "LXDDNS_HTTP_TLS_KEY")]
-		tls_key: String,
+		tls_key: Option<String>,
+
+		/// Seconds between checks for changes of the local instances while `/events/v1` has subscribers.
+		#[clap(long, value_name = "SECONDS", default_value = "5")]
+		events_interval: u64,
+
+		/// TTL suggested to querying backends in `/resolve/v2` responses, they answer with the lower of it and their own AAAA TTL.
+		#[clap(long, value_name = "SECONDS", default_value = "128")]
+		suggested_ttl: usize,
 	},
 
 	/// Run the HTTP remote backend via a stdio pipe for PowerDNS
@@ -81,9 +112,20 @@ enum Command
 		/// API root of remote instances.
 		///
 		/// The root for of a remote API with the endpoint `https://example.com/lxddns/v1/resolve` would thus be `https://example.com/lxddns`.
-		#[clap(short, long, value_name = "API_ROOT")]
+		/// Responders listening on a unix domain socket are reachable using the percent-encoded socket path as host, e.g. `http+unix://%2Frun%2Flxddns%2Fhttp.sock/lxddns`.
+		#[clap(short, long, value_name = "API_ROOT", required_unless_present_any = ["remote_srv", "remote_file"])]
 		remote: Vec<String>,
 
+		#[command(flatten)]
+		discovery_config: DiscoveryConfig,
+
+		/// When to stop waiting for remotes: "all", "first" (first match), or "quorum:N" (N agreeing matches)
+		#[clap(long, value_name = "STRATEGY", default_value = "all")]
+		strategy: QueryStrategy,
+
+		#[command(flatten)]
+		health_config: HealthConfig,
+
 		/// Hostmaster to announce in SOA (use dot notation including trailing dot as in hostmaster.example.org.)
 		#[clap(long, value_name = "SOA_HOSTMASTER")]
 		hostmaster: String,
@@ -94,6 +136,15 @@ enum Command
 
 		#[command(flatten)]
 		ttl_config: TtlConfig,
+
+		#[command(flatten)]
+		cache_config: CacheConfig,
+
+		#[command(flatten)]
+		soa_config: SoaConfig,
+
+		#[command(flatten)]
+		zone_config: ZoneConfig,
 	},
 
 	/// Run the HTTP remote backend via a Unix Domain Socket for PowerDNS
@@ -103,9 +154,20 @@ enum Command
 		/// API root of remote instances.
 		///
 		/// The root for of a remote API with the endpoint `https://example.com/lxddns/v1/resolve` would thus be `https://example.com/lxddns`.
-		#[clap(short, long, value_name = "API_ROOT")]
+		/// Responders listening on a unix domain socket are reachable using the percent-encoded socket path as host, e.g. `http+unix://%2Frun%2Flxddns%2Fhttp.sock/lxddns`.
+		#[clap(short, long, value_name = "API_ROOT", required_unless_present_any = ["remote_srv", "remote_file"])]
 		remote: Vec<String>,
 
+		#[command(flatten)]
+		discovery_config: DiscoveryConfig,
+
+		/// When to stop waiting for remotes: "all", "first" (first match), or "quorum:N" (N agreeing matches)
+		#[clap(long, value_name = "STRATEGY", default_value = "all")]
+		strategy: QueryStrategy,
+
+		#[command(flatten)]
+		health_config: HealthConfig,
+
 		/// Hostmaster to announce in SOA (use dot notation including trailing dot as in hostmaster.example.org.)
 		#[clap(long, value_name = "SOA_HOSTMASTER")]
 		hostmaster: String,
@@ -122,8 +184,25 @@ enum Command
 		#[clap(long, value_name = "THREAD_COUNT", default_value = "2")]
 		unix_workers: usize,
 
+		/// Number of requests per connection read ahead and resolved concurrently (responses keep their order)
+		#[clap(long, value_name = "DEPTH", default_value = "1")]
+		pipeline_depth: usize,
+
+		/// Merge identical lookups in flight across all connections into a single backend query
+		#[clap(long)]
+		coalesce: bool,
+
 		#[command(flatten)]
 		ttl_config: TtlConfig,
+
+		#[command(flatten)]
+		cache_config: CacheConfig,
+
+		#[command(flatten)]
+		soa_config: SoaConfig,
+
+		#[command(flatten)]
+		zone_config: ZoneConfig,
 	},
 }
 
@@ -144,42 +223,89 @@ async fn main() -> std::result::Result<(), u8>
 
 	info!("[main] logging initialised");
 
+	let shutdown = match Shutdown::on_signal(Duration::from_secs(args.shutdown_timeout))
+	{
+		Ok(shutdown) => shutdown,
+		Err(err) =>
+		{
+			error!("[main] fatal error occured: {}", err);
+			return Err(1);
+		},
+	};
+
 	let res = match args.command
 	{
-		Command::Pipe { ttl_config, remote, hostmaster, domain, } =>
+		Command::Pipe { ttl_config, cache_config, soa_config, zone_config, remote, discovery_config, strategy, health_config, hostmaster, domain, } =>
 		{
 			let pipe = Pipe::builder()
+				.shutdown(shutdown)
 				.remote(remote)
+				.discovery_config(discovery_config)
+				.strategy(strategy)
+				.health_config(health_config)
 				.domain(domain)
 				.ttl_config(ttl_config)
+				.cache_config(cache_config)
+				.soa_config(soa_config)
+				.zone_config(zone_config)
 				.hostmaster(hostmaster)
 			;
 
 			info!("[main] running http-pipe");
 			pipe.run().await
 		},
-		Command::Responder { command, https_bind, tls_chain, tls_key, max_connections, } =>
+		Command::Responder { command, https_bind, http_bind, unix_bind, tls_chain, tls_key, events_interval, suggested_ttl, max_connections, } =>
 		{
-			let responder = Responder::builder()
+			let mut responder = Responder::builder()
+				.shutdown(shutdown)
 				.command(command)
 				.max_connections(max_connections)
-				.https_bind(https_bind)
-				.tls_chain(tls_chain)
-				.tls_key(tls_key)
+				.events_interval(Duration::from_secs(events_interval))
+				.suggested_ttl(suggested_ttl)
 			;
 
+			for bind in https_bind
+			{
+				responder = responder.https_bind(bind);
+			}
+			for bind in http_bind
+			{
+				responder = responder.http_bind(bind);
+			}
+			for bind in unix_bind
+			{
+				responder = responder.unix_bind(bind);
+			}
+			if let Some(tls_chain) = tls_chain
+			{
+				responder = responder.tls_chain(tls_chain);
+			}
+			if let Some(tls_key) = tls_key
+			{
+				responder = responder.tls_key(tls_key);
+			}
+
 			info!("[main] running http-responder");
 			responder.run().await
 		},
-		Command::Unix { ttl_config, remote, domain, hostmaster, socket, unix_workers, } =>
+		Command::Unix { ttl_config, cache_config, soa_config, zone_config, remote, discovery_config, strategy, health_config, domain, hostmaster, socket, unix_workers, pipeline_depth, coalesce, } =>
 		{
 			let unix = Unix::builder()
+				.shutdown(shutdown)
 				.remote(remote)
+				.discovery_config(discovery_config)
+				.strategy(strategy)
+				.health_config(health_config)
 				.domain(domain)
 				.hostmaster(hostmaster)
 				.unixpath(socket)
 				.ttl_config(ttl_config)
+				.cache_config(cache_config)
+				.soa_config(soa_config)
+				.zone_config(zone_config)
 				.unix_workers(unix_workers)
+				.pipeline_depth(pipeline_depth)
+				.coalesce(coalesce)
 			;
 
 			info!("[main] running unix");
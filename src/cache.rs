@@ -0,0 +1,607 @@
+// Copyright (C) benaryorg <binary@benary.org>
+//
+// This software is licensed as described in the file COPYING, which
+// you should have received as part of this distribution.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::
+{
+	error::*,
+	lxd::
+	{
+		ContainerName,
+		InstanceMetadata,
+	},
+	pdns::
+	{
+		TtlConfig,
+	},
+	pdns_io::
+	{
+		RemoteQuery,
+		SharedBackend,
+	},
+};
+
+use ::
+{
+	clap::
+	{
+		Args,
+	},
+	serde_json::
+	{
+		json,
+		Value,
+	},
+	std::
+	{
+		collections::
+		{
+			BTreeMap,
+			HashMap,
+		},
+		hash::Hash,
+		net::Ipv6Addr,
+		sync::
+		{
+			Arc,
+			Mutex,
+			atomic::
+			{
+				AtomicU64,
+				Ordering,
+			},
+		},
+		time::
+		{
+			Duration,
+			Instant,
+		},
+	},
+};
+
+/// Answer cache configuration for the query side
+#[derive(Clone,Eq,PartialEq,Hash,Debug,Args)]
+pub struct CacheConfig
+{
+	/// Maximum number of cached answers, the least recently used ones are evicted first (0: no cache).
+	#[clap(long, value_name = "ENTRIES", default_value = "0")]
+	cache_size: usize,
+	/// Seconds an expired answer is still served while it is refreshed in the background, requires --cache-size.
+	#[clap(long, value_name = "SECONDS", default_value = "10", requires = "cache_size")]
+	cache_stale: u64,
+	/// Seconds past expiry an answer is still served if the backend fails, see RFC 8767, requires --cache-size.
+	///
+	/// Stale answers are taken from the cache, so without one a failing backend is always answered with SERVFAIL.
+	#[clap(long, value_name = "SECONDS", default_value = "86400", requires = "cache_size")]
+	cache_max_stale: u64,
+}
+
+impl Default for CacheConfig
+{
+	fn default() -> Self
+	{
+		CacheConfig
+		{
+			cache_size: 0,
+			cache_stale: 10,
+			cache_max_stale: 86400,
+		}
+	}
+}
+
+impl CacheConfig
+{
+	pub fn enabled(&self) -> bool
+	{
+		self.cache_size > 0
+	}
+}
+
+#[derive(Clone,Debug)]
+struct Entry<V>
+{
+	answer: V,
+	expires: Instant,
+	/// Position in the LRU order, the lowest being evicted first.
+	used: u64,
+	refreshing: bool,
+}
+
+#[derive(Debug)]
+struct Entries<K,V>
+{
+	map: HashMap<K,Entry<V>>,
+	order: BTreeMap<u64,K>,
+	tick: u64,
+}
+
+impl<K,V> Default for Entries<K,V>
+{
+	fn default() -> Self
+	{
+		Self
+		{
+			map: HashMap::new(),
+			order: BTreeMap::new(),
+			tick: 0,
+		}
+	}
+}
+
+impl<K,V> Entries<K,V>
+	where
+		K: Eq+Hash+Clone,
+{
+	fn touch(&mut self, key: &K)
+	{
+		self.tick += 1;
+		if let Some(entry) = self.map.get_mut(key)
+		{
+			self.order.remove(&entry.used);
+			entry.used = self.tick;
+			self.order.insert(self.tick, key.clone());
+		}
+	}
+
+	/// Inserts a fresh entry, returning the keys evicted to stay within `capacity`.
+	fn insert(&mut self, key: &K, answer: V, ttl: Duration, capacity: usize) -> Vec<K>
+	{
+		self.tick += 1;
+		let used = self.tick;
+		let entry = Entry
+		{
+			answer,
+			expires: Instant::now() + ttl,
+			used,
+			refreshing: false,
+		};
+
+		if let Some(previous) = self.map.insert(key.clone(), entry)
+		{
+			self.order.remove(&previous.used);
+		}
+		self.order.insert(used, key.clone());
+
+		let mut evicted = Vec::new();
+		while self.map.len() > capacity
+		{
+			let Some((_, key)) = self.order.pop_first()
+			else
+			{
+				break;
+			};
+			self.map.remove(&key);
+			evicted.push(key);
+		}
+
+		evicted
+	}
+}
+
+enum Lookup
+{
+	/// Unexpired answer along with the time it has left.
+	Fresh(Option<Vec<Ipv6Addr>>, Duration),
+	/// Expired but within the stale window, the caller is responsible for refreshing it if `refresh` is set.
+	Stale
+	{
+		answer: Option<Vec<Ipv6Addr>>,
+		refresh: bool,
+	},
+	Miss,
+}
+
+/// Answers of previous lookups, positive ones kept for the AAAA TTL, negative ones for the SOA TTL.
+///
+/// Metadata is kept alongside, with the same TTLs but without serving it stale, since it makes up records of its own.
+///
+/// Cloning this yields a handle to the same cache, so one instance is meant to be shared by all connections of a server.
+#[derive(Clone)]
+pub(crate) struct AnswerCache
+{
+	entries: Arc<Mutex<Entries<ContainerName,Option<Vec<Ipv6Addr>>>>>,
+	/// Metadata of previous lookups, `None` being the one of all instances.
+	metadata: Arc<Mutex<Entries<Option<ContainerName>,Vec<InstanceMetadata>>>>,
+	capacity: usize,
+	positive_ttl: Duration,
+	negative_ttl: Duration,
+	stale: Duration,
+	max_stale: Duration,
+	/// Seconds stale answers are served with, see [`TtlConfig::stale`].
+	stale_ttl: usize,
+	hits: Arc<AtomicU64>,
+	stale_hits: Arc<AtomicU64>,
+	misses: Arc<AtomicU64>,
+}
+
+impl AnswerCache
+{
+	pub fn new(config: &CacheConfig, ttl_config: &TtlConfig) -> Self
+	{
+		Self
+		{
+			entries: Default::default(),
+			metadata: Default::default(),
+			capacity: config.cache_size,
+			positive_ttl: Duration::from_secs(*ttl_config.aaaa_ttl() as u64),
+			negative_ttl: Duration::from_secs(*ttl_config.soa_ttl() as u64),
+			stale: Duration::from_secs(config.cache_stale),
+			max_stale: Duration::from_secs(config.cache_max_stale),
+			stale_ttl: *ttl_config.stale_ttl(),
+			hits: Default::default(),
+			stale_hits: Default::default(),
+			misses: Default::default(),
+		}
+	}
+
+	fn lookup(&self, name: &ContainerName) -> Lookup
+	{
+		let now = Instant::now();
+		let mut entries = self.entries.lock().unwrap();
+
+		let lookup = match entries.map.get_mut(name)
+		{
+			None => return Lookup::Miss,
+			Some(entry) if now < entry.expires => Lookup::Fresh(entry.answer.clone(), entry.expires - now),
+			Some(entry) if now < entry.expires + self.stale =>
+			{
+				let refresh = !entry.refreshing;
+				entry.refreshing = true;
+				Lookup::Stale
+				{
+					answer: entry.answer.clone(),
+					refresh,
+				}
+			},
+			Some(_) => return Lookup::Miss,
+		};
+
+		entries.touch(name);
+		lookup
+	}
+
+	/// Caches an answer, for no longer than the seconds suggested by the backend if it did.
+	fn insert(&self, name: &ContainerName, answer: Option<Vec<Ipv6Addr>>, suggested: Option<usize>)
+	{
+		let ttl = if answer.is_some() { self.positive_ttl } else { self.negative_ttl };
+		let ttl = suggested.map_or(ttl, |suggested| ttl.min(Duration::from_secs(suggested as u64)));
+
+		for evicted in self.entries.lock().unwrap().insert(name, answer, ttl, self.capacity)
+		{
+			trace!("[cache][{}] evicted", evicted.as_ref());
+		}
+	}
+
+	fn lookup_metadata(&self, name: Option<&ContainerName>) -> Option<Vec<InstanceMetadata>>
+	{
+		let key = name.cloned();
+		let mut metadata = self.metadata.lock().unwrap();
+
+		let entry = metadata.map.get(&key).filter(|entry| Instant::now() < entry.expires)?;
+		let answer = entry.answer.clone();
+		metadata.touch(&key);
+
+		Some(answer)
+	}
+
+	fn insert_metadata(&self, name: Option<&ContainerName>, answer: Vec<InstanceMetadata>)
+	{
+		// no metadata for a name usually means there is no such instance
+		let ttl = if answer.is_empty() && name.is_some() { self.negative_ttl } else { self.positive_ttl };
+
+		for evicted in self.metadata.lock().unwrap().insert(&name.cloned(), answer, ttl, self.capacity)
+		{
+			trace!("[cache][{}] evicted metadata", evicted.as_ref().map(AsRef::as_ref).unwrap_or("*"));
+		}
+	}
+
+	/// Addresses of an expired entry, as long as it did not expire too long ago.
+	///
+	/// Negative answers are not returned, there is nothing gained by claiming the absence of an instance without knowing.
+	fn stale(&self, name: &ContainerName) -> Option<Vec<Ipv6Addr>>
+	{
+		let entries = self.entries.lock().unwrap();
+		let entry = entries.map.get(name)?;
+
+		if Instant::now() < entry.expires + self.max_stale
+		{
+			entry.answer.clone()
+		}
+		else
+		{
+			None
+		}
+	}
+
+	/// Allows another refresh of an entry after a failed one, the stale answer is kept.
+	fn refresh_failed(&self, name: &ContainerName)
+	{
+		if let Some(entry) = self.entries.lock().unwrap().map.get_mut(name)
+		{
+			entry.refreshing = false;
+		}
+	}
+
+	fn diagnostics(&self) -> Value
+	{
+		json!(
+		{
+			"entries": self.entries.lock().unwrap().map.len(),
+			"metadata_entries": self.metadata.lock().unwrap().map.len(),
+			"capacity": self.capacity,
+			"hits": self.hits.load(Ordering::Relaxed),
+			"stale_hits": self.stale_hits.load(Ordering::Relaxed),
+			"misses": self.misses.load(Ordering::Relaxed),
+		})
+	}
+}
+
+/// Backend wrapper answering from an [`AnswerCache`] where possible.
+///
+/// Expired answers are served for a little longer while a single background lookup refreshes them.
+/// Answers are passed on with the time they have left in the cache, expired ones with the stale TTL.
+/// Failed lookups are never cached.
+pub(crate) struct CachedQuery<B>
+{
+	backend: SharedBackend<B>,
+	cache: AnswerCache,
+}
+
+impl<B> CachedQuery<B>
+	where
+		B: RemoteQuery+'static,
+{
+	pub fn new(backend: B, cache: AnswerCache) -> Self
+	{
+		Self
+		{
+			backend: SharedBackend::new(backend),
+			cache,
+		}
+	}
+}
+
+#[async_trait::async_trait]
+impl<B> RemoteQuery for CachedQuery<B>
+	where
+		B: RemoteQuery+Send+Sync+'static,
+{
+	fn name(&self) -> String
+	{
+		self.backend.name()
+	}
+
+	fn diagnostics(&self) -> Value
+	{
+		json!(
+		{
+			"cache": self.cache.diagnostics(),
+			"backend": self.backend.diagnostics(),
+		})
+	}
+
+	fn stale(&self, name: &ContainerName) -> Option<Vec<Ipv6Addr>>
+	{
+		self.cache.stale(name).or_else(|| self.backend.stale(name))
+	}
+
+	async fn metadata(&self, name: Option<&ContainerName>) -> Result<Vec<InstanceMetadata>>
+	{
+		let label = name.map(AsRef::as_ref).unwrap_or("*");
+
+		if let Some(metadata) = self.cache.lookup_metadata(name)
+		{
+			trace!("[cache][{}] metadata hit", label);
+			self.cache.hits.fetch_add(1, Ordering::Relaxed);
+			return Ok(metadata);
+		}
+
+		trace!("[cache][{}] metadata miss", label);
+		self.cache.misses.fetch_add(1, Ordering::Relaxed);
+
+		let metadata = self.backend.metadata(name).await?;
+		self.cache.insert_metadata(name, metadata.clone());
+		Ok(metadata)
+	}
+
+	async fn remote_query(&self, name: &ContainerName) -> Result<Option<Vec<Ipv6Addr>>>
+	{
+		Ok(self.remote_query_ttl(name).await?.0)
+	}
+
+	async fn remote_query_ttl(&self, name: &ContainerName) -> Result<(Option<Vec<Ipv6Addr>>, Option<usize>)>
+	{
+		match self.cache.lookup(name)
+		{
+			Lookup::Fresh(answer, remaining) =>
+			{
+				trace!("[cache][{}] hit", name.as_ref());
+				self.cache.hits.fetch_add(1, Ordering::Relaxed);
+				// downstream caches must not keep the answer for longer than this one does
+				Ok((answer, Some(remaining.as_secs() as usize)))
+			},
+			Lookup::Stale { answer, refresh, } =>
+			{
+				debug!("[cache][{}] serving stale answer", name.as_ref());
+				self.cache.stale_hits.fetch_add(1, Ordering::Relaxed);
+
+				if refresh
+				{
+					let backend = self.backend.clone();
+					let cache = self.cache.clone();
+					let name = name.clone();
+
+					tokio::spawn(async move
+					{
+						match backend.remote_query_ttl(&name).await
+						{
+							Ok((answer, ttl)) =>
+							{
+								trace!("[cache][{}] refreshed", name.as_ref());
+								cache.insert(&name, answer, ttl);
+							},
+							Err(err) =>
+							{
+								debug!("[cache][{}] refresh failed: {:#}", name.as_ref(), err);
+								cache.refresh_failed(&name);
+							},
+						}
+					});
+				}
+
+				Ok((answer, Some(self.cache.stale_ttl)))
+			},
+			Lookup::Miss =>
+			{
+				trace!("[cache][{}] miss", name.as_ref());
+				self.cache.misses.fetch_add(1, Ordering::Relaxed);
+
+				let (answer, ttl) = self.backend.remote_query_ttl(name).await?;
+				self.cache.insert(name, answer.clone(), ttl);
+				Ok((answer, ttl))
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use ::std::thread::sleep;
+
+	fn cache(capacity: usize, positive_ttl: Duration, negative_ttl: Duration, stale: Duration) -> AnswerCache
+	{
+		AnswerCache
+		{
+			entries: Default::default(),
+			metadata: Default::default(),
+			capacity,
+			positive_ttl,
+			negative_ttl,
+			stale,
+			max_stale: Duration::from_secs(3600),
+			stale_ttl: 30,
+			hits: Default::default(),
+			stale_hits: Default::default(),
+			misses: Default::default(),
+		}
+	}
+
+	fn name(name: &str) -> ContainerName
+	{
+		name.parse().unwrap()
+	}
+
+	fn found() -> Option<Vec<Ipv6Addr>>
+	{
+		Some(vec![Ipv6Addr::LOCALHOST])
+	}
+
+	#[test]
+	fn least_recently_used_answers_are_evicted_first()
+	{
+		let cache = cache(2, Duration::from_secs(60), Duration::from_secs(60), Duration::ZERO);
+
+		cache.insert(&name("a"), found(), None);
+		cache.insert(&name("b"), found(), None);
+		assert!(matches!(cache.lookup(&name("a")), Lookup::Fresh(..)));
+		cache.insert(&name("c"), found(), None);
+
+		assert!(matches!(cache.lookup(&name("a")), Lookup::Fresh(..)));
+		assert!(matches!(cache.lookup(&name("b")), Lookup::Miss));
+		assert!(matches!(cache.lookup(&name("c")), Lookup::Fresh(..)));
+	}
+
+	#[test]
+	fn negative_answers_expire_with_their_own_ttl()
+	{
+		let cache = cache(8, Duration::from_secs(60), Duration::from_millis(20), Duration::ZERO);
+
+		cache.insert(&name("a"), found(), None);
+		cache.insert(&name("b"), None, None);
+		sleep(Duration::from_millis(50));
+
+		assert!(matches!(cache.lookup(&name("a")), Lookup::Fresh(Some(_), _)));
+		assert!(matches!(cache.lookup(&name("b")), Lookup::Miss));
+	}
+
+	#[test]
+	fn fresh_answers_carry_the_time_left()
+	{
+		let cache = cache(8, Duration::from_secs(60), Duration::from_secs(60), Duration::ZERO);
+
+		cache.insert(&name("a"), found(), None);
+		let Lookup::Fresh(_, remaining) = cache.lookup(&name("a"))
+		else
+		{
+			panic!("expected a fresh answer");
+		};
+		assert!(remaining <= Duration::from_secs(60) && remaining > Duration::from_secs(59));
+
+		// a suggested TTL shortens the lifetime, but never extends it
+		cache.insert(&name("b"), found(), Some(5));
+		let Lookup::Fresh(_, remaining) = cache.lookup(&name("b"))
+		else
+		{
+			panic!("expected a fresh answer");
+		};
+		assert!(remaining <= Duration::from_secs(5));
+
+		cache.insert(&name("c"), found(), Some(600));
+		let Lookup::Fresh(_, remaining) = cache.lookup(&name("c"))
+		else
+		{
+			panic!("expected a fresh answer");
+		};
+		assert!(remaining <= Duration::from_secs(60));
+	}
+
+	#[test]
+	fn stale_answers_are_refreshed_once()
+	{
+		let cache = cache(8, Duration::from_millis(20), Duration::from_millis(20), Duration::from_secs(60));
+
+		cache.insert(&name("a"), found(), None);
+		sleep(Duration::from_millis(50));
+
+		assert!(matches!(cache.lookup(&name("a")), Lookup::Stale { answer: Some(_), refresh: true, }));
+		assert!(matches!(cache.lookup(&name("a")), Lookup::Stale { answer: Some(_), refresh: false, }));
+
+		// a successful refresh replaces the entry
+		cache.insert(&name("a"), found(), None);
+		assert!(matches!(cache.lookup(&name("a")), Lookup::Fresh(..)));
+	}
+
+	#[test]
+	fn failed_refreshes_allow_another_one()
+	{
+		let cache = cache(8, Duration::from_millis(20), Duration::from_millis(20), Duration::from_secs(60));
+
+		cache.insert(&name("a"), found(), None);
+		sleep(Duration::from_millis(50));
+
+		assert!(matches!(cache.lookup(&name("a")), Lookup::Stale { refresh: true, .. }));
+		cache.refresh_failed(&name("a"));
+		assert!(matches!(cache.lookup(&name("a")), Lookup::Stale { refresh: true, .. }));
+		assert_eq!(cache.stale(&name("a")), found());
+	}
+
+	#[test]
+	fn answers_past_the_stale_window_are_missing()
+	{
+		let cache = cache(8, Duration::from_millis(20), Duration::from_millis(20), Duration::from_millis(20));
+
+		cache.insert(&name("a"), found(), None);
+		cache.insert(&name("b"), None, None);
+		sleep(Duration::from_millis(60));
+
+		assert!(matches!(cache.lookup(&name("a")), Lookup::Miss));
+		// still good enough for a failing backend, unlike negative answers
+		assert_eq!(cache.stale(&name("a")), found());
+		assert_eq!(cache.stale(&name("b")), None);
+	}
+}
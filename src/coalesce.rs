@@ -0,0 +1,275 @@
+// Copyright (C) benaryorg <binary@benary.org>
+//
+// This software is licensed as described in the file COPYING, which
+// you should have received as part of this distribution.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::
+{
+	error::*,
+	lxd::
+	{
+		ContainerName,
+		InstanceMetadata,
+	},
+	pdns_io::
+	{
+		RemoteQuery,
+		SharedBackend,
+	},
+};
+
+use ::
+{
+	serde_json::Value,
+	futures::
+	{
+		future::
+		{
+			BoxFuture,
+			Shared,
+		},
+		FutureExt,
+	},
+	std::
+	{
+		collections::HashMap,
+		hash::Hash,
+		net::Ipv6Addr,
+		sync::
+		{
+			Arc,
+			Mutex,
+			Weak,
+		},
+	},
+};
+
+type SharedLookup<T> = Shared<BoxFuture<'static,std::result::Result<T,SharedError>>>;
+type Lookups<K,T> = Arc<Mutex<HashMap<K,Weak<Lookup<K,T>>>>>;
+
+/// Lookups currently in flight.
+///
+/// Cloning this yields a handle to the same set of lookups, so one instance is meant to be shared by all connections of a server.
+#[derive(Clone,Default)]
+pub struct Inflight
+{
+	lookups: Lookups<ContainerName,(Option<Vec<Ipv6Addr>>,Option<usize>)>,
+	/// Metadata lookups, `None` being the one of all instances.
+	metadata: Lookups<Option<ContainerName>,Vec<InstanceMetadata>>,
+}
+
+/// Lookup held by everyone waiting for it, leaving [`Inflight`] once the last of them is gone.
+struct Lookup<K,T>
+	where
+		K: Eq+Hash,
+{
+	key: K,
+	lookups: Lookups<K,T>,
+	future: SharedLookup<T>,
+}
+
+impl<K,T> Drop for Lookup<K,T>
+	where
+		K: Eq+Hash,
+{
+	fn drop(&mut self)
+	{
+		// finished or abandoned by all waiters, either way the next caller has to start over
+		let mut lookups = self.lookups.lock().unwrap();
+		if lookups.get(&self.key).is_some_and(|lookup| lookup.strong_count() == 0)
+		{
+			lookups.remove(&self.key);
+		}
+	}
+}
+
+/// Joins the lookup of `key` in flight, starting a new one with `start` if there is none.
+fn join<K,T,F>(lookups: &Lookups<K,T>, key: &K, label: &str, start: F) -> Arc<Lookup<K,T>>
+	where
+		K: Eq+Hash+Clone,
+		T: Clone+Send+Sync+'static,
+		F: FnOnce() -> BoxFuture<'static,Result<T>>,
+{
+	let mut map = lookups.lock().unwrap();
+
+	if let Some(lookup) = map.get(key).and_then(Weak::upgrade)
+	{
+		debug!("[coalesce][{}] joining lookup in flight", label);
+		return lookup;
+	}
+
+	trace!("[coalesce][{}] starting new lookup", label);
+
+	let lookup = Arc::new(Lookup
+	{
+		key: key.clone(),
+		lookups: lookups.clone(),
+		future: start().map(|result| result.map_err(|err| SharedError(Arc::new(err)))).boxed().shared(),
+	});
+
+	map.insert(key.clone(), Arc::downgrade(&lookup));
+	lookup
+}
+
+/// Backend wrapper which merges identical concurrent lookups into a single backend query.
+///
+/// Every lookup for a name which is already being looked up, by any wrapper sharing the same [`Inflight`], waits for the running query instead of issuing its own.
+pub struct CoalescingQuery<B>
+{
+	backend: SharedBackend<B>,
+	inflight: Inflight,
+}
+
+impl<B> CoalescingQuery<B>
+	where
+		B: RemoteQuery+'static,
+{
+	pub fn new(backend: B, inflight: Inflight) -> Self
+	{
+		Self
+		{
+			backend: SharedBackend::new(backend),
+			inflight,
+		}
+	}
+}
+
+#[async_trait::async_trait]
+impl<B> RemoteQuery for CoalescingQuery<B>
+	where
+		B: RemoteQuery+Send+Sync+'static,
+{
+	fn name(&self) -> String
+	{
+		self.backend.name()
+	}
+
+	fn diagnostics(&self) -> Value
+	{
+		serde_json::json!(
+		{
+			"inflight": self.inflight.lookups.lock().unwrap().len(),
+			"inflight_metadata": self.inflight.metadata.lock().unwrap().len(),
+			"backend": self.backend.diagnostics(),
+		})
+	}
+
+	fn stale(&self, name: &ContainerName) -> Option<Vec<Ipv6Addr>>
+	{
+		self.backend.stale(name)
+	}
+
+	async fn metadata(&self, name: Option<&ContainerName>) -> Result<Vec<InstanceMetadata>>
+	{
+		let backend = self.backend.clone();
+		let key = name.cloned();
+
+		let lookup = join(&self.inflight.metadata, &key, name.map(AsRef::as_ref).unwrap_or("*"), ||
+		{
+			let key = key.clone();
+			async move
+			{
+				backend.metadata(key.as_ref()).await
+			}.boxed()
+		});
+
+		Ok(lookup.future.clone().await?)
+	}
+
+	async fn remote_query(&self, name: &ContainerName) -> Result<Option<Vec<Ipv6Addr>>>
+	{
+		Ok(self.remote_query_ttl(name).await?.0)
+	}
+
+	async fn remote_query_ttl(&self, name: &ContainerName) -> Result<(Option<Vec<Ipv6Addr>>, Option<usize>)>
+	{
+		let backend = self.backend.clone();
+		let key = name.clone();
+
+		let lookup = join(&self.inflight.lookups, name, name.as_ref(), ||
+		{
+			async move
+			{
+				backend.remote_query_ttl(&key).await
+			}.boxed()
+		});
+
+		Ok(lookup.future.clone().await?)
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use ::
+	{
+		std::
+		{
+			sync::atomic::
+			{
+				AtomicUsize,
+				Ordering,
+			},
+			time::Duration,
+		},
+	};
+
+	/// Backend taking a while to answer every lookup, counting them.
+	struct Slow
+	{
+		calls: Arc<AtomicUsize>,
+	}
+
+	#[async_trait::async_trait]
+	impl RemoteQuery for Slow
+	{
+		async fn remote_query(&self, _name: &ContainerName) -> Result<Option<Vec<Ipv6Addr>>>
+		{
+			self.calls.fetch_add(1, Ordering::SeqCst);
+			tokio::time::sleep(Duration::from_millis(100)).await;
+			Ok(Some(vec![Ipv6Addr::LOCALHOST]))
+		}
+
+		fn name(&self) -> String
+		{
+			"slow".to_string()
+		}
+	}
+
+	#[tokio::test]
+	async fn concurrent_lookups_reach_the_backend_once()
+	{
+		let calls = Arc::new(AtomicUsize::new(0));
+		let inflight = Inflight::default();
+		let first = CoalescingQuery::new(Slow { calls: calls.clone(), }, inflight.clone());
+		let second = CoalescingQuery::new(Slow { calls: calls.clone(), }, inflight.clone());
+		let name = "web".parse().unwrap();
+
+		let (first, second) = tokio::join!(first.remote_query(&name), second.remote_query(&name));
+
+		assert_eq!(first.unwrap(), Some(vec![Ipv6Addr::LOCALHOST]));
+		assert_eq!(second.unwrap(), Some(vec![Ipv6Addr::LOCALHOST]));
+		assert_eq!(calls.load(Ordering::SeqCst), 1);
+		assert!(inflight.lookups.lock().unwrap().is_empty());
+	}
+
+	#[tokio::test]
+	async fn cancelled_lookups_leave_no_entry_behind()
+	{
+		let calls = Arc::new(AtomicUsize::new(0));
+		let inflight = Inflight::default();
+		let query = CoalescingQuery::new(Slow { calls: calls.clone(), }, inflight.clone());
+		let name = "web".parse().unwrap();
+
+		assert!(tokio::time::timeout(Duration::from_millis(10), query.remote_query(&name)).await.is_err());
+		assert!(inflight.lookups.lock().unwrap().is_empty());
+
+		// the abandoned lookup is not joined, the next one starts over
+		assert_eq!(query.remote_query(&name).await.unwrap(), Some(vec![Ipv6Addr::LOCALHOST]));
+		assert_eq!(calls.load(Ordering::SeqCst), 2);
+		assert!(inflight.lookups.lock().unwrap().is_empty());
+	}
+}
@@ -5,6 +5,7 @@
 //
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+#[allow(unused)]
 pub(crate) use ::
 {
 	thiserror::
@@ -69,9 +70,84 @@ pub enum Error
 	AcknowledgementError,
 	#[error("correlation id was reused")]
 	DuplicateCorrelationId,
+	#[error("no responders are bound to the message queue")]
+	NoResponders,
 	#[error("http server failed with error")]
 	HttpServerError,
 	#[error("http request failed with error")]
 	HttpRequestError,
+	#[error("remote rejected the request with `{0}`")]
+	RemoteRejected(String),
+	#[error("none of the remotes could be queried")]
+	RemotesUnavailable,
+	#[error("backend did not answer in time")]
+	BackendTimeout,
+}
+
+impl Error
+{
+	/// Stable machine-readable identifier of the error, e.g. for API responses.
+	pub fn code(&self) -> &'static str
+	{
+		match self
+		{
+			Error::CommandLineParsing(_) => "command_line_parsing",
+			Error::Io(_) => "io",
+			Error::NumberParsing(_) => "number_parsing",
+			#[cfg(feature = "amqp")]
+			Error::Lapin(_) => "amqp",
+			#[cfg(feature = "http")]
+			Error::Reqwest(_) => "http_client",
+			#[cfg(feature = "http")]
+			Error::Rustls(_) => "tls",
+			Error::ResponderError => "responder_error",
+			Error::ResponderClosed => "responder_closed",
+			Error::UnixServerError => "unix_server_error",
+			Error::UnixServerClosed => "unix_server_closed",
+			Error::LocalOutput => "lxd_output_unparsable",
+			Error::LocalExecution(_) => "lxd_execution_failed",
+			Error::UnsafeName(_) => "unsafe_name",
+			Error::MessageQueueTaint => "message_queue_taint",
+			Error::InvalidConfiguration => "invalid_configuration",
+			Error::QueueConnectionError => "queue_connection_failed",
+			Error::AcknowledgementError => "acknowledgement_failed",
+			Error::DuplicateCorrelationId => "duplicate_correlation_id",
+			Error::NoResponders => "no_responders",
+			Error::HttpServerError => "http_server_error",
+			Error::HttpRequestError => "http_request_failed",
+			Error::RemoteRejected(_) => "remote_rejected",
+			Error::RemotesUnavailable => "remotes_unavailable",
+			Error::BackendTimeout => "backend_timeout",
+		}
+	}
+
+	/// Finds the [`Error`] describing a failure, looking through errors shared by coalesced lookups.
+	pub fn find(err: &anyhow::Error) -> Option<&Error>
+	{
+		err.downcast_ref::<Error>()
+			.or_else(|| err.downcast_ref::<SharedError>().and_then(|shared| Error::find(&shared.0)))
+	}
+}
+
+/// Error of a lookup whose result is shared by several callers.
+///
+/// The original error cannot be cloned, so every caller receives a reference to it instead.
+#[derive(Debug,Clone)]
+pub struct SharedError(pub std::sync::Arc<anyhow::Error>);
+
+impl std::fmt::Display for SharedError
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+	{
+		write!(f, "{}", self.0)
+	}
+}
+
+impl std::error::Error for SharedError
+{
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)>
+	{
+		self.0.source()
+	}
 }
 
@@ -0,0 +1,270 @@
+// Copyright (C) benaryorg <binary@benary.org>
+//
+// This software is licensed as described in the file COPYING, which
+// you should have received as part of this distribution.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::error::*;
+
+use ::
+{
+	clap::
+	{
+		Args,
+	},
+	hickory_resolver::
+	{
+		proto::rr::RData,
+		TokioResolver,
+	},
+	tokio::
+	{
+		fs::read_to_string,
+		task::JoinHandle,
+		time::interval,
+	},
+	std::
+	{
+		collections::BTreeSet,
+		path::PathBuf,
+		sync::
+		{
+			Arc,
+			RwLock,
+		},
+		time::Duration,
+	},
+};
+
+/// Sources of remotes in addition to the ones given statically
+#[derive(Clone,Eq,PartialEq,Hash,Debug,Args)]
+pub struct DiscoveryConfig
+{
+	/// SRV record listing remotes, each target being queried as `https://TARGET:PORT`.
+	#[clap(long, value_name = "SRV_RECORD")]
+	remote_srv: Option<String>,
+	/// File listing one API root per line, empty lines and lines starting with `#` are ignored.
+	#[clap(long, value_name = "FILE")]
+	remote_file: Option<PathBuf>,
+	/// Seconds between refreshes of the discovered remotes.
+	#[clap(long, value_name = "SECONDS", default_value = "60")]
+	remote_refresh: u64,
+}
+
+impl Default for DiscoveryConfig
+{
+	fn default() -> Self
+	{
+		DiscoveryConfig
+		{
+			remote_srv: None,
+			remote_file: None,
+			remote_refresh: 60,
+		}
+	}
+}
+
+impl DiscoveryConfig
+{
+	fn is_dynamic(&self) -> bool
+	{
+		self.remote_srv.is_some() || self.remote_file.is_some()
+	}
+
+	/// Remotes listed in the SRV record, if there is one.
+	async fn discover_srv(&self, resolver: Option<&TokioResolver>) -> Result<BTreeSet<String>>
+	{
+		let (Some(record), Some(resolver)) = (&self.remote_srv, resolver)
+		else
+		{
+			return Ok(BTreeSet::new());
+		};
+
+		let lookup = resolver.srv_lookup(record.as_str()).await
+			.with_context(|| format!("cannot resolve SRV record {}", record))?;
+
+		Ok(lookup.answers().iter()
+			.filter_map(|record| match &record.data
+			{
+				RData::SRV(srv) => Some(format!("https://{}:{}", srv.target.to_utf8().trim_end_matches('.'), srv.port)),
+				_ => None,
+			})
+			.collect())
+	}
+
+	/// Remotes listed in the peers file, if there is one.
+	async fn discover_file(&self) -> Result<BTreeSet<String>>
+	{
+		let Some(path) = &self.remote_file
+		else
+		{
+			return Ok(BTreeSet::new());
+		};
+
+		let content = read_to_string(path).await
+			.with_context(|| format!("cannot read peers file {}", path.display()))?;
+
+		Ok(content.lines()
+			.map(str::trim)
+			.filter(|line| !line.is_empty() && !line.starts_with('#'))
+			.map(String::from)
+			.collect())
+	}
+}
+
+/// Remotes found by each discovery source, kept apart so that a failing source does not affect the others.
+#[derive(Default)]
+struct Discovered
+{
+	srv: BTreeSet<String>,
+	file: BTreeSet<String>,
+}
+
+impl Discovered
+{
+	/// Asks every source again, sources failing keep their previous remotes.
+	///
+	/// The error of the last failing source is returned once all of them were asked.
+	async fn refresh(&mut self, config: &DiscoveryConfig, resolver: Option<&TokioResolver>) -> Result<()>
+	{
+		let mut result = Ok(());
+
+		match config.discover_srv(resolver).await
+		{
+			Ok(remotes) => self.srv = remotes,
+			Err(err) =>
+			{
+				warn!("[http-discovery] keeping {} previous remotes of the SRV record: {:#}", self.srv.len(), err);
+				result = Err(err);
+			},
+		}
+
+		match config.discover_file().await
+		{
+			Ok(remotes) => self.file = remotes,
+			Err(err) =>
+			{
+				warn!("[http-discovery] keeping {} previous remotes of the peers file: {:#}", self.file.len(), err);
+				result = Err(err);
+			},
+		}
+
+		result
+	}
+
+	fn remotes(&self, fixed: &BTreeSet<String>) -> BTreeSet<String>
+	{
+		fixed.iter().chain(&self.srv).chain(&self.file).cloned().collect()
+	}
+}
+
+/// Aborts the refresh task once the last handle to the remotes is gone.
+struct Refresher(JoinHandle<()>);
+
+impl Drop for Refresher
+{
+	fn drop(&mut self)
+	{
+		self.0.abort();
+	}
+}
+
+/// Current set of remotes, consisting of the static ones and those discovered at runtime.
+///
+/// Cloning this yields a handle to the same set.
+#[derive(Clone)]
+pub(crate) struct Remotes
+{
+	current: Arc<RwLock<Arc<Vec<String>>>>,
+	_refresher: Option<Arc<Refresher>>,
+}
+
+impl Remotes
+{
+	/// Discovers the initial set of remotes and keeps refreshing it in the background.
+	///
+	/// Failing sources keep their previous remotes on refresh, a failing initial discovery starts with the remotes found otherwise unless there are none.
+	pub async fn start(fixed: Vec<String>, config: DiscoveryConfig) -> Result<Self>
+	{
+		if !config.is_dynamic()
+		{
+			if fixed.is_empty()
+			{
+				Err(Error::InvalidConfiguration).context("neither remotes nor a way to discover them provided")?;
+			}
+
+			return Ok(Self
+			{
+				current: Arc::new(RwLock::new(Arc::new(fixed))),
+				_refresher: None,
+			});
+		}
+
+		let resolver = match config.remote_srv
+		{
+			Some(_) => Some(TokioResolver::builder_tokio().context("cannot read system resolver configuration")?.build()?),
+			None => None,
+		};
+
+		let fixed: BTreeSet<String> = fixed.into_iter().collect();
+		let mut discovered = Discovered::default();
+		let initial = discovered.refresh(&config, resolver.as_ref()).await;
+		let mut known = discovered.remotes(&fixed);
+		if let Err(err) = initial
+		{
+			if known.is_empty()
+			{
+				return Err(err);
+			}
+			warn!("[http-discovery] starting with the remotes found so far");
+		}
+		info!("[http-discovery] discovered {} remotes: {:?}", known.len(), known);
+		if known.is_empty()
+		{
+			warn!("[http-discovery] no remotes discovered yet, every lookup will fail until there are");
+		}
+
+		let current = Arc::new(RwLock::new(Arc::new(known.iter().cloned().collect())));
+
+		let task = tokio::spawn(
+		{
+			let current = current.clone();
+
+			async move
+			{
+				let mut ticker = interval(Duration::from_secs(config.remote_refresh.max(1)));
+				// the first tick completes immediately and the initial discovery just happened
+				ticker.tick().await;
+
+				loop
+				{
+					ticker.tick().await;
+
+					// failures are logged by the sources themselves, the others are taken either way
+					let _ = discovered.refresh(&config, resolver.as_ref()).await;
+
+					let remotes = discovered.remotes(&fixed);
+					if remotes != known
+					{
+						info!("[http-discovery] remotes changed, added: {:?}, removed: {:?}", remotes.difference(&known).collect::<Vec<_>>(), known.difference(&remotes).collect::<Vec<_>>());
+						*current.write().unwrap() = Arc::new(remotes.iter().cloned().collect());
+						known = remotes;
+					}
+				}
+			}
+		});
+
+		Ok(Self
+		{
+			current,
+			_refresher: Some(Arc::new(Refresher(task))),
+		})
+	}
+
+	/// Snapshot of the remotes, unaffected by refreshes while in use.
+	pub fn get(&self) -> Arc<Vec<String>>
+	{
+		self.current.read().unwrap().clone()
+	}
+}
@@ -0,0 +1,205 @@
+// Copyright (C) benaryorg <binary@benary.org>
+//
+// This software is licensed as described in the file COPYING, which
+// you should have received as part of this distribution.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::
+{
+	error::*,
+	lxd::local_snapshot,
+};
+
+use ::
+{
+	serde::
+	{
+		Deserialize,
+		Serialize,
+	},
+	tokio::
+	{
+		sync::broadcast,
+		task::JoinHandle,
+		time::interval,
+	},
+	std::
+	{
+		collections::BTreeMap,
+		net::Ipv6Addr,
+		sync::
+		{
+			Arc,
+			Mutex,
+		},
+		time::Duration,
+	},
+};
+
+/// Number of events a subscriber may fall behind before it is disconnected.
+const EVENT_BACKLOG: usize = 256;
+
+type Snapshot = BTreeMap<String,Vec<Ipv6Addr>>;
+
+/// Change of the local instances as published on the event stream.
+#[derive(Serialize,Deserialize,Clone,Eq,PartialEq,Hash,Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event
+{
+	/// A running instance showed up, either started, created, or moved here.
+	Appeared
+	{
+		name: String,
+		addresses: Vec<Ipv6Addr>,
+	},
+	/// A running instance is gone, either stopped, deleted, or moved away.
+	Disappeared
+	{
+		name: String,
+	},
+	/// The published addresses of a running instance changed.
+	AddressChanged
+	{
+		name: String,
+		addresses: Vec<Ipv6Addr>,
+	},
+}
+
+impl Event
+{
+	/// Name of the event as used for the `event:` field of the stream.
+	pub fn kind(&self) -> &'static str
+	{
+		match self
+		{
+			Event::Appeared { .. } => "appeared",
+			Event::Disappeared { .. } => "disappeared",
+			Event::AddressChanged { .. } => "address_changed",
+		}
+	}
+}
+
+/// Events turning the previous snapshot into the current one.
+///
+/// A missing previous snapshot yields the entire current one as appearing.
+fn diff(previous: Option<&Snapshot>, current: &Snapshot) -> Vec<Event>
+{
+	let empty = Snapshot::new();
+	let previous = previous.unwrap_or(&empty);
+
+	let disappeared = previous.keys()
+		.filter(|name| !current.contains_key(*name))
+		.map(|name| Event::Disappeared { name: name.clone(), });
+
+	let changed = current.iter()
+		.filter_map(|(name, addresses)| match previous.get(name)
+		{
+			None => Some(Event::Appeared { name: name.clone(), addresses: addresses.clone(), }),
+			Some(old) if old != addresses => Some(Event::AddressChanged { name: name.clone(), addresses: addresses.clone(), }),
+			Some(_) => None,
+		});
+
+	disappeared.chain(changed).collect()
+}
+
+struct State
+{
+	/// Last snapshot taken, discarded while nobody is subscribed as it would go stale.
+	snapshot: Option<Snapshot>,
+	sender: broadcast::Sender<Event>,
+}
+
+/// Watches the local instances and publishes their changes to all subscribers.
+///
+/// LXD is only polled while there are subscribers.
+pub(crate) struct Events
+{
+	state: Arc<Mutex<State>>,
+	task: JoinHandle<()>,
+}
+
+impl Events
+{
+	pub fn start(command: String, period: Duration) -> Self
+	{
+		let (sender, _) = broadcast::channel(EVENT_BACKLOG);
+		let state = Arc::new(Mutex::new(State
+		{
+			snapshot: None,
+			sender,
+		}));
+
+		let task = tokio::spawn(
+		{
+			let state = state.clone();
+
+			async move
+			{
+				let mut ticker = interval(period.max(Duration::from_secs(1)));
+				loop
+				{
+					ticker.tick().await;
+
+					{
+						let mut state = state.lock().unwrap();
+						if state.sender.receiver_count() == 0
+						{
+							state.snapshot = None;
+							continue;
+						}
+					}
+
+					let current = match local_snapshot(&command).await
+					{
+						Ok(current) => current,
+						Err(err) =>
+						{
+							warn!("[http-events] cannot take snapshot: {:#}", err);
+							continue;
+						},
+					};
+
+					let mut state = state.lock().unwrap();
+					let events = diff(state.snapshot.as_ref(), &current);
+					trace!("[http-events] {} events", events.len());
+					for event in events
+					{
+						// nobody listening anymore is of no concern
+						let _ = state.sender.send(event);
+					}
+					state.snapshot = Some(current);
+				}
+			}
+		});
+
+		Self
+		{
+			state,
+			task,
+		}
+	}
+
+	/// Subscribes to all future events, preceded by the current state as far as known.
+	///
+	/// If the state is not known yet the first snapshot announces everything anyway.
+	pub fn subscribe(&self) -> (Vec<Event>, broadcast::Receiver<Event>)
+	{
+		let state = self.state.lock().unwrap();
+		let initial = match state.snapshot
+		{
+			Some(ref snapshot) => diff(None, snapshot),
+			None => Vec::new(),
+		};
+
+		(initial, state.sender.subscribe())
+	}
+}
+
+impl Drop for Events
+{
+	fn drop(&mut self)
+	{
+		self.task.abort();
+	}
+}
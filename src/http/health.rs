@@ -0,0 +1,341 @@
+// Copyright (C) benaryorg <binary@benary.org>
+//
+// This software is licensed as described in the file COPYING, which
+// you should have received as part of this distribution.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::error::*;
+
+use ::
+{
+	clap::
+	{
+		Args,
+	},
+	serde_json::
+	{
+		json,
+		Value,
+	},
+	std::
+	{
+		collections::HashMap,
+		sync::Mutex,
+		time::
+		{
+			Duration,
+			Instant,
+		},
+	},
+};
+
+/// Weight of the most recent request in the latency average.
+const LATENCY_EWMA_WEIGHT: f64 = 0.2;
+
+/// Circuit breaker configuration for HTTP remotes
+#[derive(Clone,Eq,PartialEq,Hash,Debug,Args)]
+pub struct HealthConfig
+{
+	/// Consecutive failures after which a remote is no longer queried.
+	#[clap(long, value_name = "COUNT", default_value = "3")]
+	failure_threshold: usize,
+	/// Seconds after which a remote no longer queried is probed again.
+	#[clap(long, value_name = "SECONDS", default_value = "30")]
+	probe_interval: u64,
+}
+
+impl Default for HealthConfig
+{
+	fn default() -> Self
+	{
+		HealthConfig
+		{
+			failure_threshold: 3,
+			probe_interval: 30,
+		}
+	}
+}
+
+#[derive(Clone,Copy,Eq,PartialEq,Hash,Debug)]
+enum Circuit
+{
+	/// The remote is healthy and queried.
+	Closed,
+	/// The remote failed too often and is skipped until the next probe.
+	Open
+	{
+		since: Instant,
+	},
+	/// A single probe was sent to an open remote and its outcome decides the state.
+	///
+	/// Probes may be cancelled without any outcome, so a probe is retried once it is as old as the probe interval.
+	HalfOpen
+	{
+		since: Instant,
+	},
+}
+
+#[derive(Clone,Debug)]
+struct RemoteHealth
+{
+	circuit: Circuit,
+	consecutive_failures: usize,
+	last_success: Option<Instant>,
+	latency: Option<f64>,
+}
+
+impl Default for RemoteHealth
+{
+	fn default() -> Self
+	{
+		RemoteHealth
+		{
+			circuit: Circuit::Closed,
+			consecutive_failures: 0,
+			last_success: None,
+			latency: None,
+		}
+	}
+}
+
+/// Health state of all remotes of a query backend.
+#[derive(Debug,Default)]
+pub(crate) struct HealthTracker
+{
+	config: HealthConfig,
+	remotes: Mutex<HashMap<String,RemoteHealth>>,
+}
+
+impl HealthTracker
+{
+	pub fn new(config: HealthConfig) -> Self
+	{
+		Self
+		{
+			config,
+			remotes: Default::default(),
+		}
+	}
+
+	/// Picks the remotes to query, skipping those with an open circuit.
+	///
+	/// Remotes whose circuit has been open long enough are probed, one query at a time.
+	/// If no remote is left, all of them are queried, as there is nothing to lose.
+	pub fn select<'a>(&self, remotes: &'a [String]) -> Vec<&'a String>
+	{
+		let probe_interval = Duration::from_secs(self.config.probe_interval);
+		let mut health = self.remotes.lock().unwrap();
+		// forget about remotes which are no longer discovered
+		health.retain(|remote, _| remotes.contains(remote));
+
+		let selected = remotes.iter()
+			.filter(|remote|
+			{
+				let health = health.entry(remote.to_string()).or_default();
+				match health.circuit
+				{
+					Circuit::Closed => true,
+					Circuit::Open { since, } | Circuit::HalfOpen { since, } =>
+					{
+						if since.elapsed() >= probe_interval
+						{
+							info!("[http-health][{}] probing remote after {} failures", remote, health.consecutive_failures);
+							health.circuit = Circuit::HalfOpen { since: Instant::now(), };
+							true
+						}
+						else
+						{
+							false
+						}
+					},
+				}
+			})
+			.collect::<Vec<_>>()
+		;
+
+		if selected.is_empty()
+		{
+			debug!("[http-health] all remotes are unhealthy, querying all of them");
+			remotes.iter().collect()
+		}
+		else
+		{
+			selected
+		}
+	}
+
+	/// Whether failures of the remote are worth a warning, which is not the case if it is known to be broken.
+	pub fn is_healthy(&self, remote: &str) -> bool
+	{
+		self.remotes.lock().unwrap().get(remote).is_none_or(|health| health.circuit == Circuit::Closed)
+	}
+
+	pub fn success(&self, remote: &str, latency: Duration)
+	{
+		let mut remotes = self.remotes.lock().unwrap();
+		let health = remotes.entry(remote.to_string()).or_default();
+
+		if health.circuit != Circuit::Closed
+		{
+			info!("[http-health][{}] remote recovered after {} failures", remote, health.consecutive_failures);
+		}
+
+		let latency = latency.as_secs_f64();
+		health.circuit = Circuit::Closed;
+		health.consecutive_failures = 0;
+		health.last_success = Some(Instant::now());
+		health.latency = Some(health.latency.map_or(latency, |average| average + LATENCY_EWMA_WEIGHT * (latency - average)));
+	}
+
+	pub fn failure(&self, remote: &str)
+	{
+		let mut remotes = self.remotes.lock().unwrap();
+		let health = remotes.entry(remote.to_string()).or_default();
+
+		health.consecutive_failures += 1;
+
+		match health.circuit
+		{
+			Circuit::Closed if health.consecutive_failures >= self.config.failure_threshold =>
+			{
+				warn!("[http-health][{}] remote failed {} times in a row, skipping it for {}s", remote, health.consecutive_failures, self.config.probe_interval);
+				health.circuit = Circuit::Open { since: Instant::now(), };
+			},
+			Circuit::HalfOpen { .. } =>
+			{
+				debug!("[http-health][{}] probe failed, skipping remote for another {}s", remote, self.config.probe_interval);
+				health.circuit = Circuit::Open { since: Instant::now(), };
+			},
+			_ => {},
+		}
+	}
+
+	pub fn diagnostics(&self) -> Value
+	{
+		self.remotes.lock().unwrap().iter()
+			.map(|(remote, health)|
+			{
+				let circuit = match health.circuit
+				{
+					Circuit::Closed => "closed",
+					Circuit::Open { .. } => "open",
+					Circuit::HalfOpen { .. } => "half-open",
+				};
+
+				(remote.clone(), json!(
+				{
+					"circuit": circuit,
+					"consecutive_failures": health.consecutive_failures,
+					"last_success_seconds_ago": health.last_success.map(|instant| instant.elapsed().as_secs_f64()),
+					"latency_seconds": health.latency,
+				}))
+			})
+			.collect::<serde_json::Map<_,_>>()
+			.into()
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn tracker(failure_threshold: usize, probe_interval: u64) -> HealthTracker
+	{
+		HealthTracker::new(HealthConfig
+		{
+			failure_threshold,
+			probe_interval,
+		})
+	}
+
+	fn circuit(tracker: &HealthTracker, remote: &str) -> Circuit
+	{
+		tracker.remotes.lock().unwrap()[remote].circuit
+	}
+
+	#[test]
+	fn circuit_opens_after_consecutive_failures()
+	{
+		let tracker = tracker(2, 30);
+		let remotes = vec!["a".to_string(), "b".to_string()];
+
+		tracker.failure("a");
+		assert_eq!(circuit(&tracker, "a"), Circuit::Closed);
+		assert_eq!(tracker.select(&remotes), vec!["a", "b"]);
+
+		tracker.failure("a");
+		assert!(matches!(circuit(&tracker, "a"), Circuit::Open { .. }));
+		assert!(!tracker.is_healthy("a"));
+		assert_eq!(tracker.select(&remotes), vec!["b"]);
+	}
+
+	#[test]
+	fn success_resets_the_failure_count()
+	{
+		let tracker = tracker(2, 30);
+
+		tracker.failure("a");
+		tracker.success("a", Duration::ZERO);
+		tracker.failure("a");
+		assert_eq!(circuit(&tracker, "a"), Circuit::Closed);
+	}
+
+	#[test]
+	fn open_circuit_is_probed_and_closes_on_success()
+	{
+		let tracker = tracker(1, 0);
+		let remotes = vec!["a".to_string(), "b".to_string()];
+
+		tracker.failure("a");
+		assert!(matches!(circuit(&tracker, "a"), Circuit::Open { .. }));
+
+		assert_eq!(tracker.select(&remotes), vec!["a", "b"]);
+		assert!(matches!(circuit(&tracker, "a"), Circuit::HalfOpen { .. }));
+
+		tracker.success("a", Duration::ZERO);
+		assert_eq!(circuit(&tracker, "a"), Circuit::Closed);
+		assert!(tracker.is_healthy("a"));
+	}
+
+	#[test]
+	fn failed_probe_opens_the_circuit_again()
+	{
+		let tracker = tracker(1, 0);
+		let remotes = vec!["a".to_string()];
+
+		tracker.failure("a");
+		tracker.select(&remotes);
+		assert!(matches!(circuit(&tracker, "a"), Circuit::HalfOpen { .. }));
+
+		tracker.failure("a");
+		assert!(matches!(circuit(&tracker, "a"), Circuit::Open { .. }));
+	}
+
+	#[test]
+	fn every_remote_is_queried_if_all_are_open()
+	{
+		let tracker = tracker(1, 30);
+		let remotes = vec!["a".to_string(), "b".to_string()];
+
+		tracker.failure("a");
+		tracker.failure("b");
+		assert_eq!(tracker.select(&remotes), vec!["a", "b"]);
+		// querying them anyway is no probe
+		assert!(matches!(circuit(&tracker, "a"), Circuit::Open { .. }));
+	}
+
+	#[test]
+	fn latency_is_a_moving_average()
+	{
+		let tracker = tracker(3, 30);
+
+		tracker.success("a", Duration::from_secs(1));
+		assert_eq!(tracker.remotes.lock().unwrap()["a"].latency, Some(1.0));
+
+		tracker.success("a", Duration::from_secs(2));
+		let latency = tracker.remotes.lock().unwrap()["a"].latency.unwrap();
+		assert!((latency - 1.2).abs() < 1e-9);
+	}
+}
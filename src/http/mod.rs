@@ -9,15 +9,23 @@ mod responder;
 mod pipe;
 mod unix;
 mod query;
+mod health;
+mod discovery;
+mod events;
 
 pub use responder::Responder;
 pub use unix::Unix;
 pub use pipe::Pipe;
+pub use query::QueryStrategy;
+pub use health::HealthConfig;
+pub use discovery::DiscoveryConfig;
+pub use events::Event;
 
 #[derive(Clone,Eq,PartialEq,Ord,PartialOrd,Hash,Debug,serde::Serialize,serde::Deserialize)]
 pub enum ApiResponse
 {
 	V1(ApiResponseV1),
+	V2(ApiResponseV2),
 }
 
 #[derive(Clone,Eq,PartialEq,Ord,PartialOrd,Hash,Debug,serde::Serialize,serde::Deserialize)]
@@ -27,3 +35,86 @@ pub enum ApiResponseV1
 	AnyMatch(Vec<std::net::Ipv6Addr>),
 }
 
+/// Metadata of instances, served on `/metadata/v1`.
+#[derive(Clone,Eq,PartialEq,Hash,Debug,serde::Serialize,serde::Deserialize)]
+pub enum ApiMetadata
+{
+	V1(Vec<crate::lxd::InstanceMetadata>),
+}
+
+/// Body of unsuccessful responses, served as `application/problem+json`.
+#[derive(Clone,Eq,PartialEq,Ord,PartialOrd,Hash,Debug,serde::Serialize,serde::Deserialize)]
+pub struct ApiProblem
+{
+	/// Stable identifier of the error as per [`Error::code`](crate::error::Error::code).
+	pub code: String,
+	pub status: u16,
+	/// Human-readable description, not meant to be parsed.
+	pub detail: String,
+}
+
+#[derive(Clone,Eq,PartialEq,Ord,PartialOrd,Hash,Debug,serde::Serialize,serde::Deserialize)]
+pub struct ApiResponseV2
+{
+	/// Identity of the answering host, the LXD server name.
+	pub host: String,
+	/// TTL in seconds the responder suggests for caching this answer.
+	pub ttl: usize,
+	pub result: ApiResultV2,
+}
+
+#[derive(Clone,Eq,PartialEq,Ord,PartialOrd,Hash,Debug,serde::Serialize,serde::Deserialize)]
+pub enum ApiResultV2
+{
+	NoMatch,
+	Match(ApiMatchV2),
+}
+
+#[derive(Clone,Eq,PartialEq,Ord,PartialOrd,Hash,Debug,serde::Serialize,serde::Deserialize)]
+pub struct ApiMatchV2
+{
+	/// Name of the matching instance, which differs from the queried name for prefix matches.
+	pub instance: String,
+	pub kind: crate::lxd::MatchKind,
+	pub status: String,
+	pub addresses: Vec<ApiAddressV2>,
+}
+
+#[derive(Clone,Eq,PartialEq,Ord,PartialOrd,Hash,Debug,serde::Serialize,serde::Deserialize)]
+pub struct ApiAddressV2
+{
+	pub address: std::net::IpAddr,
+	pub family: crate::lxd::AddressFamily,
+	pub interface: String,
+}
+
+impl ApiResponse
+{
+	/// TTL in seconds suggested by the responder, v1 responders not suggesting any.
+	pub fn ttl(&self) -> Option<usize>
+	{
+		match self
+		{
+			ApiResponse::V1(_) => None,
+			ApiResponse::V2(response) => Some(response.ttl),
+		}
+	}
+
+	/// IPv6 addresses of a match, `None` if there was no match.
+	pub fn addresses(self) -> Option<Vec<std::net::Ipv6Addr>>
+	{
+		match self
+		{
+			ApiResponse::V1(ApiResponseV1::NoMatch) => None,
+			ApiResponse::V1(ApiResponseV1::AnyMatch(addresses)) => Some(addresses),
+			ApiResponse::V2(ApiResponseV2 { result: ApiResultV2::NoMatch, .. }) => None,
+			ApiResponse::V2(ApiResponseV2 { result: ApiResultV2::Match(found), .. }) => Some(found.addresses.into_iter()
+				.filter_map(|address| match address.address
+				{
+					std::net::IpAddr::V6(address) => Some(address),
+					std::net::IpAddr::V4(_) => None,
+				})
+				.collect()),
+		}
+	}
+}
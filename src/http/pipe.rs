@@ -8,10 +8,35 @@
 use crate::
 {
 	error::*,
+	http::
+	{
+		DiscoveryConfig,
+		HealthConfig,
+		QueryStrategy,
+		discovery::Remotes,
+	},
 	pdns::
 	{
-		TtlConfig
+		TtlConfig,
+		Zone,
+		ZoneConfig,
+	},
+	cache::
+	{
+		AnswerCache,
+		CacheConfig,
+		CachedQuery,
+	},
+	soa::
+	{
+		Soa,
+		SoaConfig,
+	},
+	pdns_io::
+	{
+		RemoteQuery,
 	},
+	shutdown::Shutdown,
 };
 
 use ::
@@ -29,9 +54,16 @@ use ::
 pub struct Pipe
 {
 	remote: Vec<String>,
+	discovery_config: DiscoveryConfig,
+	strategy: QueryStrategy,
+	health_config: HealthConfig,
 	domain: String,
 	hostmaster: String,
 	ttl_config: TtlConfig,
+	cache_config: CacheConfig,
+	soa_config: SoaConfig,
+	zone: Zone,
+	shutdown: Shutdown,
 }
 
 impl Pipe
@@ -45,9 +77,25 @@ impl Pipe
 	{
 		debug!("[pipe] connection opened");
 
-		let backend = super::query::RemoteQuery::new(self.remote).await?;
-		let handler = crate::pdns_io::PdnsStreamHandler::new(self.domain, &self.ttl_config, self.hostmaster, backend, stdin(), stdout()).await?;
-		handler.run().await?;
+		let remotes = Remotes::start(self.remote, self.discovery_config).await?;
+		let backend = super::query::RemoteQuery::new(remotes, self.strategy, self.health_config).await?;
+		let backend: Box<dyn RemoteQuery+Send+Sync> = if self.cache_config.enabled()
+		{
+			Box::new(CachedQuery::new(backend, AnswerCache::new(&self.cache_config, &self.ttl_config)))
+		}
+		else
+		{
+			Box::new(backend)
+		};
+		self.zone.records().reload_on_signal()?;
+		let handler = crate::pdns_io::PdnsStreamHandler::new(self.domain, &self.ttl_config, self.hostmaster, backend, stdin(), stdout()).await?
+			.soa(Soa::new(self.soa_config.clone()))
+			.zone(self.zone.clone())
+			.shutdown(self.shutdown.clone());
+		if self.shutdown.drain(handler.run()).await.transpose()?.is_none()
+		{
+			warn!("[pipe] connection cut off by shutdown");
+		}
 
 		debug!("[pipe] connection closed");
 
@@ -55,13 +103,20 @@ impl Pipe
 	}
 }
 
-#[derive(Clone,Eq,PartialEq,Hash,Debug,Default)]
+#[derive(Clone,Debug,Default)]
 pub struct PipeBuilder
 {
 	remote: Option<Vec<String>>,
+	discovery_config: Option<DiscoveryConfig>,
+	strategy: Option<QueryStrategy>,
+	health_config: Option<HealthConfig>,
 	domain: Option<String>,
 	hostmaster: Option<String>,
 	ttl_config: Option<TtlConfig>,
+	cache_config: Option<CacheConfig>,
+	soa_config: Option<SoaConfig>,
+	zone_config: Option<ZoneConfig>,
+	shutdown: Option<Shutdown>,
 }
 
 impl PipeBuilder
@@ -72,6 +127,24 @@ impl PipeBuilder
 		self
 	}
 
+	pub fn discovery_config(mut self, discovery_config: DiscoveryConfig) -> Self
+	{
+		self.discovery_config = Some(discovery_config);
+		self
+	}
+
+	pub fn strategy(mut self, strategy: QueryStrategy) -> Self
+	{
+		self.strategy = Some(strategy);
+		self
+	}
+
+	pub fn health_config(mut self, health_config: HealthConfig) -> Self
+	{
+		self.health_config = Some(health_config);
+		self
+	}
+
 	pub fn domain<S: AsRef<str>>(mut self, domain: S) -> Self
 	{
 		self.domain = Some(domain.as_ref().into());
@@ -90,19 +163,58 @@ impl PipeBuilder
 		self
 	}
 
+	pub fn soa_config(mut self, soa_config: SoaConfig) -> Self
+	{
+		self.soa_config = Some(soa_config);
+		self
+	}
+
+	pub fn zone_config(mut self, zone_config: ZoneConfig) -> Self
+	{
+		self.zone_config = Some(zone_config);
+		self
+	}
+
+	pub fn cache_config(mut self, cache_config: CacheConfig) -> Self
+	{
+		self.cache_config = Some(cache_config);
+		self
+	}
+
+	pub fn shutdown(mut self, shutdown: Shutdown) -> Self
+	{
+		self.shutdown = Some(shutdown);
+		self
+	}
+
 	pub async fn run(self) -> Result<()>
 	{
-		let remote = self.remote.map(Result::Ok).unwrap_or_else(|| bail!("no remote provided")).context(Error::InvalidConfiguration)?;
+		let remote = self.remote.unwrap_or_default();
+		let discovery_config = self.discovery_config.unwrap_or_default();
 		let domain = self.domain.map(Result::Ok).unwrap_or_else(|| bail!("no domain provided")).context(Error::InvalidConfiguration)?;
 		let hostmaster = self.hostmaster.map(Result::Ok).unwrap_or_else(|| bail!("no hostmaster provided")).context(Error::InvalidConfiguration)?;
 		let ttl_config = self.ttl_config.unwrap_or_default();
+		let strategy = self.strategy.unwrap_or_default();
+		let health_config = self.health_config.unwrap_or_default();
+		let cache_config = self.cache_config.unwrap_or_default();
+		let soa_config = self.soa_config.unwrap_or_default();
+		soa_config.without_tracking().context(Error::InvalidConfiguration)?;
+		let zone = Zone::new(&domain, &self.zone_config.unwrap_or_default())?;
+		let shutdown = self.shutdown.unwrap_or_default();
 
 		Pipe
 		{
 			remote,
+			discovery_config,
+			strategy,
+			health_config,
 			domain,
 			hostmaster,
 			ttl_config,
+			cache_config,
+			soa_config,
+			zone,
+			shutdown,
 		}.run().await
 	}
 }
@@ -11,11 +11,21 @@ use crate::
 	lxd::
 	{
 		ContainerName,
+		InstanceMetadata,
 	},
 	http::
 	{
+		ApiMetadata,
+		ApiProblem,
 		ApiResponse,
-		ApiResponseV1,
+		ApiResponseV2,
+		ApiResultV2,
+		discovery::Remotes,
+		health::
+		{
+			HealthConfig,
+			HealthTracker,
+		},
 	},
 	pdns_io::
 	{
@@ -28,16 +38,26 @@ use ::
 	reqwest::
 	{
 		Client,
+		ClientBuilder,
+		Response,
+		StatusCode,
+	},
+	serde_json::
+	{
+		json,
+		Value,
 	},
 	futures::
 	{
-		stream,
-		FutureExt,
+		stream::FuturesUnordered,
 		StreamExt,
 	},
 	std::
 	{
+		collections::HashMap,
+		str::FromStr,
 		net::Ipv6Addr,
+		sync::Mutex,
 		time::
 		{
 			Duration,
@@ -46,27 +66,303 @@ use ::
 	},
 };
 
+/// When the fan-out over all remotes is considered done.
+///
+/// Requests still in flight once a decision is made are cancelled.
+///
+/// ```
+/// # use lxddns::http::QueryStrategy;
+/// assert_eq!("all".parse::<QueryStrategy>().unwrap(), QueryStrategy::All);
+/// assert_eq!("first".parse::<QueryStrategy>().unwrap(), QueryStrategy::First);
+/// assert_eq!("quorum:2".parse::<QueryStrategy>().unwrap(), QueryStrategy::Quorum(2));
+/// assert!("quorum".parse::<QueryStrategy>().is_err());
+/// assert!("quorum:0".parse::<QueryStrategy>().is_err());
+/// ```
+#[derive(Clone,Copy,Eq,PartialEq,Hash,Debug,Default)]
+pub enum QueryStrategy
+{
+	/// Wait for every remote to answer or fail.
+	#[default]
+	All,
+	/// Return as soon as any remote reports a match, otherwise wait for every remote.
+	First,
+	/// Return as soon as the given number of remotes agree on a match, otherwise wait for every remote.
+	///
+	/// An instance lives on a single host, so any number of remotes not knowing it does not prove its absence.
+	Quorum(usize),
+}
+
+impl FromStr for QueryStrategy
+{
+	type Err = crate::error::Error;
+
+	fn from_str(strategy: &str) -> std::result::Result<Self,Self::Err>
+	{
+		match strategy.split_once(':')
+		{
+			None if strategy == "all" => Ok(QueryStrategy::All),
+			None if strategy == "first" => Ok(QueryStrategy::First),
+			Some(("quorum", count)) => match count.parse()?
+			{
+				0 => Err(Error::InvalidConfiguration),
+				count => Ok(QueryStrategy::Quorum(count)),
+			},
+			_ => Err(Error::InvalidConfiguration),
+		}
+	}
+}
+
+/// Scheme of remotes reachable via a unix domain socket, the percent-encoded socket path taking the place of the host.
+///
+/// An API root at `/lxddns` served on `/run/lxddns/http.sock` would thus be `http+unix://%2Frun%2Flxddns%2Fhttp.sock/lxddns`.
+const UNIX_SCHEME: &str = "http+unix://";
+
+/// Time after which a remote only speaking v1 is asked for v2 again.
+const V2_REPROBE: Duration = Duration::from_secs(600);
+
+fn client_builder() -> ClientBuilder
+{
+	// connections are kept alive and multiplexed so handshakes with remotes are rare
+	Client::builder()
+		.timeout(Duration::from_millis(1500))
+		.connect_timeout(Duration::from_millis(500))
+		.pool_idle_timeout(Duration::from_secs(300))
+		.tcp_keepalive(Duration::from_secs(30))
+		.http2_adaptive_window(true)
+		.http2_keep_alive_interval(Duration::from_secs(30))
+		.http2_keep_alive_timeout(Duration::from_secs(10))
+		.http2_keep_alive_while_idle(true)
+}
+
+/// Decodes `%XX` sequences, leaving anything else untouched.
+fn percent_decode(input: &str) -> Result<String>
+{
+	let mut bytes = Vec::with_capacity(input.len());
+	let mut rest = input.as_bytes();
+	while let Some((&byte, tail)) = rest.split_first()
+	{
+		match (byte, tail)
+		{
+			(b'%', [high, low, tail @ ..]) =>
+			{
+				let hex = [*high, *low];
+				bytes.push(u8::from_str_radix(std::str::from_utf8(&hex)?, 16).with_context(|| format!("invalid percent-encoding in {}", input))?);
+				rest = tail;
+			},
+			_ =>
+			{
+				bytes.push(byte);
+				rest = tail;
+			},
+		}
+	}
+
+	Ok(String::from_utf8(bytes)?)
+}
+
+/// Error for an unsuccessful response, rejections by the remote being told apart by [`Error::RemoteRejected`].
+async fn failure(response: Response) -> anyhow::Error
+{
+	let status = response.status();
+	// responders predating problem bodies only send plain text
+	let problem = response.json::<ApiProblem>().await.ok();
+	let code = problem.as_ref().map(|problem| problem.code.clone()).unwrap_or_else(|| "unknown".to_string());
+	if status.is_client_error()
+	{
+		return Error::RemoteRejected(code).into();
+	}
+
+	match problem
+	{
+		Some(problem) => anyhow::anyhow!("remote failed with {} ({}): {}", status, code, problem.detail),
+		None => anyhow::anyhow!("unexpected http response code: {}", status),
+	}
+}
+
 pub struct RemoteQuery
 {
 	http: Client,
-	remote: Vec<String>,
+	unix_clients: Mutex<HashMap<String,Client>>,
+	/// Remotes which answered `/resolve/v2` with 404, i.e. older responders only speaking v1, along with when they did.
+	v1_only: Mutex<HashMap<String,Instant>>,
+	remote: Remotes,
+	strategy: QueryStrategy,
+	health: HealthTracker,
 }
 
 impl RemoteQuery
 {
-	pub async fn new(remote: Vec<String>) -> Result<Self>
+	pub async fn new(remote: Remotes, strategy: QueryStrategy, health_config: HealthConfig) -> Result<Self>
 	{
-		info!("[http-remote_query][new] instantiated");
+		info!("[http-remote_query][new] instantiated with strategy {:?}", strategy);
 
 		Ok(Self
 		{
-			http: Client::builder()
-				.timeout(Duration::from_millis(1500))
-				.connect_timeout(Duration::from_millis(500))
-				.build()?,
+			http: client_builder().build()?,
+			unix_clients: Default::default(),
+			v1_only: Default::default(),
 			remote,
+			strategy,
+			health: HealthTracker::new(health_config),
 		})
 	}
+
+	/// Result of a single remote along with the TTL it suggests, `None` if the remote failed.
+	///
+	/// Remotes rejecting the request are considered to not have a match, as they are reachable and working.
+	async fn query_remote(&self, remote: &str, name: &ContainerName) -> Option<(Option<Vec<Ipv6Addr>>, Option<usize>)>
+	{
+		let instant = Instant::now();
+		let response = self.request_remote(remote, name).await;
+
+		match response
+		{
+			Ok(response) =>
+			{
+				self.health.success(remote, instant.elapsed());
+				if let ApiResponse::V2(ApiResponseV2 { ref host, result: ApiResultV2::Match(ref found), .. }) = response
+				{
+					debug!("[remote_query][{}] {} matched {} ({:?}) on host {}", remote, name.as_ref(), found.instance, found.kind, host);
+				}
+				let ttl = response.ttl();
+				Some((response.addresses(), ttl))
+			},
+			Err(err) if matches!(Error::find(&err), Some(Error::RemoteRejected(_))) =>
+			{
+				debug!("[remote_query][{}] {:#}, assuming no match", remote, err);
+				self.health.success(remote, instant.elapsed());
+				Some((None, None))
+			},
+			Err(err) =>
+			{
+				// remotes known to be broken would otherwise flood the log on every single query
+				if self.health.is_healthy(remote)
+				{
+					warn!("[remote_query][{}] {:#}", remote, err);
+				}
+				else
+				{
+					debug!("[remote_query][{}] {:#}", remote, err);
+				}
+				self.health.failure(remote);
+				None
+			},
+		}
+	}
+
+	/// Client and API root to use for a remote, with unix socket remotes getting a client of their own.
+	fn endpoint(&self, remote: &str) -> Result<(Client, String)>
+	{
+		let Some(remote) = remote.strip_prefix(UNIX_SCHEME)
+		else
+		{
+			return Ok((self.http.clone(), remote.to_string()));
+		};
+
+		let (socket, root) = remote.split_at(remote.find('/').unwrap_or(remote.len()));
+		let socket = percent_decode(socket)?;
+
+		let mut clients = self.unix_clients.lock().unwrap();
+		let client = match clients.get(&socket)
+		{
+			Some(client) => client.clone(),
+			None =>
+			{
+				let client = client_builder().unix_socket(socket.as_str()).build()?;
+				clients.insert(socket, client.clone());
+				client
+			},
+		};
+
+		// the host is irrelevant for the socket, but required for a valid URL
+		Ok((client, format!("http://localhost{}", root)))
+	}
+
+	/// Queries a remote via `/resolve/v2`, falling back to `/resolve/v1` for remotes which do not know v2 yet.
+	async fn request_remote(&self, remote: &str, name: &ContainerName) -> Result<ApiResponse>
+	{
+		// the remote might have been upgraded since, or the 404 might not have been its own to begin with
+		let v2 = self.v1_only.lock().unwrap().get(remote).is_none_or(|since| since.elapsed() >= V2_REPROBE);
+		if v2
+		{
+			match self.request_version(remote, "v2", name).await?
+			{
+				Some(response) => return Ok(response),
+				None =>
+				{
+					info!("[remote_query][{}] remote does not support v2, falling back to v1", remote);
+					self.v1_only.lock().unwrap().insert(remote.to_string(), Instant::now());
+				},
+			}
+		}
+
+		match self.request_version(remote, "v1", name).await?
+		{
+			Some(response) => Ok(response),
+			None => bail!("unexpected http response code: {}", StatusCode::NOT_FOUND),
+		}
+	}
+
+	/// Queries a specific API version, returning `None` if the remote does not know the endpoint.
+	async fn request_version(&self, remote: &str, version: &str, name: &ContainerName) -> Result<Option<ApiResponse>>
+	{
+		let (http, root) = self.endpoint(remote)?;
+		let response = http.get(format!("{}/resolve/{}/{}", root, version, name.as_ref())).send().await
+			.map_err(|err|
+			{
+				debug!("[remote_query][{}] verbose http error: {:?}", remote, err);
+				err
+			})
+			.context("http error")?;
+
+		let status = response.status();
+		if status == StatusCode::NOT_FOUND
+		{
+			return Ok(None);
+		}
+		if !status.is_success()
+		{
+			return Err(failure(response).await);
+		}
+
+		let response = response.json::<ApiResponse>().await
+			.map_err(|err|
+			{
+				debug!("[remote_query][{}] verbose json deserialization error: {:?}", remote, err);
+				err
+			})
+			.context("json deserialization error")?;
+
+		Ok(Some(response))
+	}
+
+	/// Queries the metadata of a remote, remotes predating `/metadata/v1` having none.
+	async fn request_metadata(&self, remote: &str, name: Option<&ContainerName>) -> Result<Vec<InstanceMetadata>>
+	{
+		let (http, root) = self.endpoint(remote)?;
+		let url = match name
+		{
+			Some(name) => format!("{}/metadata/v1/{}", root, name.as_ref()),
+			None => format!("{}/metadata/v1", root),
+		};
+		let response = http.get(url).send().await.context("http error")?;
+
+		let status = response.status();
+		if status == StatusCode::NOT_FOUND
+		{
+			debug!("[remote_query][{}] remote does not support metadata", remote);
+			return Ok(Vec::new());
+		}
+		if !status.is_success()
+		{
+			return Err(failure(response).await);
+		}
+
+		match response.json::<ApiMetadata>().await.context("json deserialization error")?
+		{
+			ApiMetadata::V1(metadata) => Ok(metadata),
+		}
+	}
 }
 
 #[async_trait::async_trait]
@@ -77,60 +373,67 @@ impl RemoteQueryTrait for RemoteQuery
 		"http-remote_query".to_string()
 	}
 
+	fn diagnostics(&self) -> Value
+	{
+		json!(
+		{
+			"strategy": format!("{:?}", self.strategy),
+			"remotes": self.health.diagnostics(),
+		})
+	}
+
 	async fn remote_query(&self, name: &ContainerName) -> Result<Option<Vec<Ipv6Addr>>>
+	{
+		Ok(self.remote_query_ttl(name).await?.0)
+	}
+
+	async fn remote_query_ttl(&self, name: &ContainerName) -> Result<(Option<Vec<Ipv6Addr>>, Option<usize>)>
 	{
 		debug!("[remote_query][{}] starting query", name.as_ref());
 		let instant = Instant::now();
-		let mut requests = stream::iter(self.remote.clone())
-			.filter_map(|remote|
-			{
-				self.http.get(format!("{}/resolve/v1/{}", remote, name.as_ref())).send()
-					.then(|response| Box::pin(async move
-					{
-						match response
-						{
-							Err(err) =>
-							{
-								warn!("[remote_query][{}] http error: {}", remote, err);
-								debug!("[remote_query][{}] verbose http error: {:?}", remote, err);
-								None
-							},
-							Ok(response) =>
-							{
-								let status = response.status();
-								if !status.is_success()
-								{
-									warn!("[remote_query][{}] unexpected http response code: {}", remote, status);
-									None
-								}
-								else
-								{
-									match response.json::<ApiResponse>().await
-									{
-										Ok(response) => Some(response),
-										Err(err) =>
-										{
-											warn!("[remote_query][{}] json deserialization error: {}", remote, err);
-											debug!("[remote_query][{}] verbose json deserialization error: {:?}", remote, err);
-											None
-										},
-									}
-								}
-							}
-						}
-					}))
-			})
+		let remotes = self.remote.get();
+		let selected = self.health.select(&remotes);
+		if selected.is_empty()
+		{
+			debug!("[http-remote_query][{}] no remotes to query", name.as_ref());
+			bail!(Error::RemotesUnavailable);
+		}
+
+		let mut requests = selected.into_iter()
+			.map(|remote| self.query_remote(remote, name))
+			.collect::<FuturesUnordered<_>>()
 		;
 
 		let mut result: Option<Vec<Ipv6Addr>> = None;
+		// lowest TTL suggested along with a match, answers of several remotes being merged
+		let mut ttl: Option<usize> = None;
+		// number of remotes reporting each set of addresses, sorted as remotes answer in no particular order
+		let mut agreeing: HashMap<Vec<Ipv6Addr>,usize> = HashMap::new();
+		let mut answered = 0;
+		let mut failed = 0;
 		while let Some(response) = requests.next().await
 		{
 			trace!("[http-remote_query][{}]: {:?}", name.as_ref(), response);
 			match response
 			{
-				ApiResponse::V1(ApiResponseV1::NoMatch) => {},
-				ApiResponse::V1(ApiResponseV1::AnyMatch(response)) =>
+				None =>
 				{
+					failed += 1;
+					continue;
+				},
+				Some((None, _)) => {},
+				Some((Some(response), suggested)) =>
+				{
+					ttl = match (ttl, suggested)
+					{
+						(Some(ttl), Some(suggested)) => Some(ttl.min(suggested)),
+						(ttl, suggested) => ttl.or(suggested),
+					};
+
+					let mut addresses = response.clone();
+					addresses.sort();
+					*agreeing.entry(addresses).or_default() += 1;
+
 					match result
 					{
 						Some(ref mut vec) => vec.extend(response),
@@ -138,9 +441,263 @@ impl RemoteQueryTrait for RemoteQuery
 					}
 				},
 			}
+			answered += 1;
+
+			// negative answers never end the fan-out early, the owner of the instance might just be slow
+			let done = match self.strategy
+			{
+				QueryStrategy::All => false,
+				QueryStrategy::First => result.is_some(),
+				QueryStrategy::Quorum(quorum) => agreeing.values().any(|count| *count >= quorum),
+			};
+
+			if done
+			{
+				debug!("[http-remote_query][{}] {:?} satisfied after {} answers, cancelling {} requests", name.as_ref(), self.strategy, answered, requests.len());
+				break;
+			}
+		}
+
+		debug!("[http-remote_query][{}] got response after {:.3}s: {:?} (ttl: {:?})", name.as_ref(), instant.elapsed().as_secs_f64(), result, ttl);
+
+		// not a single answer means the instance might well exist, so the query must not be answered negatively
+		if answered == 0 && failed > 0
+		{
+			bail!(Error::RemotesUnavailable);
+		}
+
+		Ok((result, ttl))
+	}
+
+	async fn metadata(&self, name: Option<&ContainerName>) -> Result<Vec<InstanceMetadata>>
+	{
+		let label = name.map(AsRef::as_ref).unwrap_or("*");
+		debug!("[http-remote_query][{}] starting metadata query", label);
+		let remotes = self.remote.get();
+		let selected = self.health.select(&remotes);
+		if selected.is_empty()
+		{
+			debug!("[http-remote_query][{}] no remotes to ask for metadata", label);
+			bail!(Error::RemotesUnavailable);
+		}
+
+		let mut requests = selected.into_iter()
+			.map(|remote| async move { (remote, self.request_metadata(remote, name).await) })
+			.collect::<FuturesUnordered<_>>()
+		;
+
+		let mut result = Vec::new();
+		let mut answered = 0;
+		let mut failed = 0;
+		while let Some((remote, response)) = requests.next().await
+		{
+			match response
+			{
+				Ok(metadata) =>
+				{
+					answered += 1;
+					result.extend(metadata);
+
+					// an instance lives on a single host, so there is no need to wait for the others
+					if name.is_some() && !result.is_empty()
+					{
+						break;
+					}
+				},
+				Err(err) if matches!(Error::find(&err), Some(Error::RemoteRejected(_))) =>
+				{
+					debug!("[remote_query][{}] {:#}, assuming no metadata", remote, err);
+					answered += 1;
+				},
+				Err(err) =>
+				{
+					debug!("[remote_query][{}] metadata query failed: {:#}", remote, err);
+					failed += 1;
+				},
+			}
+		}
+
+		debug!("[http-remote_query][{}] got metadata of {} instances", label, result.len());
+
+		// a failed remote might have had more, which would otherwise silently be missing
+		if failed > 0 && (name.is_none() || result.is_empty())
+		{
+			debug!("[http-remote_query][{}] {} of {} remotes failed to answer", label, failed, answered + failed);
+			bail!(Error::RemotesUnavailable);
 		}
 
-		debug!("[http-remote_query][{}] got response after {:.3}s: {:?}", name.as_ref(), instant.elapsed().as_secs_f64(), result);
 		Ok(result)
 	}
 }
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use crate::
+	{
+		http::
+		{
+			ApiAddressV2,
+			ApiMatchV2,
+		},
+		lxd::
+		{
+			AddressFamily,
+			MatchKind,
+		},
+	};
+	use ::
+	{
+		tokio::
+		{
+			io::
+			{
+				AsyncReadExt,
+				AsyncWriteExt,
+			},
+			net::TcpListener,
+		},
+	};
+
+	/// Remote answering every request with the given response after a delay, returning its API root.
+	async fn remote<T: serde::Serialize>(delay: Duration, response: T) -> String
+	{
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let root = format!("http://{}", listener.local_addr().unwrap());
+		let body = serde_json::to_string(&response).unwrap();
+
+		tokio::spawn(async move
+		{
+			while let Ok((mut stream, _)) = listener.accept().await
+			{
+				let body = body.clone();
+				tokio::spawn(async move
+				{
+					let mut request = Vec::new();
+					let mut buffer = [0; 1024];
+					while !request.ends_with(b"\r\n\r\n")
+					{
+						match stream.read(&mut buffer).await
+						{
+							Ok(0) | Err(_) => return,
+							Ok(length) => request.extend_from_slice(&buffer[..length]),
+						}
+					}
+
+					tokio::time::sleep(delay).await;
+					let response = format!("HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}", body.len(), body);
+					let _ = stream.write_all(response.as_bytes()).await;
+				});
+			}
+		});
+
+		root
+	}
+
+	fn no_match(host: &str) -> ApiResponse
+	{
+		ApiResponse::V2(ApiResponseV2
+		{
+			host: host.to_string(),
+			ttl: 128,
+			result: ApiResultV2::NoMatch,
+		})
+	}
+
+	fn found(host: &str, ttl: usize, address: Ipv6Addr) -> ApiResponse
+	{
+		ApiResponse::V2(ApiResponseV2
+		{
+			host: host.to_string(),
+			ttl,
+			result: ApiResultV2::Match(ApiMatchV2
+			{
+				instance: "web".to_string(),
+				kind: MatchKind::Exact,
+				status: "Running".to_string(),
+				addresses: vec![ApiAddressV2
+				{
+					address: address.into(),
+					family: AddressFamily::Inet6,
+					interface: "eth0".to_string(),
+				}],
+			}),
+		})
+	}
+
+	#[tokio::test]
+	async fn quorum_waits_for_a_slow_owner()
+	{
+		let address: Ipv6Addr = "2001:db8::1".parse().unwrap();
+		let remotes = vec!
+		[
+			remote(Duration::ZERO, no_match("a")).await,
+			remote(Duration::ZERO, no_match("b")).await,
+			remote(Duration::from_millis(300), found("owner", 128, address)).await,
+		];
+
+		let remotes = Remotes::start(remotes, Default::default()).await.unwrap();
+		let query = RemoteQuery::new(remotes, QueryStrategy::Quorum(2), Default::default()).await.unwrap();
+
+		assert_eq!(query.remote_query(&"web".parse().unwrap()).await.unwrap(), Some(vec![address]));
+	}
+
+	#[tokio::test]
+	async fn quorum_is_reached_by_agreeing_matches()
+	{
+		let address: Ipv6Addr = "2001:db8::1".parse().unwrap();
+		let remotes = vec!
+		[
+			remote(Duration::ZERO, found("a", 128, address)).await,
+			remote(Duration::ZERO, found("b", 128, address)).await,
+			remote(Duration::from_secs(1), no_match("slow")).await,
+		];
+
+		let remotes = Remotes::start(remotes, Default::default()).await.unwrap();
+		let query = RemoteQuery::new(remotes, QueryStrategy::Quorum(2), Default::default()).await.unwrap();
+
+		let instant = Instant::now();
+		let result = query.remote_query(&"web".parse().unwrap()).await.unwrap().unwrap();
+		assert!(result.iter().all(|found| *found == address));
+		assert!(instant.elapsed() < Duration::from_millis(500));
+	}
+
+	#[tokio::test]
+	async fn lowest_suggested_ttl_of_the_matches_is_used()
+	{
+		let address: Ipv6Addr = "2001:db8::1".parse().unwrap();
+		let remotes = vec!
+		[
+			remote(Duration::ZERO, found("a", 300, address)).await,
+			remote(Duration::ZERO, found("b", 60, address)).await,
+			remote(Duration::ZERO, no_match("c")).await,
+		];
+
+		let remotes = Remotes::start(remotes, Default::default()).await.unwrap();
+		let query = RemoteQuery::new(remotes, QueryStrategy::All, Default::default()).await.unwrap();
+
+		let (_, ttl) = query.remote_query_ttl(&"web".parse().unwrap()).await.unwrap();
+		assert_eq!(ttl, Some(60));
+	}
+
+	#[tokio::test]
+	async fn metadata_of_failed_remotes_is_not_left_out()
+	{
+		let unreachable = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let remotes = vec!
+		[
+			remote(Duration::ZERO, ApiMetadata::V1(vec![])).await,
+			format!("http://{}", unreachable.local_addr().unwrap()),
+		];
+		drop(unreachable);
+
+		let remotes = Remotes::start(remotes, Default::default()).await.unwrap();
+		let query = RemoteQuery::new(remotes, QueryStrategy::All, Default::default()).await.unwrap();
+
+		let err = query.metadata(None).await.unwrap_err();
+		assert!(matches!(Error::find(&err), Some(Error::RemotesUnavailable)));
+		let err = query.metadata(Some(&"web".parse().unwrap())).await.unwrap_err();
+		assert!(matches!(Error::find(&err), Some(Error::RemotesUnavailable)));
+	}
+}
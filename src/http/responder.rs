@@ -10,13 +10,30 @@ use crate::
 	error::*,
 	lxd::
 	{
+		InstanceMatch,
+		local_lookup,
+		local_metadata,
 		local_query,
+		server_name,
 	},
 	http::
 	{
+		ApiMetadata,
+		ApiProblem,
 		ApiResponse,
 		ApiResponseV1,
+		ApiResponseV2,
+		ApiResultV2,
+		ApiMatchV2,
+		ApiAddressV2,
+		events::
+		{
+			Event,
+			Events,
+		},
 	},
+	systemd::Activated,
+	shutdown::Shutdown,
 };
 
 use ::
@@ -34,6 +51,18 @@ use ::
 		Responder as ActixResponder,
 		HttpResponse,
 		HttpRequest,
+		http::StatusCode,
+		web::Bytes,
+	},
+	futures::stream,
+	tokio::
+	{
+		sync::
+		{
+			broadcast::error::RecvError,
+			OnceCell,
+		},
+		time::interval,
 	},
 	rustls::
 	{
@@ -53,11 +82,69 @@ use ::
 	},
 	std::
 	{
-		fs::File,
+		fs::
+		{
+			File,
+			metadata,
+			remove_file,
+		},
 		io::BufReader,
+		os::unix::fs::FileTypeExt,
+		collections::VecDeque,
+		sync::Arc,
+		time::Duration,
 	},
 };
 
+/// Default listener if none is configured at all, for compatibility with earlier versions.
+const DEFAULT_HTTPS_BIND: &str = "[::1]:9132";
+
+/// `FileDescriptorName=` of activated TCP sockets to serve plain HTTP on.
+const ACTIVATED_HTTP_NAME: &str = "http";
+
+/// Interval of comments sent on idle event streams so proxies do not time them out.
+const EVENTS_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Problem response for a failed request, with the status depending on whose fault the error is.
+fn problem(err: anyhow::Error) -> HttpResponse
+{
+	let error = Error::find(&err);
+	let status = match error
+	{
+		Some(Error::UnsafeName(_)) => StatusCode::BAD_REQUEST,
+		// LXD is the upstream of the responder
+		Some(Error::LocalExecution(_) | Error::LocalOutput) => StatusCode::BAD_GATEWAY,
+		_ => StatusCode::INTERNAL_SERVER_ERROR,
+	};
+
+	if status.is_server_error()
+	{
+		warn!("[http-responder] request failed: {:#}", err);
+	}
+
+	HttpResponse::build(status)
+		.content_type("application/problem+json")
+		.json(ApiProblem
+		{
+			code: error.map(Error::code).unwrap_or("internal").to_string(),
+			status: status.as_u16(),
+			detail: format!("{:#}", err),
+		})
+}
+
+/// Fallback for unknown paths, backends rely on the 404 to detect which API versions a responder supports.
+async fn not_found(request: HttpRequest) -> HttpResponse
+{
+	HttpResponse::NotFound()
+		.content_type("application/problem+json")
+		.json(ApiProblem
+		{
+			code: "not_found".to_string(),
+			status: StatusCode::NOT_FOUND.as_u16(),
+			detail: format!("no such endpoint: {}", request.path()),
+		})
+}
+
 #[get("/resolve/v1/{name}")]
 async fn resolve(request: HttpRequest, name: web::Path<String>) -> impl ActixResponder
 {
@@ -67,28 +154,188 @@ async fn resolve(request: HttpRequest, name: web::Path<String>) -> impl ActixRes
 
 	match config.and_then(|config| Ok((config, name.parse()?)))
 	{
-		Err(err) => HttpResponse::BadRequest().body(format!("{}", err)),
+		Err(err) => problem(err),
 		Ok((config, name)) => match local_query(&config.command, &name).await
 		{
 			Ok(None) => HttpResponse::Ok().json(ApiResponse::V1(ApiResponseV1::NoMatch)),
 			Ok(Some(res)) => HttpResponse::Ok().json(ApiResponse::V1(ApiResponseV1::AnyMatch(res))),
-			Err(err) => HttpResponse::InternalServerError().body(format!("{}", err)),
+			Err(err) => problem(err),
 		},
 	}
 }
 
+/// Body of `/resolve/v2` for the result of a local lookup.
+fn resolve_v2_response(host: String, ttl: usize, found: Option<InstanceMatch>) -> ApiResponse
+{
+	let result = match found
+	{
+		None => ApiResultV2::NoMatch,
+		Some(found) => ApiResultV2::Match(ApiMatchV2
+		{
+			instance: found.name().clone(),
+			kind: *found.kind(),
+			status: found.status().clone(),
+			addresses: found.addresses().iter()
+				.map(|address| ApiAddressV2
+				{
+					address: *address.address(),
+					family: *address.family(),
+					interface: address.interface().clone(),
+				})
+				.collect(),
+		}),
+	};
+
+	ApiResponse::V2(ApiResponseV2
+	{
+		host,
+		ttl,
+		result,
+	})
+}
+
+#[get("/resolve/v2/{name}")]
+async fn resolve_v2(request: HttpRequest, name: web::Path<String>) -> impl ActixResponder
+{
+	trace!("[http-responder][resolve_v2] request received for {:?}", name);
+
+	let config: Result<&web::Data<ResponderConfig>> = request.app_data().ok_or(Error::ResponderError).context("cannot retrieve app_data");
+
+	let (config, name) = match config.and_then(|config| Ok((config, name.parse()?)))
+	{
+		Err(err) => return problem(err),
+		Ok(ok) => ok,
+	};
+
+	let response = async
+	{
+		let host = config.host.get_or_try_init(|| server_name(&config.command)).await?.clone();
+		let found = local_lookup(&config.command, &name).await?;
+
+		Result::<_>::Ok(resolve_v2_response(host, config.suggested_ttl, found))
+	};
+
+	match response.await
+	{
+		Ok(response) => HttpResponse::Ok().json(response),
+		Err(err) => problem(err),
+	}
+}
+
+/// Metadata of the instance the name refers to, empty if there is none.
+#[get("/metadata/v1/{name}")]
+async fn metadata_instance(request: HttpRequest, name: web::Path<String>) -> impl ActixResponder
+{
+	trace!("[http-responder][metadata_instance] request received for {:?}", name);
+
+	let config: Result<&web::Data<ResponderConfig>> = request.app_data().ok_or(Error::ResponderError).context("cannot retrieve app_data");
+
+	match config.and_then(|config| Ok((config, name.parse()?)))
+	{
+		Err(err) => problem(err),
+		Ok((config, name)) => match local_metadata(&config.command, Some(&name)).await
+		{
+			Ok(metadata) => HttpResponse::Ok().json(ApiMetadata::V1(metadata)),
+			Err(err) => problem(err),
+		},
+	}
+}
+
+/// Metadata of all running instances.
+#[get("/metadata/v1")]
+async fn metadata_all(request: HttpRequest) -> impl ActixResponder
+{
+	trace!("[http-responder][metadata_all] request received");
+
+	let config: Result<&web::Data<ResponderConfig>> = request.app_data().ok_or(Error::ResponderError).context("cannot retrieve app_data");
+
+	match config
+	{
+		Err(err) => problem(err),
+		Ok(config) => match local_metadata(&config.command, None).await
+		{
+			Ok(metadata) => HttpResponse::Ok().json(ApiMetadata::V1(metadata)),
+			Err(err) => problem(err),
+		},
+	}
+}
+
+fn event_frame(event: &Event) -> Bytes
+{
+	Bytes::from(format!("event: {}\ndata: {}\n\n", event.kind(), serde_json::json!(event)))
+}
+
+/// Server-Sent Events stream of changes to the local instances.
+///
+/// New subscribers first receive the current state as `appeared` events.
+/// A subscriber falling too far behind is disconnected and has to reconnect to catch up.
+#[get("/events/v1")]
+async fn events(request: HttpRequest) -> impl ActixResponder
+{
+	trace!("[http-responder][events] subscription received");
+
+	let config: Result<&web::Data<ResponderConfig>> = request.app_data().ok_or(Error::ResponderError).context("cannot retrieve app_data");
+	let config = match config
+	{
+		Ok(config) => config,
+		Err(err) => return problem(err),
+	};
+
+	let (initial, receiver) = config.events.subscribe();
+	let state = (VecDeque::from(initial), receiver, interval(EVENTS_KEEPALIVE), config.shutdown.clone());
+
+	let events = stream::unfold(state, |(mut backlog, mut receiver, mut keepalive, shutdown)| async move
+	{
+		let frame = match backlog.pop_front()
+		{
+			Some(event) => event_frame(&event),
+			None => tokio::select!
+			{
+				event = receiver.recv() => match event
+				{
+					Ok(event) => event_frame(&event),
+					Err(RecvError::Lagged(count)) =>
+					{
+						info!("[http-responder][events] subscriber missed {} events, disconnecting", count);
+						return None;
+					},
+					Err(RecvError::Closed) => return None,
+				},
+				_ = keepalive.tick() => Bytes::from_static(b": keep-alive\n\n"),
+				_ = shutdown.wait() => return None,
+			},
+		};
+
+		Some((Ok::<_,actix_web::Error>(frame), (backlog, receiver, keepalive, shutdown)))
+	});
+
+	HttpResponse::Ok()
+		.content_type("text/event-stream")
+		.insert_header(("Cache-Control", "no-cache"))
+		.streaming(events)
+}
+
 #[derive(Clone)]
 pub struct ResponderConfig
 {
 	command: String,
+	/// LXD server name, looked up on first use.
+	host: Arc<OnceCell<String>>,
+	suggested_ttl: usize,
+	events: Arc<Events>,
+	shutdown: Shutdown,
 }
 
 pub struct Responder
 {
 	config: ResponderConfig,
 	max_connections: usize,
-	https_bind: String,
-	tls_config: ServerConfig,
+	https_bind: Vec<String>,
+	http_bind: Vec<String>,
+	unix_bind: Vec<String>,
+	activated: Vec<Activated>,
+	tls_config: Option<ServerConfig>,
+	shutdown: Shutdown,
 }
 
 impl Responder
@@ -104,31 +351,186 @@ impl Responder
 
 		let config = self.config;
 
-		HttpServer::new(move ||
+		let mut server = HttpServer::new(move ||
 			{
 				App::new()
 					.app_data(web::Data::new(config.clone()))
 					.service(resolve)
+					.service(resolve_v2)
+					.service(metadata_instance)
+					.service(metadata_all)
+					.service(events)
+					.default_service(web::to(not_found))
 			})
-			.max_connections(self.max_connections)
-			.bind_rustls_0_23(self.https_bind, self.tls_config)?
-			.run()
-			.await?;
+			.max_connections(self.max_connections);
+
+		// sockets passed by the service manager are plain HTTP if named "http", HTTPS otherwise
+		for socket in self.activated
+		{
+			server = match socket
+			{
+				Activated::Unix(name, listener) =>
+				{
+					info!("[http-responder][run] listening for http on activated unix socket {}", name);
+					server.listen_uds(listener)?
+				},
+				Activated::Tcp(name, listener) if name == ACTIVATED_HTTP_NAME =>
+				{
+					info!("[http-responder][run] listening for http on activated socket {}", name);
+					server.listen(listener)?
+				},
+				Activated::Tcp(name, listener) =>
+				{
+					let tls_config = self.tls_config.clone().map(Result::Ok).unwrap_or_else(|| bail!("https listener without tls configuration")).context(Error::InvalidConfiguration)?;
+					info!("[http-responder][run] listening for https on activated socket {}", name);
+					server.listen_rustls_0_23(listener, tls_config)?
+				},
+			};
+		}
+
+		for bind in self.https_bind
+		{
+			let tls_config = self.tls_config.clone().map(Result::Ok).unwrap_or_else(|| bail!("https listener without tls configuration")).context(Error::InvalidConfiguration)?;
+			server = server.bind_rustls_0_23(&bind, tls_config).with_context(|| format!("cannot bind https listener to {}", bind))?;
+			info!("[http-responder][run] listening for https on {}", bind);
+		}
+
+		for bind in self.http_bind
+		{
+			server = server.bind(&bind).with_context(|| format!("cannot bind http listener to {}", bind))?;
+			info!("[http-responder][run] listening for http on {}", bind);
+		}
+
+		for bind in &self.unix_bind
+		{
+			remove_stale_socket(bind)?;
+			server = server.bind_uds(bind).with_context(|| format!("cannot bind http listener to unix socket {}", bind))?;
+			info!("[http-responder][run] listening for http on unix socket {}", bind);
+		}
+
+		// signals are handled by the shutdown handle shared with everything else
+		let server = server
+			.disable_signals()
+			.shutdown_timeout(self.shutdown.deadline().as_secs())
+			.run();
+		let handle = server.handle();
+		tokio::pin!(server);
+
+		let ready = crate::systemd::ready();
+		tokio::select!
+		{
+			result = &mut server => result?,
+			_ = self.shutdown.wait() =>
+			{
+				// graceful, allowing requests in flight to finish up to the shutdown timeout
+				// the server has to be polled for the stop command to be processed at all
+				let (_, result) = tokio::join!(handle.stop(true), &mut server);
+				result?;
+			},
+		}
+		drop(ready);
 
 		info!("[http-responder][run] webserver stopped");
 
+		// actix leaves socket files behind, only the ones bound here are ours to remove though
+		for bind in &self.unix_bind
+		{
+			if let Err(err) = remove_file(bind)
+			{
+				if err.kind() != std::io::ErrorKind::NotFound
+				{
+					warn!("[http-responder][run] cannot remove unix socket {}: {}", bind, err);
+				}
+			}
+		}
+
 		Ok(())
 	}
 }
 
-#[derive(Clone,Eq,PartialEq,Hash,Debug,Default)]
+fn load_tls_config(tls_chain: String, tls_key: String) -> Result<ServerConfig>
+{
+	let tls_config = ServerConfig::builder_with_provider(crypto::default_provider().into())
+		.with_safe_default_protocol_versions()
+		.context("crypto provider does not support safe protocols")?
+		.with_no_client_auth();
+
+	let tls_chain = &mut BufReader::new(File::open(tls_chain).unwrap());
+	let tls_key = &mut BufReader::new(File::open(tls_key).unwrap());
+
+	// convert files to key/cert objects
+	let tls_chain = certs(tls_chain)
+		.map(|res| Ok(res?))
+		.collect::<Result<_>>()
+		.context("cannot load certificate chain")?;
+
+	let tls_key = read_all(tls_key)
+		.map(|res| Ok(res?))
+		.collect::<Result<Vec<_>>>()
+		.context("cannot load private key")?
+		.into_iter()
+		.filter_map(|item| match item
+		{
+			Item::Pkcs1Key(key) => Some(PrivateKeyDer::Pkcs1(key)),
+			Item::Pkcs8Key(key) => Some(PrivateKeyDer::Pkcs8(key)),
+			Item::Sec1Key(key) => Some(PrivateKeyDer::Sec1(key)),
+			_ => None,
+		})
+		.next()
+		.map(Result::Ok)
+		.unwrap_or_else(|| bail!("no tls key found"))
+		.context(Error::InvalidConfiguration)?
+	;
+
+	let tls_config = tls_config.with_single_cert(tls_chain, tls_key)
+		.with_context(|| "tls configuration invalid")
+		.context(Error::InvalidConfiguration)?
+	;
+
+	Ok(tls_config)
+}
+
+fn remove_stale_socket(path: &str) -> Result<()>
+{
+	match metadata(path)
+	{
+		Ok(metadata) =>
+		{
+			if metadata.file_type().is_socket()
+			{
+				warn!("[http-responder] removing potentially stale socket {}", path);
+				remove_file(path)?;
+			}
+			else
+			{
+				Err(Error::ResponderError).with_context(|| format!("unix socket exists and is not a socket: {}", path))?;
+			}
+		},
+		Err(err) =>
+		{
+			if err.kind() != std::io::ErrorKind::NotFound
+			{
+				bail!(err);
+			}
+		},
+	}
+
+	Ok(())
+}
+
+#[derive(Clone,Debug,Default)]
 pub struct ResponderBuilder
 {
 	command: Option<String>,
 	max_connections: Option<usize>,
-	https_bind: Option<String>,
+	https_bind: Vec<String>,
+	http_bind: Vec<String>,
+	unix_bind: Vec<String>,
 	tls_key: Option<String>,
 	tls_chain: Option<String>,
+	events_interval: Option<Duration>,
+	suggested_ttl: Option<usize>,
+	shutdown: Option<Shutdown>,
 }
 
 impl ResponderBuilder
@@ -145,9 +547,24 @@ impl ResponderBuilder
 		self
 	}
 
+	/// Adds a listener for HTTPS, requires the TLS key and chain.
 	pub fn https_bind<S: AsRef<str>>(mut self, https_bind: S) -> Self
 	{
-		self.https_bind = Some(https_bind.as_ref().into());
+		self.https_bind.push(https_bind.as_ref().into());
+		self
+	}
+
+	/// Adds a listener for plain HTTP, meant for use behind a reverse proxy terminating TLS.
+	pub fn http_bind<S: AsRef<str>>(mut self, http_bind: S) -> Self
+	{
+		self.http_bind.push(http_bind.as_ref().into());
+		self
+	}
+
+	/// Adds a listener for plain HTTP on a unix domain socket.
+	pub fn unix_bind<S: AsRef<str>>(mut self, unix_bind: S) -> Self
+	{
+		self.unix_bind.push(unix_bind.as_ref().into());
 		self
 	}
 
@@ -163,50 +580,54 @@ impl ResponderBuilder
 		self
 	}
 
+	/// Interval in which the local instances are checked for changes while there are event subscribers.
+	pub fn events_interval(mut self, events_interval: Duration) -> Self
+	{
+		self.events_interval = Some(events_interval);
+		self
+	}
+
+	/// TTL suggested to querying backends in `/resolve/v2` responses.
+	pub fn suggested_ttl(mut self, suggested_ttl: usize) -> Self
+	{
+		self.suggested_ttl = Some(suggested_ttl);
+		self
+	}
+
+	pub fn shutdown(mut self, shutdown: Shutdown) -> Self
+	{
+		self.shutdown = Some(shutdown);
+		self
+	}
+
 	pub async fn run(self) -> Result<()>
 	{
 		let command = self.command.map(Result::Ok).unwrap_or_else(|| bail!("no command provided")).context(Error::InvalidConfiguration)?;
 		let max_connections = self.max_connections.map(Result::Ok).unwrap_or_else(|| bail!("no max_connections provided")).context(Error::InvalidConfiguration)?;
-		let https_bind = self.https_bind.map(Result::Ok).unwrap_or_else(|| bail!("no https_bind provided")).context(Error::InvalidConfiguration)?;
-		let tls_key = self.tls_key.map(Result::Ok).unwrap_or_else(|| bail!("no tls_key provided")).context(Error::InvalidConfiguration)?;
-		let tls_chain = self.tls_chain.map(Result::Ok).unwrap_or_else(|| bail!("no tls_chain provided")).context(Error::InvalidConfiguration)?;
-
-		let tls_config = ServerConfig::builder_with_provider(crypto::default_provider().into())
-			.with_safe_default_protocol_versions()
-			.context("crypto provider does not support safe protocols")?
-			.with_no_client_auth();
-
-		let tls_chain = &mut BufReader::new(File::open(tls_chain).unwrap());
-		let tls_key = &mut BufReader::new(File::open(tls_key).unwrap());
-
-		// convert files to key/cert objects
-		let tls_chain = certs(tls_chain)
-			.map(|res| Ok(res?))
-			.collect::<Result<_>>()
-			.context("cannot load certificate chain")?;
-
-		let tls_key = read_all(tls_key)
-			.map(|res| Ok(res?))
-			.collect::<Result<Vec<_>>>()
-			.context("cannot load private key")?
-			.into_iter()
-			.filter_map(|item| match item
-			{
-				Item::Pkcs1Key(key) => Some(PrivateKeyDer::Pkcs1(key)),
-				Item::Pkcs8Key(key) => Some(PrivateKeyDer::Pkcs8(key)),
-				Item::Sec1Key(key) => Some(PrivateKeyDer::Sec1(key)),
-				_ => None,
-			})
-			.next()
-			.map(Result::Ok)
-			.unwrap_or_else(|| bail!("no tls key found"))
-			.context(Error::InvalidConfiguration)?
-		;
+		let events_interval = self.events_interval.unwrap_or(Duration::from_secs(5));
+		let suggested_ttl = self.suggested_ttl.unwrap_or(128);
+		let shutdown = self.shutdown.unwrap_or_default();
+		let mut https_bind = self.https_bind;
+		let http_bind = self.http_bind;
+		let unix_bind = self.unix_bind;
+		let activated = crate::systemd::take_all();
+		if https_bind.is_empty() && http_bind.is_empty() && unix_bind.is_empty() && activated.is_empty()
+		{
+			https_bind.push(DEFAULT_HTTPS_BIND.to_string());
+		}
 
-		let tls_config = tls_config.with_single_cert(tls_chain, tls_key)
-			.with_context(|| "tls configuration invalid")
-			.context(Error::InvalidConfiguration)?
-		;
+		let activated_https = activated.iter().any(|socket| matches!(socket, Activated::Tcp(name, _) if name != ACTIVATED_HTTP_NAME));
+		let tls_config = if https_bind.is_empty() && !activated_https
+		{
+			None
+		}
+		else
+		{
+			let tls_key = self.tls_key.map(Result::Ok).unwrap_or_else(|| bail!("no tls_key provided")).context(Error::InvalidConfiguration)?;
+			let tls_chain = self.tls_chain.map(Result::Ok).unwrap_or_else(|| bail!("no tls_chain provided")).context(Error::InvalidConfiguration)?;
+
+			Some(load_tls_config(tls_chain, tls_key)?)
+		};
 
 		info!("[http-responder][run] certificates parsed");
 
@@ -214,13 +635,34 @@ impl ResponderBuilder
 		{
 			config: ResponderConfig
 			{
+				events: Arc::new(Events::start(command.clone(), events_interval)),
 				command,
+				host: Default::default(),
+				suggested_ttl,
+				shutdown: shutdown.clone(),
 			},
 			max_connections,
 			https_bind,
+			http_bind,
+			unix_bind,
+			activated,
 			tls_config,
+			shutdown,
 		}.run().await?;
 		Ok(())
 	}
 }
 
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn resolve_v2_suggests_a_ttl()
+	{
+		let response = serde_json::to_value(resolve_v2_response("host".to_string(), 42, None)).unwrap();
+
+		assert_eq!(response, serde_json::json!({ "V2": { "host": "host", "ttl": 42, "result": "NoMatch", }, }));
+	}
+}
@@ -8,10 +8,41 @@
 use crate::
 {
 	error::*,
+	http::
+	{
+		DiscoveryConfig,
+		HealthConfig,
+		QueryStrategy,
+		discovery::Remotes,
+	},
 	pdns::
 	{
 		TtlConfig,
+		Zone,
+		ZoneConfig,
 	},
+	pdns_io::
+	{
+		RemoteQuery,
+		SharedBackend,
+	},
+	coalesce::
+	{
+		CoalescingQuery,
+		Inflight,
+	},
+	cache::
+	{
+		AnswerCache,
+		CacheConfig,
+		CachedQuery,
+	},
+	soa::
+	{
+		Soa,
+		SoaConfig,
+	},
+	shutdown::Shutdown,
 };
 
 use ::
@@ -40,11 +71,20 @@ use ::
 pub struct Unix
 {
 	remote: Vec<String>,
+	discovery_config: DiscoveryConfig,
+	strategy: QueryStrategy,
+	health_config: HealthConfig,
 	domain: String,
 	hostmaster: String,
 	unixpath: String,
 	ttl_config: TtlConfig,
 	unix_workers: usize,
+	pipeline_depth: usize,
+	coalesce: bool,
+	cache_config: CacheConfig,
+	soa_config: SoaConfig,
+	zone: Zone,
+	shutdown: Shutdown,
 }
 
 impl Unix
@@ -58,50 +98,101 @@ impl Unix
 	{
 		debug!("[unix] started");
 
-		match metadata(&self.unixpath).await
+		// a socket passed by the service manager is owned by it, including the socket file
+		let activated = crate::systemd::take_unix_listener();
+		let owns_socket = activated.is_none();
+		let listener = match activated
 		{
-			Ok(metadata) =>
-			{
-				if metadata.file_type().is_socket()
-				{
-					warn!("[unix] removing potentially stale socket");
-					remove_file(&self.unixpath).await?;
-				}
-				else
-				{
-					Err(Error::UnixServerError).with_context(|| format!("unix socket exists and is not a file: {}", self.unixpath))?;
-				}
-			},
-			Err(err) =>
+			Some(listener) => UnixListener::from_std(listener)?,
+			None =>
 			{
-				if err.kind() != std::io::ErrorKind::NotFound
+				match metadata(&self.unixpath).await
 				{
-					bail!(err);
+					Ok(metadata) =>
+					{
+						if metadata.file_type().is_socket()
+						{
+							warn!("[unix] removing potentially stale socket");
+							remove_file(&self.unixpath).await?;
+						}
+						else
+						{
+							Err(Error::UnixServerError).with_context(|| format!("unix socket exists and is not a file: {}", self.unixpath))?;
+						}
+					},
+					Err(err) =>
+					{
+						if err.kind() != std::io::ErrorKind::NotFound
+						{
+							bail!(err);
+						}
+					},
 				}
-			},
-		}
 
-		let listener = UnixListener::bind(&self.unixpath)?;
+				UnixListener::bind(&self.unixpath)?
+			},
+		};
 		info!("[http-unix] unix socket opened");
 
-		UnixListenerStream::new(listener).map(|res| res.context(Error::UnixServerError)).try_for_each_concurrent(self.unix_workers, |stream|
+		let inflight = Inflight::default();
+		let cache = self.cache_config.enabled().then(|| AnswerCache::new(&self.cache_config, &self.ttl_config));
+		let soa = Soa::new(self.soa_config.clone());
+		self.zone.records().reload_on_signal()?;
+		let remotes = Remotes::start(self.remote.clone(), self.discovery_config.clone()).await?;
+		// a single backend for all connections, sharing the connection pool and health of the remotes
+		let backend = SharedBackend::new(super::query::RemoteQuery::new(remotes, self.strategy, self.health_config.clone()).await?);
+
+		let ready = crate::systemd::ready();
+
+		// stop accepting connections on shutdown, the open ones stop reading requests themselves
+		let serve = UnixListenerStream::new(listener).take_until(self.shutdown.wait()).map(|res| res.context(Error::UnixServerError)).try_for_each_concurrent(self.unix_workers, |stream|
 		{
 			let me = &self;
+			let inflight = &inflight;
+			let cache = &cache;
+			let soa = &soa;
+			let backend = backend.clone();
 			async move
 			{
 				debug!("[unix] connection opened");
 
-				let backend = super::query::RemoteQuery::new(self.remote.clone()).await?;
+				let backend: Box<dyn RemoteQuery+Send+Sync> = if me.coalesce
+				{
+					Box::new(CoalescingQuery::new(backend, inflight.clone()))
+				}
+				else
+				{
+					Box::new(backend)
+				};
+				// only misses reach the coalescing, a hit needs no merging
+				let backend: Box<dyn RemoteQuery+Send+Sync> = match cache
+				{
+					Some(cache) => Box::new(CachedQuery::new(backend, cache.clone())),
+					None => backend,
+				};
 				let (read, write) = stream.into_split();
-				let handler = crate::pdns_io::PdnsStreamHandler::new(&me.domain, &me.ttl_config, &me.hostmaster, backend, read, write).await?;
+				let handler = crate::pdns_io::PdnsStreamHandler::new(&me.domain, &me.ttl_config, &me.hostmaster, backend, read, write).await?
+					.pipeline_depth(me.pipeline_depth)
+					.soa(soa.clone())
+					.zone(me.zone.clone())
+					.shutdown(me.shutdown.clone());
 				handler.run().await?;
 
 				debug!("[unix] connection closed");
 				Ok(())
 			}
-		}).await?;
+		});
+
+		if self.shutdown.drain(serve).await.transpose()?.is_none()
+		{
+			warn!("[http-unix] connections cut off by shutdown");
+		}
 
-		remove_file(&self.unixpath).await?;
+		drop(ready);
+		if owns_socket
+		{
+			remove_file(&self.unixpath).await?;
+		}
 		debug!("[http-unix] stopped");
 
 		Ok(())
@@ -113,15 +204,24 @@ impl Unix
 	}
 }
 
-#[derive(Clone,Eq,PartialEq,Hash,Debug,Default)]
+#[derive(Clone,Debug,Default)]
 pub struct UnixBuilder
 {
 	remote: Option<Vec<String>>,
+	discovery_config: Option<DiscoveryConfig>,
+	strategy: Option<QueryStrategy>,
+	health_config: Option<HealthConfig>,
 	domain: Option<String>,
 	hostmaster: Option<String>,
 	unixpath: Option<String>,
 	ttl_config: Option<TtlConfig>,
 	unix_workers: Option<usize>,
+	pipeline_depth: Option<usize>,
+	coalesce: Option<bool>,
+	cache_config: Option<CacheConfig>,
+	soa_config: Option<SoaConfig>,
+	zone_config: Option<ZoneConfig>,
+	shutdown: Option<Shutdown>,
 }
 
 impl UnixBuilder
@@ -132,6 +232,24 @@ impl UnixBuilder
 		self
 	}
 
+	pub fn discovery_config(mut self, discovery_config: DiscoveryConfig) -> Self
+	{
+		self.discovery_config = Some(discovery_config);
+		self
+	}
+
+	pub fn strategy(mut self, strategy: QueryStrategy) -> Self
+	{
+		self.strategy = Some(strategy);
+		self
+	}
+
+	pub fn health_config(mut self, health_config: HealthConfig) -> Self
+	{
+		self.health_config = Some(health_config);
+		self
+	}
+
 	pub fn domain<S: AsRef<str>>(mut self, domain: S) -> Self
 	{
 		self.domain = Some(domain.as_ref().into());
@@ -162,24 +280,78 @@ impl UnixBuilder
 		self
 	}
 
+	pub fn pipeline_depth(mut self, pipeline_depth: usize) -> Self
+	{
+		self.pipeline_depth = Some(pipeline_depth);
+		self
+	}
+
+	pub fn coalesce(mut self, coalesce: bool) -> Self
+	{
+		self.coalesce = Some(coalesce);
+		self
+	}
+
+	pub fn soa_config(mut self, soa_config: SoaConfig) -> Self
+	{
+		self.soa_config = Some(soa_config);
+		self
+	}
+
+	pub fn zone_config(mut self, zone_config: ZoneConfig) -> Self
+	{
+		self.zone_config = Some(zone_config);
+		self
+	}
+
+	pub fn cache_config(mut self, cache_config: CacheConfig) -> Self
+	{
+		self.cache_config = Some(cache_config);
+		self
+	}
+
+	pub fn shutdown(mut self, shutdown: Shutdown) -> Self
+	{
+		self.shutdown = Some(shutdown);
+		self
+	}
+
 	pub async fn run(self) -> Result<()>
 	{
-		let remote = self.remote.map(Result::Ok).unwrap_or_else(|| bail!("no remote provided")).context(Error::InvalidConfiguration)?;
+		let remote = self.remote.unwrap_or_default();
+		let discovery_config = self.discovery_config.unwrap_or_default();
 		let domain = self.domain.map(Result::Ok).unwrap_or_else(|| bail!("no domain provided")).context(Error::InvalidConfiguration)?;
 		let hostmaster = self.hostmaster.map(Result::Ok).unwrap_or_else(|| bail!("no hostmaster provided")).context(Error::InvalidConfiguration)?;
 		let unixpath = self.unixpath.map(Result::Ok).unwrap_or_else(|| bail!("no unixpath provided")).context(Error::InvalidConfiguration)?;
 		let ttl_config = self.ttl_config.unwrap_or_default();
+		let strategy = self.strategy.unwrap_or_default();
+		let health_config = self.health_config.unwrap_or_default();
 		let unix_workers = self.unix_workers.unwrap_or(0);
+		let pipeline_depth = self.pipeline_depth.unwrap_or(1);
+		let coalesce = self.coalesce.unwrap_or(false);
+		let cache_config = self.cache_config.unwrap_or_default();
+		let soa_config = self.soa_config.unwrap_or_default();
+		let zone = Zone::new(&domain, &self.zone_config.unwrap_or_default())?;
+		let shutdown = self.shutdown.unwrap_or_default();
 
 		info!("[http-unix][run] parameters parsed");
 		Unix
 		{
 			remote,
+			discovery_config,
+			strategy,
+			health_config,
 			domain,
 			hostmaster,
 			ttl_config,
 			unixpath,
 			unix_workers,
+			pipeline_depth,
+			coalesce,
+			cache_config,
+			soa_config,
+			zone,
+			shutdown,
 		}.run().await?;
 		Ok(())
 	}
@@ -10,7 +10,16 @@
 pub mod error;
 pub mod lxd;
 pub mod pdns;
+pub mod soa;
+pub mod records;
+#[cfg(any(feature = "amqp", feature = "http"))]
 mod pdns_io;
+#[cfg(any(feature = "amqp", feature = "http"))]
+mod coalesce;
+#[cfg(any(feature = "amqp", feature = "http"))]
+pub mod cache;
+pub mod systemd;
+pub mod shutdown;
 
 #[cfg(feature = "amqp")]
 pub mod amqp;
@@ -13,6 +13,7 @@ use ::
 	serde::
 	{
 		Deserialize,
+		Serialize,
 	},
 	lazy_regex::regex_is_match,
 	tokio::
@@ -25,8 +26,16 @@ use ::
 	std::
 	{
 		str::FromStr,
-		collections::HashMap,
-		net::Ipv6Addr,
+		collections::
+		{
+			BTreeMap,
+			HashMap,
+		},
+		net::
+		{
+			IpAddr,
+			Ipv6Addr,
+		},
 		process::Stdio,
 		time::
 		{
@@ -61,7 +70,7 @@ pub struct MemoryState
 	usage_peak: i128,
 }
 
-#[derive(Deserialize,Clone,Eq,PartialEq,Hash,Debug)]
+#[derive(Serialize,Deserialize,Clone,Copy,Eq,PartialEq,Ord,PartialOrd,Hash,Debug)]
 pub enum AddressFamily
 {
 	#[serde(rename = "inet6")]
@@ -183,26 +192,168 @@ impl FromStr for ContainerName
 	}
 }
 
-/// Queries the local LXD instance.
-/// The local instance is queried by executing an `lxc query` command with *sudo*, where `lxc` is the passed command.
-///
-/// Values returned are either `Err` if querying failed, `Ok(None)` if the instance was not found locally, or `Ok(vec![])` if the instance was found.
-/// The last case includes instances without addresses assigned.
-pub async fn local_query(command: &String, name: &ContainerName) -> Result<Option<Vec<Ipv6Addr>>>
+#[derive(Getters,Deserialize,Clone,Eq,PartialEq,Hash,Debug)]
+pub struct ServerEnvironment
 {
-	trace!("[local_query][{}] starting query", name.as_ref());
+	#[get = "pub"]
+	server_name: String,
+}
 
-	let instant = Instant::now();
+#[derive(Getters,Deserialize,Clone,Eq,PartialEq,Hash,Debug)]
+pub struct Server
+{
+	#[get = "pub"]
+	environment: ServerEnvironment,
+}
 
-	// maybe switch to reqwest some day?
+/// How an instance matched the name it was looked up by.
+#[derive(Serialize,Deserialize,Clone,Copy,Eq,PartialEq,Ord,PartialOrd,Hash,Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchKind
+{
+	/// The instance name equals the queried name.
+	Exact,
+	/// The instance name is the queried name followed by a numeric suffix.
+	Prefix,
+	/// The instance lists the queried name in its [`ALIAS_KEY`] config key.
+	Alias,
+}
+
+/// Address of an instance along with the interface it is assigned to.
+#[derive(Getters,Clone,Eq,PartialEq,Hash,Debug)]
+pub struct InstanceAddress
+{
+	#[get = "pub"]
+	interface: String,
+	#[get = "pub"]
+	address: IpAddr,
+	#[get = "pub"]
+	family: AddressFamily,
+}
+
+/// Running local instance found for a name.
+#[derive(Getters,Clone,Eq,PartialEq,Hash,Debug)]
+pub struct InstanceMatch
+{
+	#[get = "pub"]
+	name: String,
+	#[get = "pub"]
+	kind: MatchKind,
+	#[get = "pub"]
+	status: String,
+	#[get = "pub"]
+	addresses: Vec<InstanceAddress>,
+}
 
-	trace!("[local_query][{}] getting instance list", name.as_ref());
-	// first get the list of instances
+#[derive(Deserialize,Clone,Debug)]
+struct NetOverview
+{
+	addresses: Vec<Address>,
+}
+
+#[derive(Deserialize,Clone,Debug)]
+struct StateOverview
+{
+	status: String,
+	network: Option<HashMap<String,NetOverview>>,
+}
+
+/// Instance as listed with recursion, only the parts needed for a snapshot.
+#[derive(Deserialize,Clone,Debug)]
+struct InstanceOverview
+{
+	name: String,
+	state: Option<StateOverview>,
+}
+
+/// Instance config key listing further names, separated by commas or whitespace, an instance is found by.
+pub const ALIAS_KEY: &str = "user.lxddns.alias";
+
+/// Prefix of the instance config keys published as metadata.
+pub const METADATA_PREFIX: &str = "user.lxddns.";
+
+/// Metadata of a running instance, i.e. its config keys starting with [`METADATA_PREFIX`], the prefix removed.
+#[derive(Getters,Serialize,Deserialize,Clone,Eq,PartialEq,Hash,Debug)]
+pub struct InstanceMetadata
+{
+	#[get = "pub"]
+	instance: String,
+	#[get = "pub"]
+	metadata: BTreeMap<String,String>,
+}
+
+/// Instance as listed with recursion, only the parts needed to match it by name.
+#[derive(Deserialize,Clone,Debug)]
+struct InstanceConfigOverview
+{
+	name: String,
+	#[serde(default)]
+	status: String,
+	#[serde(default)]
+	config: HashMap<String,String>,
+}
+
+impl InstanceConfigOverview
+{
+	fn aliases(&self) -> impl Iterator<Item = &str>
+	{
+		self.config.get(ALIAS_KEY).into_iter()
+			.flat_map(|aliases| aliases.split(|ch: char| ch == ',' || ch.is_whitespace()))
+			.filter(|alias| !alias.is_empty())
+	}
+}
+
+/// Instance a name refers to, if any.
+///
+/// This assumes that all matches are either exact or there is only one local instance matching.
+/// In all cases there will only be one instance, exact matches winning over prefix matches winning over aliases.
+fn find_instance<'a>(name: &ContainerName, instances: &'a [InstanceConfigOverview]) -> Option<(MatchKind, &'a InstanceConfigOverview)>
+{
+	trace!("[find_instance][{}] filtering instance list", name.as_ref());
+	instances.iter()
+		.filter_map(|instance|
+		{
+			if name.as_ref().eq(&instance.name)
+			{
+				trace!("[find_instance][{}] exact match", name.as_ref());
+				Some((MatchKind::Exact,instance))
+			}
+			else if instance.name.strip_prefix(name.as_ref()).map(|remainder| !remainder.contains(|ch: char| !ch.is_ascii_digit())).unwrap_or(false)
+			{
+				trace!("[find_instance][{}] prefix match: {}", name.as_ref(), instance.name);
+				Some((MatchKind::Prefix,instance))
+			}
+			else if instance.aliases().any(|alias| alias.eq(name.as_ref()))
+			{
+				trace!("[find_instance][{}] alias match: {}", name.as_ref(), instance.name);
+				Some((MatchKind::Alias,instance))
+			}
+			else
+			{
+				trace!("[find_instance][{}] no match: {}", name.as_ref(), instance.name);
+				None
+			}
+		})
+		.min_by_key(|(kind,_)| *kind)
+}
+
+/// Global IPv6 addresses among the given ones, those are the ones published.
+fn published_addresses<'a, I: IntoIterator<Item = &'a Address>>(addresses: I) -> Vec<Ipv6Addr>
+{
+	addresses.into_iter()
+		.filter(|address| address.scope() == &AddressScope::Global && address.family() == &AddressFamily::Inet6)
+		.filter_map(|address| address.address().parse::<Ipv6Addr>().ok())
+		.collect()
+}
+
+/// Runs `lxc query` on the given API path via *sudo*, returning the raw stdout.
+async fn query_path(command: &String, path: &str) -> Result<Vec<u8>>
+{
 	let output = Command::new("sudo")
 		.arg(command)
 		.arg("query")
 		.arg("--")
-		.arg("/1.0/instances")
+		.arg(path)
 		.stdin(Stdio::null())
 		.stdout(Stdio::piped())
 		.stderr(Stdio::piped())
@@ -210,84 +361,110 @@ pub async fn local_query(command: &String, name: &ContainerName) -> Result<Optio
 		.await
 		.context(Error::LocalExecution(None))?;
 
-	debug!("[local_query][{}] instance listing ran for {:.3}s", name.as_ref(), instant.elapsed().as_secs_f64());
-
-	trace!("[local_query][{}] validating instance list command output", name.as_ref());
 	if !output.status.success()
 	{
 		let err = String::from_utf8_lossy(&output.stderr);
 		bail!(Error::LocalExecution(Some(err.to_string())))
 	}
 
-	trace!("[local_query][{}] parsing instance list", name.as_ref());
-	let instances: Vec<String> = serde_json::from_slice(&output.stdout).context(Error::LocalOutput)?;
+	Ok(output.stdout)
+}
 
-	trace!("[local_query][{}] validating and filtering instance list", name.as_ref());
-	let instances = instances.into_iter()
+/// Retrieves the server name of the local LXD instance.
+pub async fn server_name(command: &String) -> Result<String>
+{
+	let output = query_path(command, "/1.0").await?;
+	let server: Server = serde_json::from_slice(&output).context(Error::LocalOutput)?;
+
+	Ok(server.environment.server_name)
+}
+
+/// Counts the instances on the local LXD instance, regardless of their state.
+pub async fn instance_count(command: &String) -> Result<usize>
+{
+	let output = query_path(command, "/1.0/instances").await?;
+	let instances: Vec<String> = serde_json::from_slice(&output).context(Error::LocalOutput)?;
+
+	Ok(instances.len())
+}
+
+/// Published addresses of all running instances on the local LXD instance, keyed by instance name.
+///
+/// Running instances without addresses are included with an empty list.
+pub async fn local_snapshot(command: &String) -> Result<BTreeMap<String,Vec<Ipv6Addr>>>
+{
+	let instant = Instant::now();
+	let output = query_path(command, "/1.0/instances?recursion=2").await?;
+	debug!("[local_snapshot] listing ran for {:.3}s", instant.elapsed().as_secs_f64());
+
+	let instances: Vec<InstanceOverview> = serde_json::from_slice(&output).context(Error::LocalOutput)?;
+
+	Ok(instances.into_iter()
 		.filter_map(|instance|
 		{
-			let instance = match instance.strip_prefix("/1.0/instances/")
-			{
-				Some(instance) => instance,
-				None => return None,
-			};
+			let state = instance.state.filter(|state| state.status == "Running")?;
+			let addresses = state.network.iter()
+				.flat_map(|network| network.values())
+				.flat_map(|net| net.addresses.iter());
 
-			if name.as_ref().eq(instance)
-			{
-				trace!("[local_query][{}] exact match", name.as_ref());
-				Some((true,instance.to_string()))
-			}
-			else
-			{
-				if let Some(remainder) = instance.strip_prefix(name.as_ref())
-				{
-					if !remainder.contains(|ch: char| !ch.is_ascii_digit())
-					{
-						trace!("[local_query][{}] prefix match: {}", name.as_ref(), instance);
-						Some((false,instance.to_string()))
-					}
-					else
-					{
-						trace!("[local_query][{}] prefix does not match: {}", name.as_ref(), instance);
-						None
-					}
-				}
-				else
-				{
-					trace!("[local_query][{}] no match", name.as_ref());
-					None
-				}
-			}
+			Some((instance.name, published_addresses(addresses)))
 		})
-		.collect::<Vec<_>>()
-	;
+		.collect())
+}
 
-	// this assumes that all matches are either exact or there is only one local instance matching
-	// in all cases there will only be one query
-	let instance = if let Some((_,instance)) = instances.iter().find(|(exact,_)| *exact)
-	{
-		Some(instance)
-	}
-	else
-	{
-		instances.get(0).map(|(_,instance)| instance)
-	};
+/// Queries the local LXD instance.
+/// The local instance is queried by executing an `lxc query` command with *sudo*, where `lxc` is the passed command.
+///
+/// Values returned are either `Err` if querying failed, `Ok(None)` if the instance was not found locally, or `Ok(vec![])` if the instance was found.
+/// The last case includes instances without addresses assigned.
+pub async fn local_query(command: &String, name: &ContainerName) -> Result<Option<Vec<Ipv6Addr>>>
+{
+	let found = local_lookup(command, name).await?;
+
+	Ok(found.map(|found| found.addresses.into_iter()
+		.filter_map(|address| match address.address
+		{
+			IpAddr::V6(address) => Some(address),
+			IpAddr::V4(_) => None,
+		})
+		.collect()))
+}
+
+/// Queries the local LXD instance like [`local_query`], but retains the details of the match.
+///
+/// Addresses of both families are returned as long as they are of global scope.
+pub async fn local_lookup(command: &String, name: &ContainerName) -> Result<Option<InstanceMatch>>
+{
+	trace!("[local_lookup][{}] starting query", name.as_ref());
+
+	let instant = Instant::now();
+
+	// maybe switch to reqwest some day?
+
+	trace!("[local_lookup][{}] getting instance list", name.as_ref());
+	// first get the list of instances, along with their config for the aliases
+	let output = query_path(command, "/1.0/instances?recursion=1").await?;
+
+	debug!("[local_lookup][{}] instance listing ran for {:.3}s", name.as_ref(), instant.elapsed().as_secs_f64());
 
-	let instance = match instance
+	trace!("[local_lookup][{}] parsing instance list", name.as_ref());
+	let instances: Vec<InstanceConfigOverview> = serde_json::from_slice(&output).context(Error::LocalOutput)?;
+
+	let (kind, instance) = match find_instance(name, &instances)
 	{
-		Some(instance) =>
+		Some((kind, instance)) =>
 		{
-			debug!("[local_query][{}] match: {}", name.as_ref(), instance);
-			instance
+			debug!("[local_lookup][{}] match: {}", name.as_ref(), instance.name);
+			(kind, &instance.name)
 		}
 		None =>
 		{
-			debug!("[local_query][{}] not found", name.as_ref());
+			debug!("[local_lookup][{}] not found", name.as_ref());
 			return Ok(None);
 		}
 	};
 
-	trace!("[local_query][{}] querying state", name.as_ref());
+	trace!("[local_lookup][{}] querying state", name.as_ref());
 	let output = Command::new("sudo")
 		.arg(command)
 		.arg("query")
@@ -300,25 +477,25 @@ pub async fn local_query(command: &String, name: &ContainerName) -> Result<Optio
 		.await
 		.context(Error::LocalExecution(None))?;
 
-	debug!("[local_query][{}] query ran for {:.3}s", name.as_ref(), instant.elapsed().as_secs_f64());
+	debug!("[local_lookup][{}] query ran for {:.3}s", name.as_ref(), instant.elapsed().as_secs_f64());
 
 	if !output.status.success()
 	{
 		if &output.stderr == b"Error: not found\n"
 		{
-			trace!("[local_query][{}] \"not found\"", name.as_ref());
+			trace!("[local_lookup][{}] \"not found\"", name.as_ref());
 			return Ok(None);
 		}
 		let err = String::from_utf8_lossy(&output.stderr);
 		bail!(Error::LocalExecution(Some(err.to_string())))
 	}
 
-	trace!("[local_query][{}] got response", name.as_ref());
+	trace!("[local_lookup][{}] got response", name.as_ref());
 	let state: ContainerState = serde_json::from_slice(&output.stdout).context(Error::LocalOutput)?;
 
 	if state.status() != "Running"
 	{
-		trace!("[local_query][{}] not running", name.as_ref());
+		trace!("[local_lookup][{}] not running", name.as_ref());
 		return Ok(None);
 	}
 
@@ -327,20 +504,57 @@ pub async fn local_query(command: &String, name: &ContainerName) -> Result<Optio
 		Some(network) => network,
 		None =>
 		{
-			debug!("[local_query][{}] network is null despite container running, returning None", name.as_ref());
+			debug!("[local_lookup][{}] network is null despite container running, returning None", name.as_ref());
 			return Ok(None);
 		},
 	};
 
-	let addresses = network
-		.values()
-		.flat_map(|net| net.addresses().iter())
-		.filter(|address| address.scope() == &AddressScope::Global && address.family() == &AddressFamily::Inet6)
-		.filter_map(|address| address.address().parse::<Ipv6Addr>().ok())
+	let addresses = network.iter()
+		.flat_map(|(interface, net)| net.addresses().iter().map(move |address| (interface, address)))
+		.filter(|(_, address)| address.scope() == &AddressScope::Global)
+		.filter_map(|(interface, address)| Some(InstanceAddress
+		{
+			interface: interface.clone(),
+			address: address.address().parse().ok()?,
+			family: *address.family(),
+		}))
 		.collect::<Vec<_>>();
 
-	trace!("[local_query][{}] result: {:?}", name.as_ref(), addresses);
+	trace!("[local_lookup][{}] result: {:?}", name.as_ref(), addresses);
 
-	Ok(Some(addresses))
+	Ok(Some(InstanceMatch
+	{
+		name: instance.clone(),
+		kind,
+		status: state.status().clone(),
+		addresses,
+	}))
 }
 
+/// Retrieves the metadata of the running instance a name refers to, or of all running instances if no name is given.
+///
+/// Instances are matched like in [`local_lookup`].
+pub async fn local_metadata(command: &String, name: Option<&ContainerName>) -> Result<Vec<InstanceMetadata>>
+{
+	let instant = Instant::now();
+	let output = query_path(command, "/1.0/instances?recursion=1").await?;
+	debug!("[local_metadata] listing ran for {:.3}s", instant.elapsed().as_secs_f64());
+
+	let instances: Vec<InstanceConfigOverview> = serde_json::from_slice(&output).context(Error::LocalOutput)?;
+	let instances = match name
+	{
+		Some(name) => find_instance(name, &instances).map(|(_, instance)| instance).into_iter().collect(),
+		None => instances.iter().collect::<Vec<_>>(),
+	};
+
+	Ok(instances.into_iter()
+		.filter(|instance| instance.status == "Running")
+		.map(|instance| InstanceMetadata
+		{
+			instance: instance.name.clone(),
+			metadata: instance.config.iter()
+				.filter_map(|(key, value)| Some((key.strip_prefix(METADATA_PREFIX)?.to_string(), value.clone())))
+				.collect(),
+		})
+		.collect())
+}
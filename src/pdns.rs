@@ -10,7 +10,17 @@ use crate::error::*;
 
 use crate::
 {
-	lxd::ContainerName,
+	lxd::
+	{
+		ContainerName,
+		InstanceMetadata,
+	},
+	records::
+	{
+		self,
+		Records,
+	},
+	soa::Soa,
 };
 
 use ::
@@ -28,10 +38,14 @@ use ::
 	},
 	std::
 	{
+		collections::BTreeSet,
 		net::
 		{
+			IpAddr,
 			Ipv6Addr,
 		},
+		path::PathBuf,
+		str::FromStr,
 	},
 };
 
@@ -47,12 +61,48 @@ pub enum LookupType
 	{
 		response: DumbResponse,
 	},
+	/// Name which is a static alias of an instance, answered with a CNAME.
+	Alias
+	{
+		target: ContainerName,
+	},
+	/// ACME challenge of an instance whose answer depends on the metadata of the instance, see [`Zone::acme`].
+	Acme
+	{
+		container: ContainerName,
+	},
+	/// SRV records of a service on one instance, or on all of them if there is none, see [`Zone::srv`].
+	Srv
+	{
+		service: String,
+		protocol: String,
+		container: Option<ContainerName>,
+	},
+	/// TXT records of an instance, taken from its metadata, see [`txt_records`].
+	Txt
+	{
+		container: ContainerName,
+	},
 }
 
 #[derive(Clone,Eq,PartialEq,Hash,Debug)]
 pub enum SmartResponse
 {
 	Aaaa,
+	/// Type without records on instances, *NODATA* if the instance exists, *NXDOMAIN* otherwise.
+	///
+	/// The pipe protocol has no *NODATA* of its own, so the AAAA records of the instance are sent for PowerDNS to tell the name exists.
+	///
+	/// ```
+	/// # use lxddns::pdns::*;
+	/// # use serde_json::json;
+	/// let soa = ResponseEntry::soa("example.com", &Default::default(), "hostmaster.example.com", &Default::default());
+	/// let response = |addresses| serde_json::to_value(SmartResponse::Nodata.response("container.example.com", &Default::default(), &soa, addresses)).unwrap();
+	///
+	/// assert_eq!(response(Some(vec!["2001:db8::1".parse().unwrap()]))["result"][0]["qtype"], json!("AAAA"));
+	/// assert_eq!(response(None), json!({ "result": [], }));
+	/// ```
+	Nodata,
 }
 
 impl SmartResponse
@@ -82,10 +132,51 @@ impl SmartResponse
 					DumbResponse::Nxdomain.response(qname, ttl_config, soa)
 				}
 			},
+			SmartResponse::Nodata =>
+			{
+				if let Some(addresses) = addresses
+				{
+					// an empty answer is NXDOMAIN, PowerDNS only knows the name exists if it has records of another type
+					// those are not of the queried type, so PowerDNS drops them and answers NODATA
+					debug!("[smartresponse][{}][nodata] instance exists, sending its addresses for nodata", qname.as_ref());
+					addresses.into_iter()
+						.map(|addr| ResponseEntry::aaaa(qname.as_ref(), ttl_config, addr))
+						.collect::<Vec<_>>()
+						.into()
+				}
+				else
+				{
+					trace!("[smartresponse][{}][nodata] sending nxdomain", qname.as_ref());
+					DumbResponse::Nxdomain.response(qname, ttl_config, soa)
+				}
+			},
 		}
 	}
 }
 
+/// Record which is configured rather than looked up.
+#[derive(Clone,Eq,PartialEq,Hash,Debug)]
+pub enum StaticRecord
+{
+	Soa,
+	Ns
+	{
+		target: String,
+	},
+	/// A or AAAA record depending on the address family.
+	Address
+	{
+		address: IpAddr,
+	},
+	/// Record from the static records file or the metadata of an instance, the TTL defaulting to the AAAA TTL.
+	Record
+	{
+		qtype: String,
+		content: String,
+		ttl: Option<usize>,
+	},
+}
+
 #[derive(Clone,Eq,PartialEq,Hash,Debug)]
 pub enum DumbResponse
 {
@@ -95,6 +186,10 @@ pub enum DumbResponse
 	},
 	Nxdomain,
 	Soa,
+	Static
+	{
+		records: Vec<StaticRecord>,
+	},
 }
 
 impl DumbResponse
@@ -119,6 +214,20 @@ impl DumbResponse
 				debug!("[dumbresponse][{}][soa] responding", qname.as_ref());
 				vec![soa.clone()].into()
 			},
+			DumbResponse::Static { records, } =>
+			{
+				debug!("[dumbresponse][{}][static] responding with {} records", qname.as_ref(), records.len());
+				records.into_iter()
+					.map(|record| match record
+					{
+						StaticRecord::Soa => soa.clone(),
+						StaticRecord::Ns { target, } => ResponseEntry::ns(qname.as_ref(), ttl_config, target),
+						StaticRecord::Address { address, } => ResponseEntry::glue(qname.as_ref(), ttl_config, address),
+						StaticRecord::Record { qtype, content, ttl, } => ResponseEntry::record(qname.as_ref(), qtype, content, ttl.unwrap_or(ttl_config.aaaa_ttl)),
+					})
+					.collect::<Vec<_>>()
+					.into()
+			},
 		}
 	}
 }
@@ -191,6 +300,9 @@ impl DumbResponse
 /// ## Container
 ///
 /// Existing containers need to respond to AAAA and ANY with a AAAA when they exist, *NXDOMAIN* if they do not exist.
+/// TXT records are taken from the metadata of the container, ANY includes them alongside the AAAA.
+/// Any other type is *NODATA* when the container exists and *NXDOMAIN* otherwise, which needs the container to be looked up just the same.
+/// Since an empty answer is *NXDOMAIN*, *NODATA* is the AAAA records of the container, which PowerDNS drops as they are not of the queried type.
 /// You'd expect them to also respond to SOA on SOA, but that would be a mistake as PowerDNS does not expect the backend to do anything of sorts.
 /// PowerDNS magically figures out the SOA (and which domains exist and which don't) by querying the backend with multiple queries.
 /// So from here on out, as the root zone is already established, everything that you'd expect to be SOA is actually *NXDOMAIN*.
@@ -250,6 +362,44 @@ impl DumbResponse
 /// });
 /// ```
 ///
+/// ```
+/// # use lxddns::pdns::*;
+/// # use lxddns::lxd::*;
+/// # use serde_json::{from_value, json};
+/// let response = from_value::<QueryParameters>(json!(
+/// {
+///     "qname": "container.example.com",
+///     "qtype": "TXT",
+///     "zone_id": 0,
+/// })).unwrap().type_for_domain("example.com");
+///
+/// assert_eq!(response, LookupType::Txt
+/// {
+///     container: "container".parse().unwrap(),
+/// });
+/// ```
+///
+/// ```
+/// # use lxddns::pdns::*;
+/// # use lxddns::lxd::*;
+/// # use serde_json::{from_value, json};
+/// for qtype in [ "MX", "A", "NS", "CAA" ]
+/// {
+///     let response = from_value::<QueryParameters>(json!(
+///     {
+///         "qname": "container.example.com",
+///         "qtype": qtype,
+///         "zone_id": 0,
+///     })).unwrap().type_for_domain("example.com");
+///
+///     assert_eq!(response, LookupType::Smart
+///     {
+///         container: "container".parse().unwrap(),
+///         response: SmartResponse::Nodata,
+///     }, "wrong response for {}", qtype);
+/// }
+/// ```
+///
 /// ## ACME Domains
 /// 
 /// ACME Domains respond with NS entries on all requests except for SOA which are *NXDOMAIN*, otherwise PowerDNS fails.
@@ -311,6 +461,37 @@ impl DumbResponse
 /// });
 /// ```
 ///
+/// ## Services
+///
+/// Services respond with SRV entries on SRV and ANY, either of a single container or of all containers when there is none in the name.
+///
+/// ```
+/// # use lxddns::pdns::*;
+/// # use lxddns::lxd::*;
+/// # use serde_json::{from_value, json};
+/// let lookup = |qname, qtype| from_value::<QueryParameters>(json!(
+/// {
+///     "qname": qname,
+///     "qtype": qtype,
+///     "zone_id": 0,
+/// })).unwrap().type_for_domain("example.com");
+///
+/// assert_eq!(lookup("_http._tcp.container.example.com", "SRV"), LookupType::Srv
+/// {
+///     service: "http".to_string(),
+///     protocol: "tcp".to_string(),
+///     container: Some("container".parse().unwrap()),
+/// });
+/// assert_eq!(lookup("_sip._udp.example.com", "ANY"), LookupType::Srv
+/// {
+///     service: "sip".to_string(),
+///     protocol: "udp".to_string(),
+///     container: None,
+/// });
+/// assert_eq!(lookup("_http._tcp.container.example.com", "SOA"), LookupType::Dumb { response: DumbResponse::Nxdomain, });
+/// assert_eq!(lookup("_http._tcp._container.example.com", "SRV"), LookupType::Dumb { response: DumbResponse::Nxdomain, });
+/// ```
+///
 /// ## Different Domain
 ///
 /// Unrelated domains should be *REFUSED*, but for now we send *NXDOMAIN*.
@@ -401,8 +582,167 @@ pub struct QueryParameters
 	// unused: remote, local, real-remote
 }
 
+/// Splits `_service._protocol` with an optional instance name following it into its parts.
+fn split_service(record: &str) -> Option<(&str, &str, Option<&str>)>
+{
+	let label = |label: &str| !label.is_empty() && label.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '-');
+
+	let (service, rest) = record.strip_prefix('_')?.split_once("._")?;
+	let (protocol, instance) = match rest.split_once('.')
+	{
+		Some((protocol, instance)) => (protocol, Some(instance)),
+		None => (rest, None),
+	};
+
+	(label(service) && label(protocol)).then_some((service, protocol, instance))
+}
+
 impl QueryParameters
 {
+	/// Like [`type_for_domain`](Self::type_for_domain), but answering the apex NS records, glue, static records, and aliases of the zone first.
+	///
+	/// ACME challenges are answered according to the ACME mode of the zone.
+	pub fn type_for_zone(&self, zone: &Zone) -> LookupType
+	{
+		trace!("[queryparameters][type_for_zone][{}][{}] parsing for {}", self.qname(), self.qtype(), zone.domain());
+
+		let glue = zone.glue().iter()
+			.filter(|glue| glue.name().eq(self.qname()))
+			.collect::<Vec<_>>();
+
+		if !glue.is_empty()
+		{
+			debug!("[queryparameters][type_for_zone][{}][{}] is glue", self.qname(), self.qtype());
+
+			let records = glue.into_iter()
+				.filter(|glue| matches!((self.qtype().as_str(), glue.address()), ("ANY", _) | ("AAAA", IpAddr::V6(_)) | ("A", IpAddr::V4(_))))
+				.map(|glue| StaticRecord::Address { address: *glue.address(), })
+				.collect();
+
+			return LookupType::Dumb
+			{
+				response: DumbResponse::Static { records, },
+			};
+		}
+
+		let records = zone.records().lookup(self.qname());
+		let found = !records.is_empty();
+		// a CNAME answers every type
+		let records = records.into_iter()
+			.filter(|record| match record
+			{
+				StaticRecord::Record { qtype, .. } => self.qtype().eq("ANY") || self.qtype().eq(qtype) || qtype.eq("CNAME"),
+				_ => true,
+			})
+			.collect::<Vec<_>>();
+
+		if self.qname.eq(zone.domain())
+		{
+			let nameservers = if self.qtype().eq("NS") || self.qtype().eq("ANY") { zone.nameservers().as_slice() } else { &[] };
+
+			if !nameservers.is_empty() || !records.is_empty()
+			{
+				debug!("[queryparameters][type_for_zone][{}][{}] is apex with configured records", self.qname(), self.qtype());
+
+				let soa = if self.qtype().eq("ANY") { Some(StaticRecord::Soa) } else { None };
+				let records = soa.into_iter()
+					.chain(nameservers.iter().map(|target| StaticRecord::Ns { target: target.clone(), }))
+					.chain(records)
+					.collect();
+
+				return LookupType::Dumb
+				{
+					response: DumbResponse::Static { records, },
+				};
+			}
+		}
+		else if found
+		{
+			debug!("[queryparameters][type_for_zone][{}][{}] is in the static records", self.qname(), self.qtype());
+
+			return LookupType::Dumb
+			{
+				response: DumbResponse::Static { records, },
+			};
+		}
+
+		if let Some(alias) = zone.aliases().iter().find(|alias| alias.name().eq(self.qname()))
+		{
+			if !zone.flatten_aliases()
+			{
+				debug!("[queryparameters][type_for_zone][{}][{}] is alias of {}", self.qname(), self.qtype(), alias.target().as_ref());
+
+				return LookupType::Alias
+				{
+					target: alias.target().clone(),
+				};
+			}
+
+			if self.qtype().eq("ANY") || self.qtype().eq("AAAA")
+			{
+				debug!("[queryparameters][type_for_zone][{}][{}] is flattened alias of {}", self.qname(), self.qtype(), alias.target().as_ref());
+
+				return LookupType::Smart
+				{
+					container: alias.target().clone(),
+					response: SmartResponse::Aaaa,
+				};
+			}
+
+			if self.qtype().eq("SOA")
+			{
+				debug!("[queryparameters][type_for_zone][{}][{}] omitting soa on flattened alias", self.qname(), self.qtype());
+
+				return LookupType::Dumb
+				{
+					response: DumbResponse::Nxdomain,
+				};
+			}
+
+			debug!("[queryparameters][type_for_zone][{}][{}] is flattened alias, but not aaaa-ish", self.qname(), self.qtype());
+
+			return LookupType::Smart
+			{
+				container: alias.target().clone(),
+				response: SmartResponse::Nodata,
+			};
+		}
+
+		match self.type_for_domain(zone.domain())
+		{
+			LookupType::Dumb { response: DumbResponse::Acme { target, }, } =>
+			{
+				// the target of the delegation is always the instance below the domain
+				let Some(container) = target.strip_suffix(&format!(".{}", zone.domain())).and_then(|container| container.parse::<ContainerName>().ok())
+				else
+				{
+					return LookupType::Dumb
+					{
+						response: DumbResponse::Acme { target, },
+					};
+				};
+
+				if zone.acme_instance_mode || matches!(zone.acme_mode, AcmeMode::Txt)
+				{
+					debug!("[queryparameters][type_for_zone][{}][{}] is acme depending on metadata", self.qname(), self.qtype());
+
+					LookupType::Acme
+					{
+						container,
+					}
+				}
+				else
+				{
+					LookupType::Dumb
+					{
+						response: zone.acme(&container, None),
+					}
+				}
+			},
+			lookup => lookup,
+		}
+	}
+
 	pub fn type_for_domain<S: AsRef<str>>(&self, domain: S) -> LookupType
 	{
 		trace!("[queryparameters][type_for_domain][{}][{}] parsing for {}", self.qname(), self.qtype(), domain.as_ref());
@@ -452,6 +792,43 @@ impl QueryParameters
 					}
 				}
 			}
+			else if let Some((service, protocol, instance)) = split_service(record)
+			{
+				trace!("[queryparameters][type_for_domain][{}][{}] is service", self.qname(), self.qtype());
+
+				match instance.map(str::parse::<ContainerName>).transpose()
+				{
+					Ok(container) if self.qtype().eq("SRV") || self.qtype().eq("ANY") =>
+					{
+						debug!("[queryparameters][type_for_domain][{}][{}] is srv-ish service", self.qname(), self.qtype());
+
+						LookupType::Srv
+						{
+							service: service.to_string(),
+							protocol: protocol.to_string(),
+							container,
+						}
+					},
+					Ok(_) =>
+					{
+						debug!("[queryparameters][type_for_domain][{}][{}] is service, but not srv-ish", self.qname(), self.qtype());
+
+						LookupType::Dumb
+						{
+							response: DumbResponse::Nxdomain,
+						}
+					},
+					Err(_) =>
+					{
+						debug!("[queryparameters][type_for_domain][{}][{}] is service of invalid container", self.qname(), self.qtype());
+
+						LookupType::Dumb
+						{
+							response: DumbResponse::Nxdomain,
+						}
+					},
+				}
+			}
 			else
 			{
 				trace!("[queryparameters][type_for_domain][{}][{}] is not acme", self.qname(), self.qtype());
@@ -470,15 +847,34 @@ impl QueryParameters
 							response: SmartResponse::Aaaa
 						}
 					}
-					else
+					else if self.qtype().eq("TXT")
 					{
-						debug!("[queryparameters][type_for_domain][{}][{}] is not aaaa-ish", self.qname(), self.qtype());
+						debug!("[queryparameters][type_for_domain][{}][{}] is txt of container", self.qname(), self.qtype());
+
+						LookupType::Txt
+						{
+							container,
+						}
+					}
+					else if self.qtype().eq("SOA")
+					{
+						debug!("[queryparameters][type_for_domain][{}][{}] omitting soa on container", self.qname(), self.qtype());
 
 						LookupType::Dumb
 						{
 							response: DumbResponse::Nxdomain,
 						}
 					}
+					else
+					{
+						debug!("[queryparameters][type_for_domain][{}][{}] is not aaaa-ish, nodata if container exists", self.qname(), self.qtype());
+
+						LookupType::Smart
+						{
+							container,
+							response: SmartResponse::Nodata,
+						}
+					}
 				}
 				else
 				{
@@ -515,12 +911,539 @@ impl QueryParameters
 	}
 }
 
+/// Address of a nameserver within the zone, given as `NAME=ADDRESS`.
+///
+/// ```
+/// # use lxddns::pdns::Glue;
+/// let glue = "NS1.example.com=2001:db8::53".parse::<Glue>().unwrap();
+/// assert_eq!(glue.name(), "ns1.example.com");
+/// assert_eq!(glue.address(), &"2001:db8::53".parse::<std::net::IpAddr>().unwrap());
+/// assert!("ns1.example.com".parse::<Glue>().is_err());
+/// assert!("ns1.example.com=ns2.example.com".parse::<Glue>().is_err());
+/// ```
+#[derive(Getters,Clone,Eq,PartialEq,Hash,Debug)]
+pub struct Glue
+{
+	#[get = "pub"]
+	name: String,
+	#[get = "pub"]
+	address: IpAddr,
+}
+
+impl FromStr for Glue
+{
+	type Err = crate::error::Error;
+
+	fn from_str(glue: &str) -> std::result::Result<Self,Self::Err>
+	{
+		let (name, address) = glue.split_once('=').ok_or(Error::InvalidConfiguration)?;
+
+		Ok(Glue
+		{
+			name: name.to_lowercase(),
+			address: address.parse().map_err(|_| Error::InvalidConfiguration)?,
+		})
+	}
+}
+
+/// Static alias of an instance, given as `NAME=INSTANCE`.
+///
+/// Names are relative to the domain unless they end with a dot.
+///
+/// ```
+/// # use lxddns::pdns::Alias;
+/// let alias = "DB-primary=pg-03".parse::<Alias>().unwrap();
+/// assert_eq!(alias.name(), "db-primary");
+/// assert_eq!(alias.target().as_ref(), "pg-03");
+/// assert!("db-primary".parse::<Alias>().is_err());
+/// assert!("db-primary=pg_03".parse::<Alias>().is_err());
+/// ```
+#[derive(Getters,Clone,Eq,PartialEq,Hash,Debug)]
+pub struct Alias
+{
+	#[get = "pub"]
+	name: String,
+	#[get = "pub"]
+	target: ContainerName,
+}
+
+impl FromStr for Alias
+{
+	type Err = crate::error::Error;
+
+	fn from_str(alias: &str) -> std::result::Result<Self,Self::Err>
+	{
+		let (name, target) = alias.split_once('=').ok_or(Error::InvalidConfiguration)?;
+
+		Ok(Alias
+		{
+			name: name.to_lowercase(),
+			target: target.parse().map_err(|_| Error::InvalidConfiguration)?,
+		})
+	}
+}
+
+/// Prefix of metadata keys holding a TXT record of an instance each, e.g. `user.lxddns.txt.spf`.
+pub const TXT_PREFIX: &str = "txt.";
+
+/// TXT records of an instance from its metadata, ordered by key.
+///
+/// Values are published verbatim, quoted and split into strings of at most 255 bytes.
+///
+/// ```
+/// # use lxddns::pdns::*;
+/// # use lxddns::lxd::*;
+/// # use serde_json::{from_value, json};
+/// let metadata = from_value::<InstanceMetadata>(json!(
+/// {
+///     "instance": "container",
+///     "metadata":
+///     {
+///         "alias": "www",
+///         "txt.spf": "v=spf1 -all",
+///         "txt.verification": r#"say "hello""#,
+///     },
+/// })).unwrap();
+///
+/// assert_eq!(txt_records(&metadata), vec!
+/// [
+///     StaticRecord::Record { qtype: "TXT".to_string(), content: r#""v=spf1 -all""#.to_string(), ttl: None, },
+///     StaticRecord::Record { qtype: "TXT".to_string(), content: r#""say \"hello\"""#.to_string(), ttl: None, },
+/// ]);
+///
+/// let metadata = from_value::<InstanceMetadata>(json!(
+/// {
+///     "instance": "container",
+///     "metadata":
+///     {
+///         "txt.long": "a".repeat(300),
+///     },
+/// })).unwrap();
+///
+/// assert_eq!(txt_records(&metadata), vec!
+/// [
+///     StaticRecord::Record { qtype: "TXT".to_string(), content: format!("\"{}\" \"{}\"", "a".repeat(255), "a".repeat(45)), ttl: None, },
+/// ]);
+/// ```
+pub fn txt_records(metadata: &InstanceMetadata) -> Vec<StaticRecord>
+{
+	metadata.metadata().iter()
+		.filter(|(key, _)| key.starts_with(TXT_PREFIX))
+		.map(|(_, value)|
+		{
+			let mut strings = Vec::new();
+			let mut rest = value.as_str();
+			while strings.is_empty() || !rest.is_empty()
+			{
+				let mut end = rest.len().min(255);
+				while !rest.is_char_boundary(end)
+				{
+					end -= 1;
+				}
+				let (string, tail) = rest.split_at(end);
+				strings.push(format!("\"{}\"", string.replace('\\', "\\\\").replace('"', "\\\"")));
+				rest = tail;
+			}
+
+			StaticRecord::Record { qtype: "TXT".to_string(), content: strings.join(" "), ttl: None, }
+		})
+		.collect()
+}
+
+/// Prefix of metadata keys announcing a service of an instance, see [`Zone::srv`].
+pub const SRV_PREFIX: &str = "srv.";
+
+/// Metadata key of an instance choosing its own [`AcmeMode`], if permitted.
+pub const ACME_MODE_KEY: &str = "acme-mode";
+/// Metadata key of an instance listing its ACME challenges for [`AcmeMode::Txt`], separated by commas or whitespace.
+pub const ACME_CHALLENGE_KEY: &str = "acme-challenge";
+
+/// How ACME challenges at `_acme-challenge.<instance>.<domain>` are answered.
+///
+/// ```
+/// # use lxddns::pdns::AcmeMode;
+/// assert_eq!("delegate".parse::<AcmeMode>().unwrap(), AcmeMode::Delegate);
+/// assert_eq!("central:acme.example.net.".parse::<AcmeMode>().unwrap(), AcmeMode::Central("acme.example.net.".to_string()));
+/// assert_eq!("cname:acme.example.net.".parse::<AcmeMode>().unwrap(), AcmeMode::Cname("acme.example.net.".to_string()));
+/// assert_eq!("txt".parse::<AcmeMode>().unwrap(), AcmeMode::Txt);
+/// assert!("central".parse::<AcmeMode>().is_err());
+/// assert!("central:".parse::<AcmeMode>().is_err());
+/// ```
+#[derive(Clone,Eq,PartialEq,Hash,Debug,Default)]
+pub enum AcmeMode
+{
+	/// NS record delegating to the instance itself, which has to run a DNS server.
+	#[default]
+	Delegate,
+	/// NS record delegating to a central DNS server, e.g. acme-dns.
+	Central(String),
+	/// CNAME to `<instance>.<zone>`, e.g. labels registered with acme-dns.
+	Cname(String),
+	/// TXT records listed by the instance in its metadata.
+	Txt,
+}
+
+impl FromStr for AcmeMode
+{
+	type Err = crate::error::Error;
+
+	fn from_str(mode: &str) -> std::result::Result<Self,Self::Err>
+	{
+		match mode.split_once(':')
+		{
+			None if mode == "delegate" => Ok(AcmeMode::Delegate),
+			None if mode == "txt" => Ok(AcmeMode::Txt),
+			Some(("central", host)) if !host.is_empty() => Ok(AcmeMode::Central(host.to_lowercase())),
+			Some(("cname", zone)) if !zone.is_empty() => Ok(AcmeMode::Cname(zone.to_lowercase())),
+			_ => Err(Error::InvalidConfiguration),
+		}
+	}
+}
+
+/// Records of the zone itself, as opposed to the ones of the instances
+#[derive(Clone,Eq,PartialEq,Hash,Debug,Default,Args)]
+pub struct ZoneConfig
+{
+	/// Nameserver announced in NS records at the zone apex (may be repeated).
+	#[clap(long = "apex-ns", value_name = "NAME")]
+	apex_ns: Vec<String>,
+	/// Address of a nameserver inside the zone, as `NAME=ADDRESS` (may be repeated).
+	#[clap(long = "glue", value_name = "NAME=ADDRESS")]
+	glue: Vec<Glue>,
+	/// File of static records in zone file syntax, reloaded on SIGHUP, whose names take precedence over instances.
+	#[clap(long, value_name = "FILE")]
+	records: Option<PathBuf>,
+	/// Alias of an instance, as `NAME=INSTANCE` (may be repeated), instances may also list aliases in their `user.lxddns.alias` config key.
+	#[clap(long = "alias", value_name = "NAME=INSTANCE")]
+	aliases: Vec<Alias>,
+	/// Answer static aliases with the addresses of the instance rather than a CNAME, like the aliases listed by instances.
+	#[clap(long)]
+	flatten_aliases: bool,
+	/// How ACME challenges are answered: `delegate` (NS to the instance), `central:HOST` (NS to HOST), `cname:ZONE` (CNAME to the instance below ZONE), or `txt` (TXT from `user.lxddns.acme-challenge`).
+	#[clap(long, value_name = "MODE", default_value = "delegate")]
+	acme_mode: AcmeMode,
+	/// Let instances choose their ACME mode in their `user.lxddns.acme-mode` config key.
+	#[clap(long)]
+	acme_instance_mode: bool,
+}
+
+/// The zone served, along with the records configured for it.
+///
+/// # Tests
+///
+/// ```
+/// # use lxddns::pdns::*;
+/// # use serde_json::{from_value, json};
+/// let zone = Zone::new("example.com", &ZoneConfig::default()).unwrap();
+/// let response = from_value::<QueryParameters>(json!(
+/// {
+///     "qname": "example.com",
+///     "qtype": "NS",
+/// })).unwrap().type_for_zone(&zone);
+///
+/// assert_eq!(response, LookupType::Dumb
+/// {
+///     response: DumbResponse::Soa,
+/// });
+/// ```
+///
+/// Configured nameservers are announced at the apex, their glue takes precedence over instances.
+///
+/// ```
+/// # use lxddns::pdns::*;
+/// # use serde_json::{from_value, json};
+/// # use clap::Parser;
+/// # #[derive(Parser)]
+/// # struct Cli { #[command(flatten)] zone: ZoneConfig }
+/// let config = Cli::parse_from(["lxddns", "--apex-ns", "ns1.example.com", "--apex-ns", "ns.example.net", "--glue", "ns1.example.com=2001:db8::53"]).zone;
+/// let zone = Zone::new("example.com", &config).unwrap();
+/// let lookup = |qname, qtype| from_value::<QueryParameters>(json!(
+/// {
+///     "qname": qname,
+///     "qtype": qtype,
+/// })).unwrap().type_for_zone(&zone);
+///
+/// let ns1 = StaticRecord::Ns { target: "ns1.example.com".to_string(), };
+/// let ns2 = StaticRecord::Ns { target: "ns.example.net".to_string(), };
+/// let glue = StaticRecord::Address { address: "2001:db8::53".parse().unwrap(), };
+///
+/// assert_eq!(lookup("example.com", "NS"), LookupType::Dumb { response: DumbResponse::Static { records: vec![ns1.clone(), ns2.clone()], }, });
+/// assert_eq!(lookup("example.com", "ANY"), LookupType::Dumb { response: DumbResponse::Static { records: vec![StaticRecord::Soa, ns1, ns2], }, });
+/// assert_eq!(lookup("example.com", "SOA"), LookupType::Dumb { response: DumbResponse::Soa, });
+/// assert_eq!(lookup("ns1.example.com", "AAAA"), LookupType::Dumb { response: DumbResponse::Static { records: vec![glue.clone()], }, });
+/// assert_eq!(lookup("ns1.example.com", "ANY"), LookupType::Dumb { response: DumbResponse::Static { records: vec![glue], }, });
+/// assert_eq!(lookup("ns1.example.com", "A"), LookupType::Dumb { response: DumbResponse::Static { records: vec![], }, });
+///
+/// let config = Cli::parse_from(["lxddns", "--glue", "ns1.example.net=2001:db8::53"]).zone;
+/// assert!(Zone::new("example.com", &config).is_err());
+/// ```
+///
+/// Static aliases are answered with a CNAME to the instance, or looked up like the instance if flattened.
+///
+/// ```
+/// # use lxddns::pdns::*;
+/// # use lxddns::lxd::*;
+/// # use serde_json::{from_value, json};
+/// # use clap::Parser;
+/// # #[derive(Parser)]
+/// # struct Cli { #[command(flatten)] zone: ZoneConfig }
+/// let lookup = |zone: &Zone, qtype| from_value::<QueryParameters>(json!(
+/// {
+///     "qname": "db-primary.example.com",
+///     "qtype": qtype,
+/// })).unwrap().type_for_zone(zone);
+///
+/// let zone = Zone::new("example.com", &Cli::parse_from(["lxddns", "--alias", "db-primary=pg-03"]).zone).unwrap();
+/// assert_eq!(lookup(&zone, "SOA"), LookupType::Alias { target: "pg-03".parse().unwrap(), });
+///
+/// let zone = Zone::new("example.com", &Cli::parse_from(["lxddns", "--alias", "db-primary=pg-03", "--flatten-aliases"]).zone).unwrap();
+/// assert_eq!(lookup(&zone, "AAAA"), LookupType::Smart { container: "pg-03".parse().unwrap(), response: SmartResponse::Aaaa, });
+/// assert_eq!(lookup(&zone, "SOA"), LookupType::Dumb { response: DumbResponse::Nxdomain, });
+/// assert_eq!(lookup(&zone, "MX"), LookupType::Smart { container: "pg-03".parse().unwrap(), response: SmartResponse::Nodata, });
+///
+/// assert!(Zone::new("example.com", &Cli::parse_from(["lxddns", "--alias", "db.example.net.=pg-03"]).zone).is_err());
+/// ```
+///
+/// ACME challenges are answered according to the ACME mode, which instances may choose themselves if permitted.
+///
+/// ```
+/// # use lxddns::pdns::*;
+/// # use lxddns::lxd::*;
+/// # use serde_json::{from_value, json};
+/// # use clap::Parser;
+/// # #[derive(Parser)]
+/// # struct Cli { #[command(flatten)] zone: ZoneConfig }
+/// let lookup = |zone: &Zone| from_value::<QueryParameters>(json!(
+/// {
+///     "qname": "_acme-challenge.web.example.com",
+///     "qtype": "ANY",
+/// })).unwrap().type_for_zone(zone);
+/// let web = "web".parse::<ContainerName>().unwrap();
+/// let cname = |content: &str| StaticRecord::Record { qtype: "CNAME".to_string(), content: content.to_string(), ttl: None, };
+/// let txt = |content: &str| StaticRecord::Record { qtype: "TXT".to_string(), content: content.to_string(), ttl: None, };
+///
+/// let zone = Zone::new("example.com", &Cli::parse_from(["lxddns", "--acme-mode", "cname:acme.example.net."]).zone).unwrap();
+/// assert_eq!(lookup(&zone), LookupType::Dumb { response: DumbResponse::Static { records: vec![cname("web.acme.example.net.")], }, });
+///
+/// let zone = Zone::new("example.com", &Cli::parse_from(["lxddns", "--acme-mode", "txt"]).zone).unwrap();
+/// assert_eq!(lookup(&zone), LookupType::Acme { container: web.clone(), });
+/// let metadata = from_value::<InstanceMetadata>(json!({ "instance": "web", "metadata": { "acme-challenge": "token1, token2" }, })).unwrap();
+/// assert_eq!(zone.acme(&web, Some(&metadata)), DumbResponse::Static { records: vec![txt("\"token1\""), txt("\"token2\"")], });
+///
+/// let zone = Zone::new("example.com", &Cli::parse_from(["lxddns", "--acme-instance-mode"]).zone).unwrap();
+/// assert_eq!(lookup(&zone), LookupType::Acme { container: web.clone(), });
+/// let metadata = from_value::<InstanceMetadata>(json!({ "instance": "web", "metadata": { "acme-mode": "central:acme.example.net." }, })).unwrap();
+/// assert_eq!(zone.acme(&web, Some(&metadata)), DumbResponse::Acme { target: "acme.example.net.".to_string(), });
+/// assert_eq!(zone.acme(&web, None), DumbResponse::Acme { target: "web.example.com".to_string(), });
+/// ```
+///
+/// Services are announced by the instances offering them, lookups of the whole zone combining all of them.
+///
+/// ```
+/// # use lxddns::pdns::*;
+/// # use lxddns::lxd::*;
+/// # use serde_json::{from_value, json};
+/// let zone = Zone::from("example.com");
+/// let srv = |content: &str| StaticRecord::Record { qtype: "SRV".to_string(), content: content.to_string(), ttl: None, };
+/// let metadata = from_value::<Vec<InstanceMetadata>>(json!(
+/// [
+///     { "instance": "web1", "metadata": { "srv.http": "443", "srv.sip.udp": "10 5 5060", }, },
+///     { "instance": "web2", "metadata": { "srv.http.tcp": "8443", "srv.sip.tcp": "five", }, },
+///     { "instance": "db", "metadata": {}, },
+/// ])).unwrap();
+///
+/// assert_eq!(zone.srv("http", "tcp", &metadata), DumbResponse::Static { records: vec![srv("0 0 443 web1.example.com"), srv("0 0 8443 web2.example.com")], });
+/// assert_eq!(zone.srv("sip", "udp", &metadata), DumbResponse::Static { records: vec![srv("10 5 5060 web1.example.com")], });
+/// assert_eq!(zone.srv("sip", "tcp", &metadata), DumbResponse::Static { records: vec![], });
+/// assert_eq!(zone.srv("http", "tcp", &metadata[2..]), DumbResponse::Static { records: vec![], });
+/// ```
+#[derive(Getters,Clone,Debug)]
+pub struct Zone
+{
+	#[get = "pub"]
+	domain: String,
+	#[get = "pub"]
+	nameservers: Vec<String>,
+	#[get = "pub"]
+	glue: Vec<Glue>,
+	#[get = "pub"]
+	records: Records,
+	/// Aliases by their fully qualified name.
+	#[get = "pub"]
+	aliases: Vec<Alias>,
+	#[get = "pub"]
+	flatten_aliases: bool,
+	#[get = "pub"]
+	acme_mode: AcmeMode,
+	#[get = "pub"]
+	acme_instance_mode: bool,
+}
+
+impl Zone
+{
+	pub fn new<S: AsRef<str>>(domain: S, config: &ZoneConfig) -> Result<Self>
+	{
+		let domain = domain.as_ref().to_string();
+		let suffix = format!(".{}", domain.to_lowercase());
+
+		for glue in config.glue.iter()
+		{
+			if !glue.name().ends_with(&suffix)
+			{
+				return Err(Error::InvalidConfiguration).with_context(|| format!("glue for {} is outside of {}", glue.name(), domain));
+			}
+		}
+
+		let nameservers = config.apex_ns.iter()
+			.map(|nameserver| nameserver.to_lowercase())
+			.collect::<Vec<_>>();
+
+		for nameserver in nameservers.iter()
+		{
+			if nameserver.ends_with(&suffix) && !config.glue.iter().any(|glue| glue.name().eq(nameserver))
+			{
+				warn!("[zone][{}] nameserver {} is inside the zone but has no glue", domain, nameserver);
+			}
+		}
+
+		let records = Records::load(&domain, config.records.as_deref()).context(Error::InvalidConfiguration)?;
+
+		let aliases = config.aliases.iter()
+			.map(|alias| Alias
+			{
+				name: records::qualify(alias.name(), &domain),
+				target: alias.target().clone(),
+			})
+			.collect::<Vec<_>>();
+
+		if let Some(alias) = aliases.iter().find(|alias| !alias.name().ends_with(&suffix))
+		{
+			return Err(Error::InvalidConfiguration).with_context(|| format!("alias {} is not below {}", alias.name(), domain));
+		}
+
+		Ok(Self
+		{
+			domain,
+			nameservers,
+			glue: config.glue.clone(),
+			records,
+			aliases,
+			flatten_aliases: config.flatten_aliases,
+			acme_mode: config.acme_mode.clone(),
+			acme_instance_mode: config.acme_instance_mode,
+		})
+	}
+
+	/// Answer to the ACME challenge of an instance, given its metadata if needed.
+	pub fn acme(&self, container: &ContainerName, metadata: Option<&InstanceMetadata>) -> DumbResponse
+	{
+		let metadata = metadata.map(InstanceMetadata::metadata);
+
+		let mode = match metadata.and_then(|metadata| metadata.get(ACME_MODE_KEY)).filter(|_| self.acme_instance_mode)
+		{
+			Some(mode) => mode.parse().unwrap_or_else(|_|
+			{
+				warn!("[zone][{}] ignoring invalid acme mode {:?} of {}", self.domain, mode, container.as_ref());
+				self.acme_mode.clone()
+			}),
+			None => self.acme_mode.clone(),
+		};
+		trace!("[zone][{}] acme mode of {}: {:?}", self.domain, container.as_ref(), mode);
+
+		match mode
+		{
+			AcmeMode::Delegate => DumbResponse::Acme
+			{
+				target: format!("{}.{}", container.as_ref(), self.domain),
+			},
+			AcmeMode::Central(host) => DumbResponse::Acme
+			{
+				target: host,
+			},
+			AcmeMode::Cname(zone) => DumbResponse::Static
+			{
+				records: vec![StaticRecord::Record { qtype: "CNAME".to_string(), content: format!("{}.{}", container.as_ref(), zone), ttl: None, }],
+			},
+			AcmeMode::Txt => DumbResponse::Static
+			{
+				records: metadata.and_then(|metadata| metadata.get(ACME_CHALLENGE_KEY)).into_iter()
+					.flat_map(|challenges| challenges.split(|ch: char| ch == ',' || ch.is_whitespace()))
+					.filter(|challenge| !challenge.is_empty())
+					.map(|challenge| StaticRecord::Record { qtype: "TXT".to_string(), content: format!("\"{}\"", challenge), ttl: None, })
+					.collect(),
+			},
+		}
+	}
+
+	/// Answer to a service lookup, given the metadata of the instances which may offer it.
+	///
+	/// An instance offers a service on `_<service>._<protocol>` with a metadata key `srv.<service>.<protocol>`, or just `srv.<service>` for TCP.
+	/// The value is either the port or `<priority> <weight> <port>`, the target being the name of the instance.
+	pub fn srv(&self, service: &str, protocol: &str, metadata: &[InstanceMetadata]) -> DumbResponse
+	{
+		let mut keys = vec![format!("{}{}.{}", SRV_PREFIX, service, protocol)];
+		if protocol.eq("tcp")
+		{
+			keys.push(format!("{}{}", SRV_PREFIX, service));
+		}
+
+		// instances may be reported by more than one host, so this also deduplicates
+		let contents = metadata.iter()
+			.flat_map(|metadata| keys.iter().filter_map(move |key| metadata.metadata().get(key)).map(move |value| (metadata.instance(), value)))
+			.filter_map(|(instance, value)|
+			{
+				let fields = value.split_whitespace().map(str::parse::<u16>).collect::<std::result::Result<Vec<_>,_>>();
+				let (priority, weight, port) = match fields.as_deref()
+				{
+					Ok([port]) => (0, 0, *port),
+					Ok([priority, weight, port]) => (*priority, *weight, *port),
+					_ =>
+					{
+						warn!("[zone][{}] ignoring invalid service {:?} of {}", self.domain, value, instance);
+						return None;
+					},
+				};
+
+				Some(format!("{} {} {} {}.{}", priority, weight, port, instance, self.domain))
+			})
+			.collect::<BTreeSet<_>>();
+
+		DumbResponse::Static
+		{
+			records: contents.into_iter()
+				.map(|content| StaticRecord::Record { qtype: "SRV".to_string(), content, ttl: None, })
+				.collect(),
+		}
+	}
+}
+
+impl From<&str> for Zone
+{
+	fn from(domain: &str) -> Self
+	{
+		Self
+		{
+			domain: domain.to_string(),
+			nameservers: Vec::new(),
+			glue: Vec::new(),
+			records: Default::default(),
+			aliases: Vec::new(),
+			flatten_aliases: false,
+			acme_mode: Default::default(),
+			acme_instance_mode: false,
+		}
+	}
+}
+
 #[derive(Deserialize,Clone,Eq,PartialEq,Hash,Debug)]
 #[serde(tag = "method")]
 pub enum Query
 {
 	#[serde(rename = "initialize")]
 	Initialize,
+	/// Not part of the PowerDNS protocol, requests the state of the backend for debugging purposes.
+	#[serde(rename = "diagnostics")]
+	Diagnostics,
 	#[serde(rename = "lookup")]
 	Lookup
 	{
@@ -531,18 +1454,25 @@ pub enum Query
 }
 
 /// DNS TTL related configuration options, used to generate DNS records
-#[derive(Clone,Eq,PartialEq,Hash,Debug,Args)]
+#[derive(Getters,Clone,Eq,PartialEq,Hash,Debug,Args)]
 pub struct TtlConfig
 {
 	/// TTL for SOA records, aka NXDOMAIN caching.
 	#[clap(long, default_value = "64")]
+	#[get = "pub"]
 	soa_ttl: usize,
 	/// TTL for AAAA records, aka the container records.
 	#[clap(long, default_value = "128")]
+	#[get = "pub"]
 	aaaa_ttl: usize,
 	/// TTL for NS records, aka the ACME challenge records.
 	#[clap(long, default_value = "7200")]
+	#[get = "pub"]
 	ns_ttl: usize,
+	/// TTL for stale answers served because the backend failed, see RFC 8767.
+	#[clap(long, default_value = "30")]
+	#[get = "pub"]
+	stale_ttl: usize,
 }
 
 impl Default for TtlConfig
@@ -554,6 +1484,39 @@ impl Default for TtlConfig
 			soa_ttl: 64,
 			aaaa_ttl: 128,
 			ns_ttl: 7200,
+			stale_ttl: 30,
+		}
+	}
+}
+
+impl TtlConfig
+{
+	/// Configuration for answering with addresses which may only be cached for the given number of seconds.
+	///
+	/// ```
+	/// # use lxddns::pdns::TtlConfig;
+	/// assert_eq!(*TtlConfig::default().capped(60).aaaa_ttl(), 60);
+	/// assert_eq!(*TtlConfig::default().capped(600).aaaa_ttl(), 128);
+	/// assert_eq!(*TtlConfig::default().capped(60).soa_ttl(), 64);
+	/// ```
+	pub fn capped(&self, ttl: usize) -> Self
+	{
+		TtlConfig
+		{
+			aaaa_ttl: self.aaaa_ttl.min(ttl),
+			..self.clone()
+		}
+	}
+
+	/// Configuration for answering with stale data, every TTL of records derived from it capped to the stale TTL.
+	pub fn stale(&self) -> Self
+	{
+		TtlConfig
+		{
+			soa_ttl: self.soa_ttl.min(self.stale_ttl),
+			aaaa_ttl: self.aaaa_ttl.min(self.stale_ttl),
+			ns_ttl: self.ns_ttl.min(self.stale_ttl),
+			stale_ttl: self.stale_ttl,
 		}
 	}
 }
@@ -574,11 +1537,11 @@ pub struct ResponseEntry
 
 impl ResponseEntry
 {
-	pub fn soa<D: AsRef<str>, H: AsRef<str>>(domain: D, ttl: &TtlConfig, hostmaster: H) -> Self
+	pub fn soa<D: AsRef<str>, H: AsRef<str>>(domain: D, ttl: &TtlConfig, hostmaster: H, soa: &Soa) -> Self
 	{
 		ResponseEntry
 		{
-			content: format!("{} {} 1 86400 7200 3600000 3600", domain.as_ref(), hostmaster.as_ref()),
+			content: soa.content(domain.as_ref(), hostmaster, ttl.soa_ttl),
 			qtype: "SOA".to_string(),
 			qname: domain.as_ref().to_string(),
 			ttl: ttl.soa_ttl,
@@ -596,6 +1559,40 @@ impl ResponseEntry
 		}
 	}
 
+	/// Address of a nameserver, which is as static as the NS record and thus shares its TTL.
+	pub fn glue<D: AsRef<str>>(domain: D, ttl: &TtlConfig, addr: IpAddr) -> Self
+	{
+		ResponseEntry
+		{
+			content: format!("{}", addr),
+			qtype: if addr.is_ipv6() { "AAAA" } else { "A" }.to_string(),
+			qname: domain.as_ref().to_string(),
+			ttl: ttl.ns_ttl,
+		}
+	}
+
+	pub fn cname<D: AsRef<str>, T: AsRef<str>>(domain: D, ttl: &TtlConfig, target: T) -> Self
+	{
+		ResponseEntry
+		{
+			content: target.as_ref().to_string(),
+			qtype: "CNAME".to_string(),
+			qname: domain.as_ref().to_string(),
+			ttl: ttl.aaaa_ttl,
+		}
+	}
+
+	pub fn record<D: AsRef<str>>(domain: D, qtype: String, content: String, ttl: usize) -> Self
+	{
+		ResponseEntry
+		{
+			content,
+			qtype,
+			qname: domain.as_ref().to_string(),
+			ttl,
+		}
+	}
+
 	pub fn aaaa<D: AsRef<str>>(domain: D, ttl: &TtlConfig, addr: Ipv6Addr) -> Self
 	{
 		ResponseEntry
@@ -626,6 +1623,25 @@ impl From<Vec<ResponseEntry>> for Response
 	}
 }
 
+impl Extend<ResponseEntry> for Response
+{
+	fn extend<I: IntoIterator<Item = ResponseEntry>>(&mut self, entries: I)
+	{
+		self.result.extend(entries);
+	}
+}
+
+impl IntoIterator for Response
+{
+	type Item = ResponseEntry;
+	type IntoIter = std::vec::IntoIter<ResponseEntry>;
+
+	fn into_iter(self) -> Self::IntoIter
+	{
+		self.result.into_iter()
+	}
+}
+
 fn deserialize_string_lowercase<'de, D>(deserializer: D) -> std::result::Result<String, D::Error>
 	where
 		D: Deserializer<'de>
@@ -11,21 +11,36 @@ use crate::
 	lxd::
 	{
 		ContainerName,
+		InstanceMetadata,
 	},
 	pdns::
 	{
+		txt_records,
+		AcmeMode,
+		DumbResponse,
 		LookupType,
 		Query,
 		ResponseEntry,
 		TtlConfig,
+		Zone,
 	},
+	soa::Soa,
+	shutdown::Shutdown,
 };
 
 use ::
 {
+	futures::
+	{
+		future::BoxFuture,
+		stream,
+		FutureExt,
+		StreamExt,
+	},
 	serde_json::
 	{
 		json,
+		Value,
 	},
 	tokio::
 	{
@@ -47,15 +62,214 @@ use ::
 			Instant,
 		},
 		net::Ipv6Addr,
+		sync::
+		{
+			Arc,
+			Mutex,
+		},
 	},
 };
 
 /// Trait specifying how to query a remote backend.
 #[async_trait::async_trait]
-pub trait RemoteQuery
+pub trait RemoteQuery: Send+Sync
 {
 	async fn remote_query(&self, name: &ContainerName) -> Result<Option<Vec<Ipv6Addr>>>;
 	fn name(&self) -> String;
+
+	/// Like [`remote_query`](Self::remote_query), along with the seconds the answer may be cached for if the backend limits them.
+	async fn remote_query_ttl(&self, name: &ContainerName) -> Result<(Option<Vec<Ipv6Addr>>, Option<usize>)>
+	{
+		Ok((self.remote_query(name).await?, None))
+	}
+
+	/// State of the backend worth inspecting when debugging, returned for `diagnostics` requests.
+	fn diagnostics(&self) -> Value
+	{
+		Value::Null
+	}
+
+	/// Last known addresses of an instance, for answering while the backend is failing.
+	fn stale(&self, _name: &ContainerName) -> Option<Vec<Ipv6Addr>>
+	{
+		None
+	}
+
+	/// Metadata of the instance a name refers to, or of all running instances if no name is given.
+	///
+	/// Backends unable to retrieve metadata have none.
+	async fn metadata(&self, _name: Option<&ContainerName>) -> Result<Vec<InstanceMetadata>>
+	{
+		Ok(Vec::new())
+	}
+
+	/// Releases resources held by the backend once the handler is done with it.
+	async fn close(&self) -> Result<()>
+	{
+		Ok(())
+	}
+}
+
+#[async_trait::async_trait]
+impl<T> RemoteQuery for Box<T>
+	where
+		T: RemoteQuery+Send+Sync+?Sized,
+{
+	async fn remote_query(&self, name: &ContainerName) -> Result<Option<Vec<Ipv6Addr>>>
+	{
+		(**self).remote_query(name).await
+	}
+
+	async fn remote_query_ttl(&self, name: &ContainerName) -> Result<(Option<Vec<Ipv6Addr>>, Option<usize>)>
+	{
+		(**self).remote_query_ttl(name).await
+	}
+
+	fn name(&self) -> String
+	{
+		(**self).name()
+	}
+
+	fn diagnostics(&self) -> Value
+	{
+		(**self).diagnostics()
+	}
+
+	fn stale(&self, name: &ContainerName) -> Option<Vec<Ipv6Addr>>
+	{
+		(**self).stale(name)
+	}
+
+	async fn metadata(&self, name: Option<&ContainerName>) -> Result<Vec<InstanceMetadata>>
+	{
+		(**self).metadata(name).await
+	}
+
+	async fn close(&self) -> Result<()>
+	{
+		(**self).close().await
+	}
+}
+
+/// Handle on a backend used by several owners at once, e.g. all connections of a server or background tasks.
+///
+/// Closing a handle does nothing, the backend itself is closed once the last handle is dropped.
+pub struct SharedBackend<B>
+{
+	backend: Arc<B>,
+	_closer: Arc<Closer>,
+}
+
+/// Closes the backend when dropped, i.e. when the last [`SharedBackend`] is gone.
+struct Closer
+{
+	// only ever touched on drop, the mutex merely makes this shareable
+	close: Mutex<Option<BoxFuture<'static,Result<()>>>>,
+}
+
+impl Drop for Closer
+{
+	fn drop(&mut self)
+	{
+		let Some(close) = self.close.get_mut().ok().and_then(Option::take)
+		else
+		{
+			return;
+		};
+
+		match tokio::runtime::Handle::try_current()
+		{
+			Ok(runtime) =>
+			{
+				runtime.spawn(async move
+				{
+					if let Err(err) = close.await
+					{
+						debug!("[pdns_io][shared] error closing backend: {:#}", err);
+					}
+				});
+			},
+			Err(_) => debug!("[pdns_io][shared] runtime is gone, not closing backend"),
+		}
+	}
+}
+
+impl<B> SharedBackend<B>
+	where
+		B: RemoteQuery+'static,
+{
+	pub fn new(backend: B) -> Self
+	{
+		let backend = Arc::new(backend);
+		let close =
+		{
+			let backend = backend.clone();
+			async move { backend.close().await }.boxed()
+		};
+
+		Self
+		{
+			backend,
+			_closer: Arc::new(Closer
+			{
+				close: Mutex::new(Some(close)),
+			}),
+		}
+	}
+}
+
+impl<B> Clone for SharedBackend<B>
+{
+	fn clone(&self) -> Self
+	{
+		Self
+		{
+			backend: self.backend.clone(),
+			_closer: self._closer.clone(),
+		}
+	}
+}
+
+#[async_trait::async_trait]
+impl<B> RemoteQuery for SharedBackend<B>
+	where
+		B: RemoteQuery+'static,
+{
+	async fn remote_query(&self, name: &ContainerName) -> Result<Option<Vec<Ipv6Addr>>>
+	{
+		self.backend.remote_query(name).await
+	}
+
+	async fn remote_query_ttl(&self, name: &ContainerName) -> Result<(Option<Vec<Ipv6Addr>>, Option<usize>)>
+	{
+		self.backend.remote_query_ttl(name).await
+	}
+
+	fn name(&self) -> String
+	{
+		self.backend.name()
+	}
+
+	fn diagnostics(&self) -> Value
+	{
+		self.backend.diagnostics()
+	}
+
+	fn stale(&self, name: &ContainerName) -> Option<Vec<Ipv6Addr>>
+	{
+		self.backend.stale(name)
+	}
+
+	async fn metadata(&self, name: Option<&ContainerName>) -> Result<Vec<InstanceMetadata>>
+	{
+		self.backend.metadata(name).await
+	}
+
+	async fn close(&self) -> Result<()>
+	{
+		// other owners might still be using this backend, the last one to drop it closes it
+		Ok(())
+	}
 }
 
 pub struct PdnsStreamHandler<R, W, B>
@@ -64,12 +278,28 @@ pub struct PdnsStreamHandler<R, W, B>
 		W: AsyncWrite+Unpin,
 		B: RemoteQuery,
 {
-	domain: String,
+	zone: Zone,
 	hostmaster: String,
 	ttl_config: TtlConfig,
+	soa: Soa,
 	backend: B,
 	reader: R,
 	writer: W,
+	pipeline_depth: usize,
+	shutdown: Shutdown,
+}
+
+/// Outcome of handling a single request line.
+enum Reply
+{
+	/// Line to send back to PowerDNS.
+	Line(String),
+	/// Line to send back to PowerDNS, after which the connection is to be closed and the error passed on.
+	Last(String, anyhow::Error),
+	/// The connection is to be closed without sending anything.
+	Close,
+	/// The connection is to be closed and the error passed on.
+	Fail(anyhow::Error),
 }
 
 impl<R, W, B> PdnsStreamHandler<R, W, B>
@@ -85,145 +315,329 @@ impl<R, W, B> PdnsStreamHandler<R, W, B>
 	{
 		Ok(Self
 		{
-			domain: domain.as_ref().to_string(),
+			zone: domain.as_ref().into(),
 			hostmaster: hostmaster.as_ref().to_string(),
 			ttl_config: ttl_config.clone(),
+			soa: Soa::default(),
 			backend,
 			reader,
 			writer,
+			pipeline_depth: 1,
+			shutdown: Shutdown::never(),
 		})
 	}
 
-	pub async fn run(mut self) -> Result<()>
+	/// Number of requests read ahead and processed concurrently.
+	///
+	/// Responses are still written in the order the requests were received.
+	/// The default of 1 handles strictly one request at a time.
+	pub fn pipeline_depth(mut self, pipeline_depth: usize) -> Self
+	{
+		self.pipeline_depth = pipeline_depth.max(1);
+		self
+	}
+
+	/// Records of the zone itself, replacing the bare domain given on creation.
+	pub fn zone(mut self, zone: Zone) -> Self
 	{
-		let soa_record = &ResponseEntry::soa(&self.domain, &self.ttl_config, &self.hostmaster);
-		let ttl_config = &self.ttl_config;
+		self.zone = zone;
+		self
+	}
 
-		debug!("[pdns_io][handler] handling stream with queue {}", self.backend.name());
+	/// SOA record state, to be shared with all other handlers of the same server.
+	pub fn soa(mut self, soa: Soa) -> Self
+	{
+		self.soa = soa;
+		self
+	}
+
+	/// Stops reading requests once a shutdown is requested.
+	///
+	/// Requests already read are still answered before the connection is closed.
+	pub fn shutdown(mut self, shutdown: Shutdown) -> Self
+	{
+		self.shutdown = shutdown;
+		self
+	}
 
-		let mut lines = BufReader::new(&mut self.reader).split(b'\n');
-		while let Some(input) = lines.next_segment().await.transpose()
+	pub async fn run(self) -> Result<()>
+	{
+		let Self { zone, hostmaster, ttl_config, soa, backend, reader, mut writer, pipeline_depth, shutdown, } = self;
+
+		let ttl_config = &ttl_config;
+		let soa = &soa;
+		let hostmaster = &hostmaster;
+		let zone = &zone;
+		let backend = &backend;
+
+		debug!("[pdns_io][handler] handling stream with queue {} (pipeline depth {})", backend.name(), pipeline_depth);
+
+		let lines = stream::unfold(BufReader::new(reader).split(b'\n'), |mut lines| async move
 		{
-			trace!("[pdns_io][handler] request received");
-			let input = match input
+			lines.next_segment().await.transpose().map(|input| (input, lines))
+		});
+
+		let replies = lines
+			.take_until(shutdown.wait())
+			.map(|input| async move
 			{
-				Err(err) =>
+				trace!("[pdns_io][handler] request received");
+				match input
 				{
-					warn!("[pdns_io][handler] read error: {}", err);
-					break;
-				},
-				Ok(ok) => ok,
+					Err(err) =>
+					{
+						warn!("[pdns_io][handler] read error: {}", err);
+						Reply::Close
+					},
+					Ok(input) => Self::handle(zone, hostmaster, ttl_config, soa, backend, &input).await,
+				}
+			})
+			.buffered(pipeline_depth)
+		;
+		// boxed so the lookups still in flight can be dropped before closing the backend
+		let mut replies = Box::pin(replies);
+
+		while let Some(reply) = replies.next().await
+		{
+			let (line, failure) = match reply
+			{
+				Reply::Line(line) => (Some(line), None),
+				Reply::Last(line, err) => (Some(line), Some(err)),
+				Reply::Fail(err) => (None, Some(err)),
+				Reply::Close => break,
 			};
 
-			trace!("[pdns_io][handler] parsing request");
-			match serde_json::from_slice::<Query>(&input)
+			if let Some(line) = line
 			{
-				Ok(Query::Lookup { parameters: query, }) =>
+				if let Err(err) = writer.write_all(line.as_bytes()).await
 				{
-					debug!("[pdns_io][handler][{}] type {}", query.qname(), query.qtype());
+					warn!("[pdns_io][handler] closing unix stream due to socket error: {}", err);
+					break;
+				}
 
-					match query.type_for_domain(&self.domain)
-					{
-						LookupType::Smart { container, response, } =>
-						{
-							debug!("[pdns_io][handler][{}] smart response, querying {}", query.qname(), container.as_ref());
+				trace!("[pdns_io][handler] flushing");
+				if let Err(err) = writer.flush().await
+				{
+					warn!("[pdns_io][handler] closing unix stream due to socket error: {}", err);
+					break;
+				}
+			}
 
-							let instant = Instant::now();
-							let result = timeout(Duration::from_millis(4500),self.backend.remote_query(&container)).await;
+			if let Some(err) = failure
+			{
+				drop(replies);
+				if let Err(close_err) = backend.close().await
+				{
+					debug!("[pdns_io][handler] error closing backend: {}", close_err);
+				}
+				return Err(err);
+			}
+		}
+		drop(replies);
+		backend.close().await.context("error closing backend")?;
+		debug!("[pdns_io][handler] connection closed");
+
+		Ok(())
+	}
+
+	async fn handle(zone: &Zone, hostmaster: &str, ttl_config: &TtlConfig, soa: &Soa, backend: &B, input: &[u8]) -> Reply
+	{
+		trace!("[pdns_io][handler] parsing request");
+		match serde_json::from_slice::<Query>(input)
+		{
+			Ok(Query::Lookup { parameters: query, }) =>
+			{
+				debug!("[pdns_io][handler][{}] type {}", query.qname(), query.qtype());
 
-							debug!("[pdns_io][handler][{}] remote_query ran for {:.3}s (timeout: {})", query.qname(), instant.elapsed().as_secs_f64(), result.is_err());
+				// the serial may change at any time
+				let soa_record = &ResponseEntry::soa(zone.domain(), ttl_config, hostmaster, soa);
+
+				// set if the answer is still sent, but the connection is to be closed afterwards
+				let mut taint = None;
+				let response = match query.type_for_zone(zone)
+				{
+					LookupType::Smart { container, response, } =>
+					{
+						debug!("[pdns_io][handler][{}] smart response, querying {}", query.qname(), container.as_ref());
 
-							match result.ok().unwrap_or(Ok(None))
+						let instant = Instant::now();
+						// PowerDNS mostly asks for ANY, which includes the TXT records of the instance
+						let (result, metadata) = tokio::join!
+						(
+							timeout(Duration::from_millis(4500), backend.remote_query_ttl(&container)),
+							async
 							{
-								Ok(result) =>
+								if query.qtype().eq("ANY")
 								{
-									debug!("[pdns_io][handler][{}] got {:?}", query.qname(), result);
+									Some(timeout(Duration::from_millis(4500), backend.metadata(Some(&container))).await)
+								}
+								else
+								{
+									None
+								}
+							},
+						);
 
-									let response = response.response(query.qname(), ttl_config, soa_record, result);
+						debug!("[pdns_io][handler][{}] remote_query ran for {:.3}s (timeout: {})", query.qname(), instant.elapsed().as_secs_f64(), result.is_err());
 
-									match serde_json::to_string(&response)
-									{
-										Ok(json) =>
-										{
-											if let Err(err) = self.writer.write_all(format!("{}\n", json).as_bytes()).await
-											{
-												warn!("[pdns_io][handler][{}] closing unix stream due to socket error: {}", query.qname(), err);
-												break;
-											}
-										},
-										Err(err) =>
-										{
-											warn!("[pdns_io][handler][{}] closing unix stream due to json error: {}", query.qname(), err);
-											break;
-										},
-									}
-								},
-								Err(err) =>
-								{
-									warn!("[pdns_io][handler][{}] resolve error, assuming taint: {}", query.qname(), err);
-									Err(err).context(Error::MessageQueueTaint)?;
-								},
-							}
-						},
-						LookupType::Dumb { response, } =>
+						// missing TXT records are not worth failing the addresses over
+						let txt = match metadata.map(|metadata| metadata.unwrap_or_else(|_| Err(Error::BackendTimeout.into())))
 						{
-							debug!("[pdns_io][handler][{}] dumb response", query.qname());
+							Some(Ok(metadata)) => metadata.first().map(txt_records).unwrap_or_default(),
+							Some(Err(err)) =>
+							{
+								info!("[pdns_io][handler][{}] metadata error, omitting txt records: {:#}", query.qname(), err);
+								Vec::new()
+							},
+							None => Vec::new(),
+						};
 
-							let response = response.response(query.qname(), ttl_config, soa_record);
+						// a timeout says nothing about the instance, so it must not end up as NXDOMAIN
+						match result.unwrap_or_else(|_| Err(Error::BackendTimeout.into()))
+						{
+							Ok((result, ttl)) =>
+							{
+								debug!("[pdns_io][handler][{}] got {:?} (ttl: {:?})", query.qname(), result, ttl);
+								soa.observe(&container, &result);
+
+								let ttl_config = &ttl.map_or_else(|| ttl_config.clone(), |ttl| ttl_config.capped(ttl));
 
-							match serde_json::to_string(&response)
+								let exists = result.is_some();
+								let mut response = response.response(query.qname(), ttl_config, soa_record, result);
+								if exists
+								{
+									response.extend(DumbResponse::Static { records: txt, }.response(query.qname(), ttl_config, soa_record));
+								}
+								response
+							},
+							Err(err) =>
 							{
-								Ok(json) =>
+								// nothing is wrong with the connection, there is just nobody to answer
+								let recoverable = matches!(Error::find(&err), Some(Error::NoResponders | Error::RemotesUnavailable | Error::BackendTimeout));
+
+								let Some(stale) = backend.stale(&container)
+								else
 								{
-									if let Err(err) = self.writer.write_all(format!("{}\n", json).as_bytes()).await
+									if recoverable
 									{
-										warn!("[pdns_io][handler][{}] closing unix stream due to socket error: {}", query.qname(), err);
-										break;
+										warn!("[pdns_io][handler][{}] resolve error, failing query: {}", query.qname(), err);
+										return Reply::Line(format!("{}\n", json!({ "result": false })));
 									}
-								},
-								Err(err) =>
+
+									warn!("[pdns_io][handler][{}] resolve error, assuming taint: {}", query.qname(), err);
+									return Reply::Fail(err.context(Error::MessageQueueTaint));
+								};
+
+								info!("[pdns_io][handler][{}] resolve error, serving stale answer: {}", query.qname(), err);
+								if !recoverable
 								{
-									warn!("[pdns_io][handler][{}] closing unix stream due to json error: {}", query.qname(), err);
-									break;
-								},
-							}
-						},
-					}
-				},
-				Ok(Query::Initialize) =>
-				{
-					if let Err(err) = self.writer.write_all(format!("{}\n", json!({ "result": true })).as_bytes()).await
+									warn!("[pdns_io][handler][{}] resolve error, assuming taint: {}", query.qname(), err);
+									taint = Some(err.context(Error::MessageQueueTaint));
+								}
+
+								response.response(query.qname(), &ttl_config.stale(), soa_record, Some(stale))
+							},
+						}
+					},
+					LookupType::Alias { target, } =>
 					{
-						warn!("[pdns_io][handler] closing unix stream due to socket error: {}", err);
-						break;
-					}
-				},
-				Ok(Query::Unknown) =>
-				{
-					debug!("[pdns_io][handler] unknown query: {:?}", String::from_utf8_lossy(&input));
-					if let Err(err) = self.writer.write_all(format!("{}\n", json!({ "result": false })).as_bytes()).await
+						debug!("[pdns_io][handler][{}] alias of {}", query.qname(), target.as_ref());
+
+						vec![ResponseEntry::cname(query.qname(), ttl_config, format!("{}.{}", target.as_ref(), zone.domain()))].into()
+					},
+					LookupType::Acme { container, } =>
 					{
-						warn!("[pdns_io][handler] closing unix stream due to socket error: {}", err);
-						break;
-					}
-				},
-				Err(err) =>
-				{
-					warn!("[pdns_io][handler] error parsing request: {}", err);
-					break;
-				},
-			}
+						debug!("[pdns_io][handler][{}] acme response, querying metadata of {}", query.qname(), container.as_ref());
 
-			trace!("[pdns_io][handler] flushing");
-			if let Err(err) = self.writer.flush().await
+						let result = timeout(Duration::from_millis(4500), backend.metadata(Some(&container))).await;
+						match result.unwrap_or_else(|_| Err(Error::BackendTimeout.into()))
+						{
+							Ok(metadata) => zone.acme(&container, metadata.first()).response(query.qname(), ttl_config, soa_record),
+							// the challenges themselves are part of the metadata
+							Err(err) if matches!(zone.acme_mode(), AcmeMode::Txt) =>
+							{
+								warn!("[pdns_io][handler][{}] metadata error, failing query: {:#}", query.qname(), err);
+								return Reply::Line(format!("{}\n", json!({ "result": false })));
+							},
+							Err(err) =>
+							{
+								info!("[pdns_io][handler][{}] metadata error, using the default acme mode: {:#}", query.qname(), err);
+								zone.acme(&container, None).response(query.qname(), ttl_config, soa_record)
+							},
+						}
+					},
+					LookupType::Srv { service, protocol, container, } =>
+					{
+						let label = container.as_ref().map(AsRef::as_ref).unwrap_or("*");
+						debug!("[pdns_io][handler][{}] srv response, querying metadata of {}", query.qname(), label);
+
+						let result = timeout(Duration::from_millis(4500), backend.metadata(container.as_ref())).await;
+						match result.unwrap_or_else(|_| Err(Error::BackendTimeout.into()))
+						{
+							Ok(metadata) => zone.srv(&service, &protocol, &metadata).response(query.qname(), ttl_config, soa_record),
+							Err(err) =>
+							{
+								warn!("[pdns_io][handler][{}] metadata error, failing query: {:#}", query.qname(), err);
+								return Reply::Line(format!("{}\n", json!({ "result": false })));
+							},
+						}
+					},
+					LookupType::Txt { container, } =>
+					{
+						debug!("[pdns_io][handler][{}] txt response, querying metadata of {}", query.qname(), container.as_ref());
+
+						let result = timeout(Duration::from_millis(4500), backend.metadata(Some(&container))).await;
+						match result.unwrap_or_else(|_| Err(Error::BackendTimeout.into()))
+						{
+							Ok(metadata) => DumbResponse::Static
+							{
+								records: metadata.first().map(txt_records).unwrap_or_default(),
+							}.response(query.qname(), ttl_config, soa_record),
+							Err(err) =>
+							{
+								warn!("[pdns_io][handler][{}] metadata error, failing query: {:#}", query.qname(), err);
+								return Reply::Line(format!("{}\n", json!({ "result": false })));
+							},
+						}
+					},
+					LookupType::Dumb { response, } =>
+					{
+						debug!("[pdns_io][handler][{}] dumb response", query.qname());
+
+						response.response(query.qname(), ttl_config, soa_record)
+					},
+				};
+
+				match serde_json::to_string(&response)
+				{
+					Ok(json) => match taint
+					{
+						Some(err) => Reply::Last(format!("{}\n", json), err),
+						None => Reply::Line(format!("{}\n", json)),
+					},
+					Err(err) =>
+					{
+						warn!("[pdns_io][handler][{}] closing unix stream due to json error: {}", query.qname(), err);
+						Reply::Close
+					},
+				}
+			},
+			Ok(Query::Initialize) => Reply::Line(format!("{}\n", json!({ "result": true }))),
+			Ok(Query::Diagnostics) =>
 			{
-				warn!("[pdns_io][handler] closing unix stream due to socket error: {}", err);
-				break;
-			}
+				debug!("[pdns_io][handler] diagnostics requested");
+				Reply::Line(format!("{}\n", json!({ "result": backend.diagnostics() })))
+			},
+			Ok(Query::Unknown) =>
+			{
+				debug!("[pdns_io][handler] unknown query: {:?}", String::from_utf8_lossy(input));
+				Reply::Line(format!("{}\n", json!({ "result": false })))
+			},
+			Err(err) =>
+			{
+				warn!("[pdns_io][handler] error parsing request: {}", err);
+				Reply::Close
+			},
 		}
-		debug!("[pdns_io][handler] connection closed");
-
-		Ok(())
 	}
 }
-
@@ -0,0 +1,348 @@
+// Copyright (C) benaryorg <binary@benary.org>
+//
+// This software is licensed as described in the file COPYING, which
+// you should have received as part of this distribution.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::
+{
+	error::*,
+	pdns::StaticRecord,
+};
+
+use ::
+{
+	tokio::signal::unix::
+	{
+		signal,
+		SignalKind,
+	},
+	std::
+	{
+		collections::HashMap,
+		net::
+		{
+			Ipv4Addr,
+			Ipv6Addr,
+		},
+		path::
+		{
+			Path,
+			PathBuf,
+		},
+		sync::
+		{
+			Arc,
+			RwLock,
+		},
+	},
+};
+
+type Names = HashMap<String,Vec<StaticRecord>>;
+
+/// Splits a line into whitespace separated tokens, keeping quoted strings intact and dropping `;` comments.
+fn tokenize(line: &str) -> Result<Vec<String>>
+{
+	let mut tokens = Vec::new();
+	let mut token = String::new();
+	let mut quoted = false;
+	let mut chars = line.chars();
+
+	while let Some(c) = chars.next()
+	{
+		match c
+		{
+			'"' =>
+			{
+				token.push(c);
+				quoted = !quoted;
+			},
+			'\\' if quoted =>
+			{
+				token.push(c);
+				token.extend(chars.next());
+			},
+			';' if !quoted => break,
+			c if c.is_whitespace() && !quoted =>
+			{
+				if !token.is_empty()
+				{
+					tokens.push(std::mem::take(&mut token));
+				}
+			},
+			c => token.push(c),
+		}
+	}
+
+	if quoted
+	{
+		bail!("unterminated quoted string");
+	}
+	if !token.is_empty()
+	{
+		tokens.push(token);
+	}
+
+	Ok(tokens)
+}
+
+/// Fully qualified version of a name relative to the domain, `@` being the domain itself.
+///
+/// Names ending with a dot are absolute, the qualified name ends with a dot only if the domain does, so both are spelled the same.
+pub(crate) fn qualify(name: &str, domain: &str) -> String
+{
+	let dot = if domain.ends_with('.') { "." } else { "" };
+	let domain = domain.trim_end_matches('.');
+
+	let name = if name == "@"
+	{
+		domain.to_string()
+	}
+	else if let Some(name) = name.strip_suffix('.')
+	{
+		name.to_string()
+	}
+	else
+	{
+		format!("{}.{}", name, domain)
+	};
+
+	format!("{}{}", name, dot).to_lowercase()
+}
+
+fn quoted(token: &str) -> Result<&str>
+{
+	if token.len() >= 2 && token.starts_with('"') && token.ends_with('"')
+	{
+		Ok(token)
+	}
+	else
+	{
+		bail!("{} is not a quoted string", token)
+	}
+}
+
+/// Content of a record as sent to PowerDNS, names being qualified.
+fn content(qtype: &str, data: &[String], domain: &str) -> Result<String>
+{
+	let content = match (qtype, data)
+	{
+		("AAAA", [address]) => address.parse::<Ipv6Addr>()?.to_string(),
+		("A", [address]) => address.parse::<Ipv4Addr>()?.to_string(),
+		("CNAME", [target]) => qualify(target, domain),
+		("TXT", strings) if !strings.is_empty() => strings.iter()
+			.map(|string| quoted(string))
+			.collect::<Result<Vec<_>>>()?
+			.join(" "),
+		("MX", [preference, exchange]) => format!("{} {}", preference.parse::<u16>()?, qualify(exchange, domain)),
+		("SRV", [priority, weight, port, target]) => format!("{} {} {} {}", priority.parse::<u16>()?, weight.parse::<u16>()?, port.parse::<u16>()?, qualify(target, domain)),
+		("CAA", [flags, tag, value]) => format!("{} {} {}", flags.parse::<u8>()?, tag.to_lowercase(), quoted(value)?),
+		("AAAA" | "A" | "CNAME" | "TXT" | "MX" | "SRV" | "CAA", _) => bail!("wrong number of fields for {}", qtype),
+		_ => bail!("unsupported record type {}", qtype),
+	};
+
+	Ok(content)
+}
+
+/// Parses records in zone file syntax, `NAME [TTL] [IN] TYPE DATA...`.
+///
+/// Names are relative to the domain unless they end with a dot, `@` denotes the domain itself.
+/// Everything after a `;` outside of quotes is a comment.
+/// Supported types are AAAA, A, CNAME, TXT, MX, SRV, and CAA.
+///
+/// ```
+/// # use lxddns::records::parse;
+/// # use lxddns::pdns::StaticRecord;
+/// let records = parse("example.com.", r#"
+///     ; hosts which are not instances
+///     metal        3600 IN AAAA 2001:db8::1
+///     metal.example.com.  A    192.0.2.1
+///     www               CNAME  metal
+///     @                 MX     10 metal
+///     _dmarc            TXT    "v=DMARC1; p=none"
+/// "#).unwrap();
+///
+/// assert_eq!(records["metal.example.com."], vec!
+/// [
+///     StaticRecord::Record { qtype: "AAAA".to_string(), content: "2001:db8::1".to_string(), ttl: Some(3600), },
+///     StaticRecord::Record { qtype: "A".to_string(), content: "192.0.2.1".to_string(), ttl: None, },
+/// ]);
+/// assert_eq!(records["www.example.com."], vec![StaticRecord::Record { qtype: "CNAME".to_string(), content: "metal.example.com.".to_string(), ttl: None, }]);
+/// assert_eq!(records["example.com."], vec![StaticRecord::Record { qtype: "MX".to_string(), content: "10 metal.example.com.".to_string(), ttl: None, }]);
+/// assert_eq!(records["_dmarc.example.com."], vec![StaticRecord::Record { qtype: "TXT".to_string(), content: r#""v=DMARC1; p=none""#.to_string(), ttl: None, }]);
+///
+/// let records = parse("example.com", "metal.example.com. AAAA 2001:db8::1\nwww CNAME metal.example.com.").unwrap();
+/// assert_eq!(records["metal.example.com"], vec![StaticRecord::Record { qtype: "AAAA".to_string(), content: "2001:db8::1".to_string(), ttl: None, }]);
+/// assert_eq!(records["www.example.com"], vec![StaticRecord::Record { qtype: "CNAME".to_string(), content: "metal.example.com".to_string(), ttl: None, }]);
+///
+/// assert!(parse("example.com.", "host.example.net. AAAA ::1").is_err());
+/// assert!(parse("example.com", "host.example.net. AAAA ::1").is_err());
+/// assert!(parse("example.com.", "host AAAA 192.0.2.1").is_err());
+/// assert!(parse("example.com.", "host NS ns1").is_err());
+/// assert!(parse("example.com.", "@ CNAME host").is_err());
+/// assert!(parse("example.com.", "host CNAME www\nhost AAAA ::1").is_err());
+/// ```
+pub fn parse(domain: &str, input: &str) -> Result<HashMap<String,Vec<StaticRecord>>>
+{
+	let apex = qualify("@", domain);
+	let suffix = format!(".{}", apex);
+	let mut names = Names::new();
+
+	for (number, line) in input.lines().enumerate()
+	{
+		let parsed = (|| -> Result<Option<(String,StaticRecord)>>
+		{
+			let tokens = tokenize(line)?;
+			let Some((name, mut rest)) = tokens.split_first()
+			else
+			{
+				return Ok(None);
+			};
+
+			let name = qualify(name, domain);
+			if name != apex && !name.ends_with(&suffix)
+			{
+				bail!("{} is outside of {}", name, domain);
+			}
+
+			let ttl = match rest.first().map(|ttl| ttl.parse::<usize>())
+			{
+				Some(Ok(ttl)) =>
+				{
+					rest = &rest[1..];
+					Some(ttl)
+				},
+				_ => None,
+			};
+			if rest.first().map(|class| class.eq_ignore_ascii_case("IN")).unwrap_or(false)
+			{
+				rest = &rest[1..];
+			}
+
+			let Some((qtype, data)) = rest.split_first()
+			else
+			{
+				bail!("missing record type");
+			};
+			let qtype = qtype.to_uppercase();
+			let content = content(&qtype, data, domain)?;
+
+			if qtype == "CNAME" && name == apex
+			{
+				bail!("CNAME at the zone apex");
+			}
+
+			Ok(Some((name, StaticRecord::Record { qtype, content, ttl, })))
+		})().with_context(|| format!("line {}: {}", number + 1, line.trim()))?;
+
+		if let Some((name, record)) = parsed
+		{
+			names.entry(name).or_default().push(record);
+		}
+	}
+
+	for (name, records) in names.iter()
+	{
+		let cname = records.iter().any(|record| matches!(record, StaticRecord::Record { qtype, .. } if qtype == "CNAME"));
+		if cname && records.len() > 1
+		{
+			bail!("CNAME for {} alongside other records", name);
+		}
+	}
+
+	Ok(names)
+}
+
+/// Records from a static file, served alongside the instances.
+///
+/// Cloning this yields a handle to the same records, a reload is seen by all of them.
+#[derive(Clone,Debug,Default)]
+pub struct Records
+{
+	domain: String,
+	path: Option<PathBuf>,
+	names: Arc<RwLock<Names>>,
+}
+
+impl Records
+{
+	/// Records of the file, if any, failing if it cannot be read or parsed.
+	pub fn load<S: AsRef<str>>(domain: S, path: Option<&Path>) -> Result<Self>
+	{
+		let domain = domain.as_ref().to_string();
+		let names = match path
+		{
+			Some(path) =>
+			{
+				let input = std::fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))?;
+				let names = parse(&domain, &input).with_context(|| format!("cannot parse {}", path.display()))?;
+				info!("[records] loaded {} names from {}", names.len(), path.display());
+				names
+			},
+			None => Default::default(),
+		};
+
+		Ok(Self
+		{
+			domain,
+			path: path.map(Path::to_path_buf),
+			names: Arc::new(RwLock::new(names)),
+		})
+	}
+
+	/// Records of the name, empty if the file does not contain it.
+	pub fn lookup(&self, name: &str) -> Vec<StaticRecord>
+	{
+		self.names.read().unwrap().get(name).cloned().unwrap_or_default()
+	}
+
+	/// Reads the file again, keeping the previous records if that fails.
+	pub async fn reload(&self)
+	{
+		let Some(ref path) = self.path
+		else
+		{
+			return;
+		};
+
+		let names = match tokio::fs::read_to_string(path).await
+		{
+			Ok(input) => parse(&self.domain, &input),
+			Err(err) => Err(err.into()),
+		};
+
+		match names
+		{
+			Ok(names) =>
+			{
+				info!("[records] reloaded {} names from {}", names.len(), path.display());
+				*self.names.write().unwrap() = names;
+			},
+			Err(err) => warn!("[records] keeping previous records, cannot reload {}: {:#}", path.display(), err),
+		}
+	}
+
+	/// Reloads the file on every SIGHUP, unless there is no file.
+	pub fn reload_on_signal(&self) -> Result<()>
+	{
+		if self.path.is_none()
+		{
+			return Ok(());
+		}
+
+		let mut sighup = signal(SignalKind::hangup()).context("cannot install SIGHUP handler")?;
+		let records = self.clone();
+
+		tokio::spawn(async move
+		{
+			while sighup.recv().await.is_some()
+			{
+				debug!("[records] SIGHUP received");
+				records.reload().await;
+			}
+		});
+
+		Ok(())
+	}
+}
@@ -0,0 +1,137 @@
+// Copyright (C) benaryorg <binary@benary.org>
+//
+// This software is licensed as described in the file COPYING, which
+// you should have received as part of this distribution.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::error::*;
+
+use ::
+{
+	futures::future::pending,
+	tokio::
+	{
+		signal::unix::
+		{
+			signal,
+			SignalKind,
+		},
+		sync::watch,
+		time::timeout,
+	},
+	std::
+	{
+		future::Future,
+		time::Duration,
+	},
+};
+
+/// Time granted to work in progress after a shutdown was requested, unless configured otherwise.
+pub const DEFAULT_DEADLINE: Duration = Duration::from_secs(10);
+
+/// Handle to a shutdown request.
+///
+/// Cloning this yields a handle to the same request, so a single one triggered by signals is meant to be passed to every server and connection.
+#[derive(Clone,Debug)]
+pub struct Shutdown
+{
+	receiver: watch::Receiver<bool>,
+	deadline: Duration,
+}
+
+impl Shutdown
+{
+	/// Triggers on the first SIGTERM or SIGINT.
+	///
+	/// In-flight work is granted `deadline` to finish after that.
+	pub fn on_signal(deadline: Duration) -> Result<Self>
+	{
+		let mut sigterm = signal(SignalKind::terminate()).context("cannot install SIGTERM handler")?;
+		let mut sigint = signal(SignalKind::interrupt()).context("cannot install SIGINT handler")?;
+		let (sender, receiver) = watch::channel(false);
+
+		tokio::spawn(async move
+		{
+			tokio::select!
+			{
+				_ = sigterm.recv() => info!("[shutdown] SIGTERM received, shutting down"),
+				_ = sigint.recv() => info!("[shutdown] SIGINT received, shutting down"),
+			}
+			sender.send_replace(true);
+			// keep the sender, otherwise waiting handles could not tell a shutdown from the lack of one
+			pending::<()>().await;
+		});
+
+		Ok(Self
+		{
+			receiver,
+			deadline,
+		})
+	}
+
+	/// Handle which never triggers.
+	pub fn never() -> Self
+	{
+		let (_, receiver) = watch::channel(false);
+
+		Self
+		{
+			receiver,
+			deadline: DEFAULT_DEADLINE,
+		}
+	}
+
+	pub fn is_triggered(&self) -> bool
+	{
+		*self.receiver.borrow()
+	}
+
+	/// Completes once a shutdown was requested.
+	pub async fn wait(&self)
+	{
+		let mut receiver = self.receiver.clone();
+		if receiver.wait_for(|triggered| *triggered).await.is_err()
+		{
+			pending::<()>().await;
+		}
+	}
+
+	/// Runs the future to completion, but grants it only the deadline once a shutdown was requested.
+	///
+	/// Returns `None` if the future had to be cancelled.
+	pub async fn drain<F: Future>(&self, future: F) -> Option<F::Output>
+	{
+		tokio::pin!(future);
+
+		tokio::select!
+		{
+			output = &mut future => return Some(output),
+			_ = self.wait() => {},
+		}
+
+		debug!("[shutdown] draining for up to {:.1}s", self.deadline.as_secs_f64());
+		match timeout(self.deadline, future).await
+		{
+			Ok(output) => Some(output),
+			Err(_) =>
+			{
+				warn!("[shutdown] work still in progress after {:.1}s, cancelling", self.deadline.as_secs_f64());
+				None
+			},
+		}
+	}
+
+	pub fn deadline(&self) -> Duration
+	{
+		self.deadline
+	}
+}
+
+impl Default for Shutdown
+{
+	fn default() -> Self
+	{
+		Self::never()
+	}
+}
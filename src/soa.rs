@@ -0,0 +1,334 @@
+// Copyright (C) benaryorg <binary@benary.org>
+//
+// This software is licensed as described in the file COPYING, which
+// you should have received as part of this distribution.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::
+{
+	error::*,
+	lxd::
+	{
+		ContainerName,
+	},
+};
+
+use ::
+{
+	clap::
+	{
+		Args,
+	},
+	std::
+	{
+		collections::HashMap,
+		net::Ipv6Addr,
+		str::FromStr,
+		sync::
+		{
+			Arc,
+			Mutex,
+		},
+		time::
+		{
+			SystemTime,
+			UNIX_EPOCH,
+		},
+	},
+};
+
+/// How the serial announced in the SOA record is chosen.
+///
+/// ```
+/// # use lxddns::soa::SerialStrategy;
+/// assert_eq!("static:42".parse::<SerialStrategy>().unwrap(), SerialStrategy::Static(42));
+/// assert_eq!("timestamp".parse::<SerialStrategy>().unwrap(), SerialStrategy::Timestamp);
+/// assert_eq!("date".parse::<SerialStrategy>().unwrap(), SerialStrategy::Date);
+/// assert_eq!("changes".parse::<SerialStrategy>().unwrap(), SerialStrategy::Changes);
+/// assert!("static".parse::<SerialStrategy>().is_err());
+/// assert!("static:x".parse::<SerialStrategy>().is_err());
+/// ```
+#[derive(Clone,Copy,Eq,PartialEq,Hash,Debug)]
+pub enum SerialStrategy
+{
+	/// Always the given serial.
+	Static(u32),
+	/// Current unix timestamp.
+	Timestamp,
+	/// Current date as `YYYYMMDDnn`, `nn` starting at the time of day in steps of 1/100 day and counting up on every observed change.
+	///
+	/// The serial never goes backwards while running; more than 99 changes a day carry over into the following date.
+	/// Across a restart it only does if the changes outpaced the time of day, i.e. more than one change every 14.4 minutes.
+	/// Changes are tracked in memory, so this is only available in unix mode where a single process answers every query.
+	Date,
+	/// Starts at the unix timestamp of startup and is bumped on every observed change.
+	///
+	/// Like [`SerialStrategy::Date`] this is only available in unix mode.
+	Changes,
+}
+
+impl Default for SerialStrategy
+{
+	fn default() -> Self
+	{
+		SerialStrategy::Static(1)
+	}
+}
+
+impl FromStr for SerialStrategy
+{
+	type Err = crate::error::Error;
+
+	fn from_str(strategy: &str) -> std::result::Result<Self,Self::Err>
+	{
+		match strategy.split_once(':')
+		{
+			None if strategy == "timestamp" => Ok(SerialStrategy::Timestamp),
+			None if strategy == "date" => Ok(SerialStrategy::Date),
+			None if strategy == "changes" => Ok(SerialStrategy::Changes),
+			Some(("static", serial)) => Ok(SerialStrategy::Static(serial.parse()?)),
+			_ => Err(Error::InvalidConfiguration),
+		}
+	}
+}
+
+/// SOA record configuration
+#[derive(Clone,Eq,PartialEq,Hash,Debug,Args)]
+pub struct SoaConfig
+{
+	/// Primary nameserver announced in SOA (MNAME, defaults to the domain itself).
+	#[clap(long, value_name = "NAME")]
+	soa_primary: Option<String>,
+	/// Serial announced in SOA: `static:SERIAL`, `timestamp`, `date` (YYYYMMDDnn), or `changes` (bumped whenever an answer changes, `date` and `changes` in unix mode only).
+	#[clap(long, value_name = "STRATEGY", default_value = "static:1")]
+	soa_serial: SerialStrategy,
+	/// Refresh interval announced in SOA.
+	#[clap(long, value_name = "SECONDS", default_value = "86400")]
+	soa_refresh: u32,
+	/// Retry interval announced in SOA.
+	#[clap(long, value_name = "SECONDS", default_value = "7200")]
+	soa_retry: u32,
+	/// Expiry announced in SOA.
+	#[clap(long, value_name = "SECONDS", default_value = "3600000")]
+	soa_expire: u32,
+	/// Negative caching TTL announced in SOA (defaults to the SOA TTL).
+	#[clap(long, value_name = "SECONDS")]
+	soa_minimum: Option<u32>,
+}
+
+impl Default for SoaConfig
+{
+	fn default() -> Self
+	{
+		SoaConfig
+		{
+			soa_primary: None,
+			soa_serial: SerialStrategy::default(),
+			soa_refresh: 86400,
+			soa_retry: 7200,
+			soa_expire: 3600000,
+			soa_minimum: None,
+		}
+	}
+}
+
+impl SoaConfig
+{
+	/// Fails for serial strategies tracking changes, as every process answering queries tracks its own.
+	///
+	/// ```
+	/// # use lxddns::soa::SoaConfig;
+	/// assert!(SoaConfig::default().without_tracking().is_ok());
+	/// ```
+	pub fn without_tracking(&self) -> Result<()>
+	{
+		if matches!(self.soa_serial, SerialStrategy::Date | SerialStrategy::Changes)
+		{
+			bail!("serial strategy {:?} is only available in unix mode", self.soa_serial);
+		}
+
+		Ok(())
+	}
+}
+
+/// Year, month, and day of the given number of days since the unix epoch.
+fn civil_date(days: i64) -> (i64, i64, i64)
+{
+	// see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+	let days = days + 719468;
+	let era = days.div_euclid(146097);
+	let day_of_era = days - era * 146097;
+	let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let month = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * month + 2) / 5 + 1;
+	let month = if month < 10 { month + 3 } else { month - 9 };
+	let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+	(year, month, day)
+}
+
+/// Serial of the `date` strategy at the given unix timestamp, `nn` being the time of day in steps of 1/100 day.
+fn date_serial(now: u64) -> u32
+{
+	let (year, month, date) = civil_date((now / 86400) as i64);
+	let slot = (now % 86400) / 864;
+
+	(year * 1000000 + month * 10000 + date * 100) as u32 + slot as u32
+}
+
+fn now() -> u64
+{
+	SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or_default()
+}
+
+#[derive(Debug)]
+struct Changes
+{
+	/// Last answer seen for every instance known to exist.
+	answers: HashMap<ContainerName,Vec<Ipv6Addr>>,
+	/// Serial of the `changes` strategy.
+	counter: u32,
+	/// Serial of the `date` strategy, never below the serial derived from the time of the last change.
+	date: u32,
+}
+
+/// SOA record state, shared by all connections of a server so that they all announce the same serial.
+#[derive(Clone,Debug)]
+pub struct Soa
+{
+	config: SoaConfig,
+	changes: Arc<Mutex<Changes>>,
+}
+
+impl Soa
+{
+	pub fn new(config: SoaConfig) -> Self
+	{
+		let now = now();
+
+		Self
+		{
+			config,
+			changes: Arc::new(Mutex::new(Changes
+			{
+				answers: Default::default(),
+				counter: now as u32,
+				date: date_serial(now),
+			})),
+		}
+	}
+
+	/// Records the answer of a lookup, bumping the serial if it differs from the last one for the same name.
+	pub fn observe(&self, name: &ContainerName, answer: &Option<Vec<Ipv6Addr>>)
+	{
+		if !matches!(self.config.soa_serial, SerialStrategy::Date | SerialStrategy::Changes)
+		{
+			return;
+		}
+
+		let mut changes = self.changes.lock().unwrap();
+		let changed = match answer
+		{
+			// only instances that exist are kept, so lookups of arbitrary names cannot grow this
+			// the first answer for a name is only news to this process, e.g. after a restart
+			Some(addresses) =>
+			{
+				// remotes answer in no particular order
+				let mut addresses = addresses.clone();
+				addresses.sort();
+				changes.answers.insert(name.clone(), addresses.clone()).is_some_and(|previous| previous != addresses)
+			},
+			None => changes.answers.remove(name).is_some(),
+		};
+
+		if changed
+		{
+			debug!("[soa][{}] answer changed, bumping serial", name.as_ref());
+			changes.counter = changes.counter.wrapping_add(1);
+			changes.date = date_serial(now()).max(changes.date.saturating_add(1));
+		}
+	}
+
+	pub fn serial(&self) -> u32
+	{
+		match self.config.soa_serial
+		{
+			SerialStrategy::Static(serial) => serial,
+			SerialStrategy::Timestamp => now() as u32,
+			SerialStrategy::Changes => self.changes.lock().unwrap().counter,
+			// only moves on change or once the date changes, never backwards
+			SerialStrategy::Date => (date_serial(now()) / 100 * 100).max(self.changes.lock().unwrap().date),
+		}
+	}
+
+	/// Content of the SOA record, the negative caching TTL defaulting to the given SOA TTL.
+	pub fn content<D: AsRef<str>, H: AsRef<str>>(&self, domain: D, hostmaster: H, soa_ttl: usize) -> String
+	{
+		format!("{} {} {} {} {} {} {}",
+			self.config.soa_primary.as_deref().unwrap_or(domain.as_ref()),
+			hostmaster.as_ref(),
+			self.serial(),
+			self.config.soa_refresh,
+			self.config.soa_retry,
+			self.config.soa_expire,
+			self.config.soa_minimum.unwrap_or(soa_ttl as u32),
+		)
+	}
+}
+
+impl Default for Soa
+{
+	fn default() -> Self
+	{
+		Soa::new(Default::default())
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn soa() -> Soa
+	{
+		Soa::new(SoaConfig
+		{
+			soa_serial: SerialStrategy::Changes,
+			..Default::default()
+		})
+	}
+
+	fn name() -> ContainerName
+	{
+		"a".parse().unwrap()
+	}
+
+	#[test]
+	fn first_answers_do_not_bump_the_serial()
+	{
+		let soa = soa();
+		let serial = soa.serial();
+
+		soa.observe(&name(), &Some(vec![Ipv6Addr::LOCALHOST]));
+		soa.observe(&name(), &Some(vec![Ipv6Addr::LOCALHOST]));
+		soa.observe(&"b".parse().unwrap(), &None);
+
+		assert_eq!(soa.serial(), serial);
+	}
+
+	#[test]
+	fn changed_and_removed_answers_bump_the_serial()
+	{
+		let soa = soa();
+		let serial = soa.serial();
+
+		soa.observe(&name(), &Some(vec![Ipv6Addr::LOCALHOST]));
+		soa.observe(&name(), &Some(vec![Ipv6Addr::UNSPECIFIED]));
+		assert_eq!(soa.serial(), serial + 1);
+
+		soa.observe(&name(), &None);
+		assert_eq!(soa.serial(), serial + 2);
+	}
+}
@@ -0,0 +1,224 @@
+// Copyright (C) benaryorg <binary@benary.org>
+//
+// This software is licensed as described in the file COPYING, which
+// you should have received as part of this distribution.
+//
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Integration with the service manager, namely socket activation and readiness notification.
+//!
+//! Everything in here is a no-op when not running under systemd.
+
+use crate::error::*;
+
+use ::
+{
+	tokio::
+	{
+		task::JoinHandle,
+		time::interval,
+	},
+	std::
+	{
+		env,
+		net::TcpListener,
+		os::
+		{
+			fd::
+			{
+				FromRawFd,
+				OwnedFd,
+				RawFd,
+			},
+			linux::net::SocketAddrExt,
+			unix::net::
+			{
+				SocketAddr,
+				UnixDatagram,
+				UnixListener,
+			},
+		},
+		sync::
+		{
+			Mutex,
+			OnceLock,
+		},
+		time::Duration,
+	},
+};
+
+/// First file descriptor passed by the service manager.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A socket passed by the service manager along with its `FileDescriptorName=`.
+#[derive(Debug)]
+pub enum Activated
+{
+	Unix(String, UnixListener),
+	Tcp(String, TcpListener),
+}
+
+/// Sockets passed by the service manager which have not been taken yet.
+///
+/// The environment is only read once and left as is, child processes (e.g. `sudo`) ignore it as `LISTEN_PID` is not theirs.
+fn activated() -> &'static Mutex<Vec<Activated>>
+{
+	static ACTIVATED: OnceLock<Mutex<Vec<Activated>>> = OnceLock::new();
+
+	ACTIVATED.get_or_init(||
+	{
+		let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+		let count = env::var("LISTEN_FDS").ok().and_then(|count| count.parse::<RawFd>().ok()).unwrap_or(0);
+		let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+
+		if pid != Some(std::process::id())
+		{
+			return Default::default();
+		}
+
+		let mut names = names.split(':');
+		let sockets = (LISTEN_FDS_START..LISTEN_FDS_START + count)
+			.filter_map(|raw|
+			{
+				let name = names.next().unwrap_or("unknown").to_string();
+				// SAFETY: the service manager passes these file descriptors to us and nothing else uses them
+				let fd = unsafe { OwnedFd::from_raw_fd(raw) };
+				// passed descriptors lack FD_CLOEXEC and would leak into every child (e.g. `sudo`), the duplicate has it set
+				let fd = match fd.try_clone()
+				{
+					Ok(duplicate) => duplicate,
+					Err(err) =>
+					{
+						warn!("[systemd] cannot set close-on-exec on passed file descriptor {} ({}): {}", raw, name, err);
+						fd
+					},
+				};
+
+				let unix = UnixListener::from(fd);
+				if unix.local_addr().is_ok()
+				{
+					info!("[systemd] adopting unix socket {}", name);
+					return Some(Activated::Unix(name, unix));
+				}
+
+				let tcp = TcpListener::from(OwnedFd::from(unix));
+				match tcp.local_addr()
+				{
+					Ok(addr) =>
+					{
+						info!("[systemd] adopting tcp socket {} on {}", name, addr);
+						Some(Activated::Tcp(name, tcp))
+					},
+					Err(err) =>
+					{
+						warn!("[systemd] ignoring passed file descriptor {} ({}): {}", raw, name, err);
+						None
+					},
+				}
+			})
+			.collect();
+
+		Mutex::new(sockets)
+	})
+}
+
+/// Takes the first unix socket passed by the service manager, if any.
+///
+/// The socket is set to non-blocking mode for use with *tokio*.
+pub fn take_unix_listener() -> Option<UnixListener>
+{
+	let mut activated = activated().lock().unwrap();
+	let index = activated.iter().position(|socket| matches!(socket, Activated::Unix(..)))?;
+
+	match activated.remove(index)
+	{
+		Activated::Unix(_, listener) => listener.set_nonblocking(true).map(|_| listener).ok(),
+		_ => unreachable!(),
+	}
+}
+
+/// Takes all sockets passed by the service manager.
+pub fn take_all() -> Vec<Activated>
+{
+	std::mem::take(&mut *activated().lock().unwrap())
+}
+
+/// Sends a state change to the service manager, see *sd_notify(3)*.
+pub fn notify(state: &str)
+{
+	let Ok(path) = env::var("NOTIFY_SOCKET")
+	else
+	{
+		return;
+	};
+
+	let result = UnixDatagram::unbound()
+		.and_then(|socket|
+		{
+			let addr = match path.strip_prefix('@')
+			{
+				Some(name) => SocketAddr::from_abstract_name(name)?,
+				None => SocketAddr::from_pathname(&path)?,
+			};
+			socket.send_to_addr(state.as_bytes(), &addr)
+		});
+
+	match result
+	{
+		Ok(_) => trace!("[systemd] notified {:?}", state),
+		Err(err) => warn!("[systemd] cannot notify {:?}: {}", state, err),
+	}
+}
+
+/// Readiness of the service, announced on creation.
+///
+/// Keeps the watchdog fed while alive and announces the shutdown once dropped.
+pub struct Ready
+{
+	watchdog: Option<JoinHandle<()>>,
+}
+
+/// Announces readiness to the service manager and starts feeding the watchdog if one is configured.
+pub fn ready() -> Ready
+{
+	notify("READY=1");
+
+	let pid_matches = env::var("WATCHDOG_PID").ok()
+		.map(|pid| pid.parse::<u32>().ok() == Some(std::process::id()))
+		.unwrap_or(true);
+	let timeout = env::var("WATCHDOG_USEC").ok()
+		.and_then(|usec| usec.parse::<u64>().ok())
+		.filter(|_| pid_matches)
+		.map(Duration::from_micros);
+
+	let watchdog = timeout.map(|timeout|
+	{
+		debug!("[systemd] feeding watchdog with a timeout of {:?}", timeout);
+		tokio::spawn(async move
+		{
+			let mut ticker = interval(timeout / 2);
+			loop
+			{
+				ticker.tick().await;
+				notify("WATCHDOG=1");
+			}
+		})
+	});
+
+	Ready
+	{
+		watchdog,
+	}
+}
+
+impl Drop for Ready
+{
+	fn drop(&mut self)
+	{
+		if let Some(watchdog) = self.watchdog.take()
+		{
+			watchdog.abort();
+		}
+		notify("STOPPING=1");
+	}
+}